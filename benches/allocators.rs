@@ -0,0 +1,227 @@
+//! Compare the heap allocators under the same workloads.
+//!
+//! Each allocator gets its own instance on a separate region, so the
+//! global allocator is not involved and all three can run in the same
+//! kernel. For every workload and allocator we print the allocations
+//! and deallocations per second, how many allocations failed, and the
+//! fragmentation that is left over. Run it with `cargo bench`.
+//!
+//! The bump allocator is measured a second time without its lock, the
+//! way the global allocator uses it while [blog_os::boot_init] sets up
+//! the heap, to see what skipping the lock saves.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use blog_os::allocator::bump::BumpAllocator;
+use blog_os::allocator::fixed_size_block::FixedSizeBlockAllocator;
+use blog_os::allocator::linked_list::LinkedListAllocator;
+use blog_os::allocator::Locked;
+use blog_os::{cpu, exit_qemu, serial_println, QemuExitCode};
+use bootloader::{entry_point, BootInfo};
+use core::cell::UnsafeCell;
+use core::panic::PanicInfo;
+
+/// Where the allocators under test get their memory from. This is
+/// separate from the kernel heap.
+const BENCH_HEAP_START: usize = blog_os::allocator::SPARE_HEAP_START;
+const BENCH_HEAP_SIZE: usize = 256 * 1024;
+
+/// Number of allocations that are alive at the same time. Every
+/// iteration frees the oldest one and replaces it.
+const SLOTS: usize = 64;
+const ITERATIONS: usize = 20_000;
+
+/// Precision of the search for the largest possible allocation.
+const PROBE_STEP: usize = 1024;
+
+/// An allocation pattern. `size` gives the size of the allocation made
+/// in the given iteration, so the sequence is the same for every
+/// allocator.
+struct Workload {
+    name: &'static str,
+    size: fn(usize) -> usize,
+}
+
+const WORKLOADS: &[Workload] = &[
+    Workload {
+        name: "same-size churn",
+        size: |_| 64,
+    },
+    Workload {
+        name: "mixed sizes",
+        size: mixed_size,
+    },
+];
+
+/// A size from small blocks up to fallback sized ones, chosen by a hash
+/// of the iteration.
+fn mixed_size(iteration: usize) -> usize {
+    const SIZES: &[usize] = &[8, 24, 64, 200, 512, 1000, 2048, 3000, 4096];
+
+    let hash = iteration.wrapping_mul(2_654_435_761) >> 7;
+    SIZES[hash % SIZES.len()]
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::boot_init(boot_info);
+    map_bench_heap();
+    // Calibrate now, so that it isn't part of the first measurement.
+    cpu::tsc_frequency();
+
+    serial_println!(
+        "{:<16} {:<16} {:>10} {:>9} {:>7}",
+        "workload",
+        "allocator",
+        "ops/sec",
+        "failures",
+        "frag %"
+    );
+    for workload in WORKLOADS {
+        let bump = Locked::new(BumpAllocator::new());
+        unsafe { bump.lock().init(BENCH_HEAP_START, BENCH_HEAP_SIZE) };
+        bench(workload, "bump", &bump);
+
+        let unlocked_bump = UnlockedBump(UnsafeCell::new(BumpAllocator::new()));
+        unsafe {
+            (*unlocked_bump.0.get()).init(BENCH_HEAP_START, BENCH_HEAP_SIZE)
+        };
+        bench(workload, "bump (unlocked)", &unlocked_bump);
+
+        let linked_list = Locked::new(LinkedListAllocator::new());
+        unsafe { linked_list.lock().init(BENCH_HEAP_START, BENCH_HEAP_SIZE) };
+        bench(workload, "linked_list", &linked_list);
+
+        let fixed_size_block = Locked::new(FixedSizeBlockAllocator::new());
+        unsafe {
+            fixed_size_block
+                .lock()
+                .init(BENCH_HEAP_START, BENCH_HEAP_SIZE)
+        };
+        bench(workload, "fixed_size_block", &fixed_size_block);
+    }
+
+    exit_qemu(QemuExitCode::Success);
+    blog_os::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// The bump allocator without its lock, see
+/// [blog_os::allocator::begin_single_threaded]. Only the benchmark uses
+/// it, so nothing accesses it concurrently.
+struct UnlockedBump(UnsafeCell<BumpAllocator>);
+
+unsafe impl GlobalAlloc for UnlockedBump {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        (*self.0.get()).alloc_unlocked(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        (*self.0.get()).dealloc_unlocked(ptr)
+    }
+}
+
+fn map_bench_heap() {
+    use blog_os::memory;
+    use x86_64::structures::paging::{Page, PageTableFlags};
+    use x86_64::VirtAddr;
+
+    let start = VirtAddr::new(BENCH_HEAP_START as u64);
+    let pages = Page::range(
+        Page::containing_address(start),
+        Page::containing_address(start + BENCH_HEAP_SIZE),
+    );
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    memory::with_mapper(|mapper, frame_allocator| {
+        for page in pages {
+            memory::create_mapping(page, flags, mapper, frame_allocator)
+                .expect("Mapping the benchmark heap failed");
+        }
+    });
+}
+
+/// Run `workload` on `allocator` and print the results. The allocator
+/// must be freshly initialized on the benchmark heap.
+fn bench(workload: &Workload, name: &str, allocator: &dyn GlobalAlloc) {
+    let mut slots: [Option<(*mut u8, Layout)>; SLOTS] = [None; SLOTS];
+    let mut ops = 0u64;
+    let mut failures = 0;
+
+    let start = cpu::rdtsc();
+    for i in 0..ITERATIONS {
+        let slot = &mut slots[i % SLOTS];
+        if let Some((ptr, layout)) = slot.take() {
+            unsafe { allocator.dealloc(ptr, layout) };
+            ops += 1;
+        }
+
+        let layout = Layout::from_size_align((workload.size)(i), 8).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        ops += 1;
+        if ptr.is_null() {
+            failures += 1;
+        }
+        else {
+            *slot = Some((ptr, layout));
+        }
+    }
+    let ns = cpu::cycles_to_ns(cpu::rdtsc() - start).max(1);
+    let ops_per_sec = ops * 1_000_000_000 / ns;
+
+    // Keep every 8th allocation alive and see how much of the rest of
+    // the heap can still be used in one piece.
+    let mut live = 0;
+    for (i, slot) in slots.iter_mut().enumerate() {
+        match slot {
+            Some((_, layout)) if i % 8 == 0 => live += layout.size(),
+            Some((ptr, layout)) => {
+                unsafe { allocator.dealloc(*ptr, *layout) };
+                *slot = None;
+            }
+            None => {}
+        }
+    }
+    let largest = largest_allocation(allocator);
+    let fragmentation =
+        100usize.saturating_sub(largest * 100 / (BENCH_HEAP_SIZE - live));
+
+    serial_println!(
+        "{:<16} {:<16} {:>10} {:>9} {:>7}",
+        workload.name,
+        name,
+        ops_per_sec,
+        failures,
+        fragmentation
+    );
+
+    for (ptr, layout) in slots.iter().flatten() {
+        unsafe { allocator.dealloc(*ptr, *layout) };
+    }
+}
+
+/// Find the largest allocation that `allocator` can still make, give or
+/// take [PROBE_STEP] bytes.
+///
+/// We go down from the size of the heap instead of doing a binary
+/// search, because a successful probe permanently uses up memory in
+/// the bump allocator. This way only the last probe succeeds.
+fn largest_allocation(allocator: &dyn GlobalAlloc) -> usize {
+    for size in (PROBE_STEP..=BENCH_HEAP_SIZE).rev().step_by(PROBE_STEP) {
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        if !ptr.is_null() {
+            unsafe { allocator.dealloc(ptr, layout) };
+            return size;
+        }
+    }
+    0
+}
@@ -4,38 +4,519 @@
 //! or strings, you are required to call [init_heap] exactly once! You
 //! don't have to do anything else, as the module uses
 //! `#[global_allocator]` to set the allocator globally.
+//!
+//! By default allocations are served by the [FixedSizeBlockAllocator].
+//! To use one of the other allocators instead, eg to compare them, call
+//! [set_backend] before [init_heap].
+//!
+//! Use [stats] to see how the heap is used. When an allocation fails,
+//! the stats are reported before the kernel panics, see
+//! [report_alloc_error].
+//!
+//! For scratch allocations that shouldn't touch the heap, see
+//! [arena::ArenaAllocator]. The other allocators can also manage a
+//! separate region, eg a `Locked<FixedSizeBlockAllocator>`, and they
+//! implement [Allocator] for use with `Vec::new_in` and the like.
+//!
+//! The panic handler must not allocate, because the code that panicked
+//! may hold the lock of the allocator. It calls [set_panicking] so that
+//! if it does anyway, the allocation fails instead of hanging.
+//!
+//! With [Backend::Bump], boot code that runs before interrupts are
+//! enabled can skip the lock of the allocator, see
+//! [begin_single_threaded].
+//!
+//! In debug builds, the linked list and fixed size block allocators
+//! fill freed memory with [POISON], so that a use after free reads
+//! obviously wrong values instead of stale but plausible ones.
 
+pub mod arena;
+pub mod bump;
 pub mod fixed_size_block;
 pub mod linked_list;
 
-use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::mapper::{
+    MapToError, Translate, TranslateResult,
+};
 use x86_64::structures::paging::{
     FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
 };
 use x86_64::VirtAddr;
 
+use crate::counters::NamedCounters;
+use crate::memory::VirtRange;
+use alloc::alloc::{GlobalAlloc, Layout};
+use bump::BumpAllocator;
+use core::alloc::{AllocError, Allocator};
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
 use fixed_size_block::FixedSizeBlockAllocator;
+use linked_list::LinkedListAllocator;
 
 #[global_allocator]
-static ALLOCATOR: Locked<FixedSizeBlockAllocator> =
-    Locked::new(FixedSizeBlockAllocator::new());
+static ALLOCATOR: KernelAllocator = KernelAllocator::new();
+
+/// The allocator implementations that can serve heap allocations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Backend {
+    Bump,
+    LinkedList,
+    FixedSizeBlock,
+}
+
+impl Backend {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Backend::Bump,
+            1 => Backend::LinkedList,
+            _ => Backend::FixedSizeBlock,
+        }
+    }
+}
+
+/// The global allocator. It contains every [Backend] and forwards each
+/// call to the selected one. Only the selected backend is initialized,
+/// so the others cost nothing but their (small) size.
+struct KernelAllocator {
+    backend: AtomicU8,
+    initialized: AtomicBool,
+    /// Start and size of the heap that was passed to `init`.
+    heap_start: AtomicUsize,
+    heap_size: AtomicUsize,
+    /// Bytes currently allocated, as requested by the layouts.
+    used: AtomicUsize,
+    /// The largest value `used` has had.
+    peak: AtomicUsize,
+    bump: Locked<BumpAllocator>,
+    linked_list: Locked<LinkedListAllocator>,
+    fixed_size_block: Locked<FixedSizeBlockAllocator>,
+    /// Whether the bump allocator is in `unlocked_bump`, see
+    /// [begin_single_threaded].
+    single_threaded: AtomicBool,
+    unlocked_bump: UnlockedBump,
+}
+
+/// Holds the state of the bump allocator between
+/// [begin_single_threaded] and [end_single_threaded].
+struct UnlockedBump(UnsafeCell<BumpAllocator>);
+
+// Safe because the allocator is only accessed through it while the
+// kernel is single threaded, so never concurrently.
+unsafe impl Sync for UnlockedBump {}
+
+impl KernelAllocator {
+    const fn new() -> Self {
+        KernelAllocator {
+            backend: AtomicU8::new(Backend::FixedSizeBlock as u8),
+            initialized: AtomicBool::new(false),
+            heap_start: AtomicUsize::new(0),
+            heap_size: AtomicUsize::new(0),
+            used: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            bump: Locked::new(BumpAllocator::new()),
+            linked_list: Locked::new(LinkedListAllocator::new()),
+            fixed_size_block: Locked::new(FixedSizeBlockAllocator::new()),
+            single_threaded: AtomicBool::new(false),
+            unlocked_bump: UnlockedBump(UnsafeCell::new(BumpAllocator::new())),
+        }
+    }
+
+    /// Get the bump allocator if allocations skip its lock, ie between
+    /// [begin_single_threaded] and [end_single_threaded].
+    ///
+    /// This is unsafe because the caller must not keep the reference
+    /// around, since the kernel may become multi threaded after.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn unlocked_bump(&self) -> Option<&mut BumpAllocator> {
+        if self.single_threaded.load(Ordering::SeqCst) {
+            Some(&mut *self.unlocked_bump.0.get())
+        }
+        else {
+            None
+        }
+    }
+
+    /// The backend that was selected with [set_backend].
+    fn selected(&self) -> &dyn GlobalAlloc {
+        match backend() {
+            Backend::Bump => &self.bump,
+            Backend::LinkedList => &self.linked_list,
+            Backend::FixedSizeBlock => &self.fixed_size_block,
+        }
+    }
+
+    /// Initialize the selected backend with the given heap bounds.
+    ///
+    /// This is unsafe for the same reasons as the `init` of each
+    /// backend.
+    unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        assert!(
+            !self.initialized.swap(true, Ordering::SeqCst),
+            "Heap already initialized"
+        );
+        self.heap_start.store(heap_start, Ordering::SeqCst);
+        self.heap_size.store(heap_size, Ordering::SeqCst);
+        match backend() {
+            Backend::Bump => match self.unlocked_bump() {
+                Some(bump) => bump.init(heap_start, heap_size),
+                None => self.bump.lock().init(heap_start, heap_size),
+            },
+            Backend::LinkedList => {
+                self.linked_list.lock().init(heap_start, heap_size)
+            }
+            Backend::FixedSizeBlock => {
+                self.fixed_size_block.lock().init(heap_start, heap_size)
+            }
+        }
+    }
+}
+
+/// Set by [set_panicking].
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Make every allocation fail, and every deallocation leak, without
+/// touching the lock of the allocator. The panic handler calls this
+/// first, because the panic might have happened while the lock was
+/// held, eg in the allocator itself, and waiting for it would hang.
+///
+/// The panic path formats into fixed buffers and is not supposed to
+/// allocate at all, this is what makes sure that a mistake shows up as
+/// an allocation error instead of a hang. There is no way back, since
+/// we don't expect to do anything but report the panic.
+pub fn set_panicking() {
+    PANICKING.store(true, Ordering::SeqCst);
+}
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if PANICKING.load(Ordering::SeqCst) {
+            COUNTERS.inc_at(ALLOC_FAILED_COUNTER);
+            return core::ptr::null_mut();
+        }
+        let ptr = match self.unlocked_bump() {
+            Some(bump) => {
+                assert!(
+                    !crate::interrupts::are_enabled(),
+                    "Interrupts enabled before allocator::end_single_threaded"
+                );
+                bump.alloc_unlocked(layout)
+            }
+            None => self.selected().alloc(layout),
+        };
+        if ptr.is_null() {
+            COUNTERS.inc_at(ALLOC_FAILED_COUNTER);
+        }
+        else {
+            COUNTERS.inc_at(ALLOC_COUNTER);
+            let used = self.used.fetch_add(layout.size(), Ordering::Relaxed)
+                + layout.size();
+            self.peak.fetch_max(used, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if PANICKING.load(Ordering::SeqCst) {
+            return;
+        }
+        match self.unlocked_bump() {
+            Some(bump) => bump.dealloc_unlocked(ptr),
+            None => self.selected().dealloc(ptr, layout),
+        }
+        COUNTERS.inc_at(DEALLOC_COUNTER);
+        self.used.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+/// Select which allocator should serve heap allocations.
+///
+/// Panics if called after [init_heap], because the backends can't take
+/// over each other's allocations.
+pub fn set_backend(backend: Backend) {
+    assert!(
+        !ALLOCATOR.initialized.load(Ordering::SeqCst),
+        "allocator::set_backend called after init_heap"
+    );
+    assert!(
+        !is_single_threaded(),
+        "allocator::set_backend called before end_single_threaded"
+    );
+    ALLOCATOR.backend.store(backend as u8, Ordering::SeqCst);
+}
+
+/// Get the allocator that serves heap allocations.
+pub fn backend() -> Backend {
+    Backend::from_u8(ALLOCATOR.backend.load(Ordering::SeqCst))
+}
+
+/// Make the global allocator skip its lock until [end_single_threaded],
+/// which speeds up the many small allocations of boot code. This needs
+/// [Backend::Bump], and it can be called before or after [init_heap].
+///
+/// Allocating while interrupts are enabled panics until
+/// [end_single_threaded], and [crate::init] calls that before it
+/// enables interrupts, so boot code has to use the heap before it.
+/// [crate::boot_init] does this by itself when the backend is
+/// [Backend::Bump], so the heap is set up without the lock.
+///
+/// This is unsafe because the caller must make sure that nothing else
+/// runs until [end_single_threaded], eg other CPUs. Panics if
+/// interrupts are enabled or if the backend is not [Backend::Bump].
+pub unsafe fn begin_single_threaded() {
+    assert!(
+        !crate::interrupts::are_enabled(),
+        "allocator::begin_single_threaded called with interrupts enabled"
+    );
+    assert_eq!(
+        backend(),
+        Backend::Bump,
+        "Only the bump allocator can skip the lock"
+    );
+    if !ALLOCATOR.single_threaded.load(Ordering::SeqCst) {
+        let bump = core::mem::replace(
+            &mut *ALLOCATOR.bump.lock(),
+            BumpAllocator::new(),
+        );
+        *ALLOCATOR.unlocked_bump.0.get() = bump;
+        ALLOCATOR.single_threaded.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Make the global allocator lock again, keeping every allocation made
+/// since [begin_single_threaded]. Does nothing if it already locks.
+/// This has to be called before anything can run concurrently, eg
+/// before enabling interrupts.
+pub fn end_single_threaded() {
+    if ALLOCATOR.single_threaded.swap(false, Ordering::SeqCst) {
+        // Safe because we were single threaded until now, and nothing
+        // uses the unlocked state anymore.
+        let bump = unsafe {
+            core::mem::replace(
+                &mut *ALLOCATOR.unlocked_bump.0.get(),
+                BumpAllocator::new(),
+            )
+        };
+        *ALLOCATOR.bump.lock() = bump;
+    }
+}
+
+/// Check whether the global allocator skips its lock, see
+/// [begin_single_threaded].
+pub fn is_single_threaded() -> bool {
+    ALLOCATOR.single_threaded.load(Ordering::SeqCst)
+}
+
+/// Get the number of allocations made so far per block size. See
+/// [FixedSizeBlockAllocator::size_histogram] for the meaning of each
+/// entry. This is only tracked for [Backend::FixedSizeBlock], so it's
+/// all zeroes with the other backends.
+pub fn size_histogram() -> [u64; fixed_size_block::HISTOGRAM_BUCKETS] {
+    ALLOCATOR.fixed_size_block.lock().size_histogram()
+}
+
+// Indices of the counters in COUNTERS, so that allocations don't look
+// them up by name.
+const ALLOC_COUNTER: usize = 0;
+const DEALLOC_COUNTER: usize = 1;
+const ALLOC_FAILED_COUNTER: usize = 2;
+
+/// Calls to the global allocator, see [counters].
+static COUNTERS: NamedCounters<3> = {
+    let mut names = [""; 3];
+    names[ALLOC_COUNTER] = "alloc";
+    names[DEALLOC_COUNTER] = "dealloc";
+    names[ALLOC_FAILED_COUNTER] = "alloc_failed";
+    NamedCounters::new(names)
+};
+
+/// Get the number of calls to the global allocator by name: `"alloc"`
+/// and `"dealloc"` for successful ones, and `"alloc_failed"`.
+pub fn counters() -> &'static NamedCounters<3> {
+    &COUNTERS
+}
+
+/// A snapshot of how the heap is used, see [stats].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    pub backend: Backend,
+    pub heap_size: usize,
+    /// Bytes currently allocated. This counts the sizes that were
+    /// requested, not what the backend rounded them up to.
+    pub used: usize,
+    /// The most bytes that were allocated at the same time.
+    pub peak: usize,
+    /// Free blocks of each of the [fixed_size_block::BLOCK_SIZES]. Only
+    /// known for [Backend::FixedSizeBlock].
+    pub free_blocks: Option<[usize; fixed_size_block::BLOCK_SIZES.len()]>,
+    /// Size of the largest contiguous free region, ie roughly the
+    /// largest allocation that can still succeed.
+    pub largest_free: Option<usize>,
+}
+
+/// Get the current [HeapStats].
+///
+/// This doesn't wait for the lock of the backend, because it's meant to
+/// work even when an allocation failed in the middle of something. If
+/// the lock is held, the fields that need it are `None`.
+pub fn stats() -> HeapStats {
+    let mut stats = HeapStats {
+        backend: backend(),
+        heap_size: ALLOCATOR.heap_size.load(Ordering::SeqCst),
+        used: ALLOCATOR.used.load(Ordering::Relaxed),
+        peak: ALLOCATOR.peak.load(Ordering::Relaxed),
+        free_blocks: None,
+        largest_free: None,
+    };
+
+    match stats.backend {
+        Backend::Bump => {
+            // Safe because the reference doesn't outlive this statement.
+            if let Some(bump) = unsafe { ALLOCATOR.unlocked_bump() } {
+                stats.largest_free = Some(bump.remaining());
+            }
+            else if let Some(bump) = ALLOCATOR.bump.try_lock() {
+                stats.largest_free = Some(bump.remaining());
+            }
+        }
+        Backend::LinkedList => {
+            if let Some(linked_list) = ALLOCATOR.linked_list.try_lock() {
+                stats.largest_free = Some(linked_list.largest_free_region());
+            }
+        }
+        Backend::FixedSizeBlock => {
+            if let Some(allocator) = ALLOCATOR.fixed_size_block.try_lock() {
+                stats.free_blocks = Some(allocator.free_blocks());
+                stats.largest_free = allocator.largest_free_region();
+            }
+        }
+    }
+
+    stats
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "--- heap stats ({:?}) ---", self.backend)?;
+        writeln!(f, "size: {} bytes", self.heap_size)?;
+        writeln!(f, "used: {} bytes, peak {} bytes", self.used, self.peak)?;
+        match self.largest_free {
+            Some(size) => writeln!(f, "largest free region: {} bytes", size)?,
+            None => writeln!(f, "largest free region: unknown")?,
+        }
+        if let Some(free_blocks) = self.free_blocks {
+            write!(f, "free blocks:")?;
+            for (size, count) in
+                fixed_size_block::BLOCK_SIZES.iter().zip(free_blocks)
+            {
+                write!(f, " {}: {}", size, count)?;
+            }
+            writeln!(f)?;
+        }
+        write!(f, "--- end of heap stats ---")
+    }
+}
+
+/// Where [report_alloc_error] writes, set with [set_alloc_error_output]
+/// and stored as a function pointer cast to `usize`. Zero means serial.
+static ALLOC_ERROR_OUTPUT: AtomicUsize = AtomicUsize::new(0);
+
+/// Send the report of [report_alloc_error] to `output` instead of the
+/// serial port, eg to check it in tests. It's called with pieces of the
+/// report, in order.
+pub fn set_alloc_error_output(output: fn(&str)) {
+    ALLOC_ERROR_OUTPUT.store(output as usize, Ordering::SeqCst);
+}
+
+/// Writes to the output selected with [set_alloc_error_output].
+struct AllocErrorOutput;
+
+impl fmt::Write for AllocErrorOutput {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match ALLOC_ERROR_OUTPUT.load(Ordering::SeqCst) {
+            // The failed allocation may have come from code that holds
+            // the serial lock, so we can't wait for it.
+            0 => crate::serial::_irq_print(format_args!("{}", s)),
+            output => {
+                // Safe because set_alloc_error_output is the only thing
+                // storing nonzero values, and those always come from a
+                // `fn(&str)`.
+                let output: fn(&str) = unsafe { core::mem::transmute(output) };
+                output(s);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Report that an allocation with `layout` failed, along with the
+/// current [stats]. The kernel's alloc error handler calls this before
+/// panicking, so that the panic can be explained.
+pub fn report_alloc_error(layout: Layout) {
+    use core::fmt::Write;
+
+    // There's nothing we can do if reporting fails.
+    let _ = writeln!(
+        AllocErrorOutput,
+        "\nallocation of {} bytes with alignment {} failed\n{}\n{}",
+        layout.size(),
+        layout.align(),
+        COUNTERS,
+        stats()
+    );
+}
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024;
 
+/// Start of a region of virtual memory that the kernel never maps by
+/// itself, for heaps other than the global one, eg to compare the
+/// allocators in the benchmarks.
+pub const SPARE_HEAP_START: usize = 0x_6666_0000_0000;
+
+/// Map the heap with the default size of [HEAP_SIZE] and initialize
+/// the allocator with it.
+///
+/// In debug builds, the mapping is checked with [verify_heap_range]
+/// before the allocator is initialized, and we panic if it's broken.
 pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), HeapInitError> {
+    init_heap_with_size(mapper, frame_allocator, HEAP_SIZE)
+}
+
+/// Like [init_heap], but with a heap of `heap_size` bytes starting at
+/// [HEAP_START].
+pub fn init_heap_with_size(
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    heap_size: usize,
+) -> Result<(), HeapInitError> {
+    init_heap_at(mapper, frame_allocator, HEAP_START, heap_size)
+}
+
+/// Like [init_heap], but with a heap of `heap_size` bytes starting at
+/// `heap_start`.
+///
+/// Returns [HeapInitError::InvalidRange] without mapping anything if
+/// the heap would wrap around the address space or isn't canonical.
+pub fn init_heap_at(
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
+    heap_start: usize,
+    heap_size: usize,
+) -> Result<(), HeapInitError> {
+    let invalid = HeapInitError::InvalidRange {
+        start: heap_start,
+        size: heap_size,
     };
+    let start = VirtAddr::try_new(heap_start as u64).map_err(|_| invalid)?;
+    let heap = VirtRange::try_new(start, heap_size as u64).ok_or(invalid)?;
 
-    for page in page_range {
+    for page in heap.pages() {
         let frame = frame_allocator
             .allocate_frame()
             .ok_or(MapToError::FrameAllocationFailed)?;
@@ -43,13 +524,133 @@ pub fn init_heap(
         unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
     }
 
+    #[cfg(debug_assertions)]
+    verify_heap_range(mapper, start, heap_size)
+        .expect("Heap verification failed");
+
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.init(heap_start, heap_size);
+    }
+
+    Ok(())
+}
+
+/// Errors returned by [init_heap] and its variants.
+#[derive(Debug)]
+pub enum HeapInitError {
+    /// The `size` bytes at `start` wrap around the address space or
+    /// include non-canonical addresses.
+    InvalidRange { start: usize, size: usize },
+    /// Mapping the heap failed.
+    MapTo(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for HeapInitError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        HeapInitError::MapTo(error)
+    }
+}
+
+/// Problems found by [verify_heap].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapError {
+    /// [init_heap] has not been called.
+    NotInitialized,
+    /// The page containing the address is not mapped.
+    Unmapped(VirtAddr),
+    /// The page containing the address is mapped, but not writable.
+    ReadOnly(VirtAddr),
+    /// A value written to the address could not be read back.
+    PatternMismatch(VirtAddr),
+}
+
+/// Check that the heap is usable, see [verify_heap_range]. `mapper`
+/// must be the mapper that the heap was mapped with.
+pub fn verify_heap(mapper: &impl Translate) -> Result<(), HeapError> {
+    match ALLOCATOR.heap_size.load(Ordering::SeqCst) {
+        0 => Err(HeapError::NotInitialized),
+        heap_size => verify_heap_range(
+            mapper,
+            VirtAddr::new(ALLOCATOR.heap_start.load(Ordering::SeqCst) as u64),
+            heap_size,
+        ),
+    }
+}
+
+/// Check that the first and last page of the `size` bytes at `start`
+/// are mapped writable according to `mapper`, and that each word of
+/// them can hold a test pattern. Pages that aren't mapped are never
+/// touched, so this reports a broken mapping instead of faulting.
+///
+/// The original contents are restored, so this can be used on a heap
+/// that is in use. Interrupts are disabled while the pattern is in
+/// place, so that nothing else sees it.
+pub fn verify_heap_range(
+    mapper: &impl Translate,
+    start: VirtAddr,
+    size: usize,
+) -> Result<(), HeapError> {
+    const PATTERN: u64 = 0x_a5a5_5a5a_c3c3_3c3c;
+
+    let range = VirtRange::new(start, size as u64);
+    let (first_page, last_page) = match range.last() {
+        Some(last) => (
+            Page::<Size4KiB>::containing_address(start),
+            Page::containing_address(last),
+        ),
+        None => return Ok(()),
+    };
+
+    for page in [first_page, last_page] {
+        let addr = page.start_address();
+        match mapper.translate(addr) {
+            TranslateResult::Mapped { flags, .. } => {
+                if !flags.contains(PageTableFlags::WRITABLE) {
+                    return Err(HeapError::ReadOnly(addr));
+                }
+            }
+            _ => return Err(HeapError::Unmapped(addr)),
+        }
+
+        let words: *mut u64 = addr.as_mut_ptr();
+        let word_count = page.size() as usize / 8;
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            for i in 0..word_count {
+                // Safe because the page is mapped and writable, and we
+                // restore the original value.
+                unsafe {
+                    let word = words.add(i);
+                    let original = word.read_volatile();
+                    word.write_volatile(PATTERN);
+                    let read = word.read_volatile();
+                    word.write_volatile(original);
+                    if read != PATTERN {
+                        let offset = 8 * i as u64;
+                        return Err(HeapError::PatternMismatch(addr + offset));
+                    }
+                }
+            }
+            Ok(())
+        })?;
     }
 
     Ok(())
 }
 
+/// The byte that freed memory is filled with in debug builds.
+pub const POISON: u8 = 0xde;
+
+/// Fill `size` bytes at `ptr` with [POISON]. Does nothing in release
+/// builds.
+///
+/// This is unsafe because the caller must own the memory, eg because it
+/// was just freed and not handed out again yet.
+unsafe fn poison(ptr: *mut u8, size: usize) {
+    if cfg!(debug_assertions) {
+        core::ptr::write_bytes(ptr, POISON, size);
+    }
+}
+
 /// Align the given address `addr` upwards to alignment `align`.
 ///
 /// Requires that `align` is a power of two, which it normally should
@@ -76,10 +677,25 @@ fn align_up(addr: usize, align: usize) -> usize {
 }
 
 /// A wrapper around spin::Mutex to permit trait implementations.
+///
+/// By default, [Locked::lock] simply spins until the lock is free. A
+/// hook can be registered with [Locked::set_yield_hook] to be called
+/// whenever we have spun for a while without getting the lock, eg to
+/// `hlt` or to yield to a scheduler instead of wasting CPU time.
 pub struct Locked<A> {
     inner: spin::Mutex<A>,
 }
 
+/// The hook registered with [Locked::set_yield_hook], as a function
+/// pointer cast to `usize`. Zero means that there is no hook.
+static YIELD_HOOK: AtomicUsize = AtomicUsize::new(0);
+
+/// How many times [Locked::lock] tries to take the lock between calls
+/// to the yield hook.
+static SPIN_LIMIT: AtomicUsize = AtomicUsize::new(DEFAULT_SPIN_LIMIT);
+
+const DEFAULT_SPIN_LIMIT: usize = 1000;
+
 impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Locked {
@@ -88,6 +704,107 @@ impl<A> Locked<A> {
     }
 
     pub fn lock(&self) -> spin::MutexGuard<A> {
-        self.inner.lock()
+        let hook = YIELD_HOOK.load(Ordering::Relaxed);
+        if hook == 0 {
+            return self.inner.lock();
+        }
+
+        // Safe because set_yield_hook is the only thing storing nonzero
+        // values, and those always come from a `fn()`.
+        let hook: fn() = unsafe { core::mem::transmute(hook) };
+        loop {
+            for _ in 0..SPIN_LIMIT.load(Ordering::Relaxed).max(1) {
+                if let Some(guard) = self.inner.try_lock() {
+                    return guard;
+                }
+                core::hint::spin_loop();
+            }
+            hook();
+        }
+    }
+
+    /// Take the lock if it is free, without spinning or yielding.
+    pub fn try_lock(&self) -> Option<spin::MutexGuard<A>> {
+        self.inner.try_lock()
+    }
+}
+
+/// Lets a locked allocator serve collections like `Vec::new_in`, on top
+/// of whatever region it was initialized with. The allocators are
+/// written for `GlobalAlloc`, so this forwards to that, except for zero
+/// sized allocations, which `GlobalAlloc` doesn't allow.
+unsafe impl<A> Allocator for Locked<A>
+where
+    Locked<A>: GlobalAlloc,
+{
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = if layout.size() == 0 {
+            // Any aligned address is fine, it is never accessed.
+            layout.align() as *mut u8
+        }
+        else {
+            // Safe because the size is nonzero.
+            unsafe { self.alloc(layout) }
+        };
+        let ptr = NonNull::new(ptr).ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() != 0 {
+            self.dealloc(ptr.as_ptr(), layout);
+        }
+    }
+}
+
+// The yield hook is shared by every Locked regardless of what it wraps,
+// so these don't need a type parameter. Putting them in an impl for a
+// concrete type lets callers write `Locked::set_yield_hook` without
+// specifying one.
+impl Locked<()> {
+    /// Call `hook` every time [Locked::lock] has spun for the configured
+    /// number of iterations without getting the lock.
+    ///
+    /// This affects every lock, including the one of the global
+    /// allocator, so the hook must be safe to call from anywhere we
+    /// might lock, including interrupt handlers. For instance, `hlt`
+    /// with interrupts disabled would never return.
+    pub fn set_yield_hook(hook: fn()) {
+        YIELD_HOOK.store(hook as usize, Ordering::Relaxed);
+    }
+
+    /// Remove the yield hook, so that we go back to pure spinning.
+    pub fn clear_yield_hook() {
+        YIELD_HOOK.store(0, Ordering::Relaxed);
+    }
+
+    /// Set how many times [Locked::lock] should try to take the lock
+    /// before calling the yield hook.
+    pub fn set_spin_limit(spins: usize) {
+        SPIN_LIMIT.store(spins, Ordering::Relaxed);
+    }
+}
+
+#[test_case]
+fn test_yield_hook_called_on_contention() {
+    static LOCK: Locked<u32> = Locked::new(0);
+    static HOOK_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn hook() {
+        HOOK_CALLS.fetch_add(1, Ordering::Relaxed);
+        // Pretend that whoever held the lock got to run and released it.
+        unsafe { LOCK.inner.force_unlock() };
     }
+
+    // The hook releases this lock, so we must not drop the guard.
+    core::mem::forget(LOCK.lock());
+
+    Locked::set_spin_limit(10);
+    Locked::set_yield_hook(hook);
+    let guard = LOCK.lock();
+    Locked::clear_yield_hook();
+    Locked::set_spin_limit(DEFAULT_SPIN_LIMIT);
+
+    drop(guard);
+    assert_eq!(HOOK_CALLS.load(Ordering::Relaxed), 1);
 }
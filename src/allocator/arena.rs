@@ -0,0 +1,96 @@
+use super::align_up;
+use core::alloc::{AllocError, Allocator, Layout};
+use core::cell::Cell;
+use core::marker::PhantomData;
+use core::ptr::{self, NonNull};
+
+/// Bump allocator over a buffer provided by the caller, for short lived
+/// scratch allocations.
+///
+/// It doesn't use the heap, so it works before [super::init_heap] and
+/// doesn't contend with anything else for the allocator lock. It
+/// implements [Allocator] instead of `GlobalAlloc`, so it is used
+/// explicitly, eg with `Vec::new_in(&arena)`.
+///
+/// Like [super::bump::BumpAllocator], it never reuses memory on its
+/// own. Instead, [ArenaAllocator::reset] frees everything at once. It
+/// takes `&mut self`, so the borrow checker makes sure that nothing
+/// allocated from the arena is still alive at that point.
+pub struct ArenaAllocator<'a> {
+    start: *mut u8,
+    size: usize,
+    /// Offset of the first unused byte from `start`.
+    next: Cell<usize>,
+    _buffer: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> ArenaAllocator<'a> {
+    /// Create an empty arena that allocates from `buffer`.
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        ArenaAllocator {
+            start: buffer.as_mut_ptr(),
+            size: buffer.len(),
+            next: Cell::new(0),
+            _buffer: PhantomData,
+        }
+    }
+
+    /// Number of bytes in use, including padding for alignment.
+    pub fn used(&self) -> usize {
+        self.next.get()
+    }
+
+    /// Free every allocation at once.
+    pub fn reset(&mut self) {
+        self.next.set(0);
+    }
+}
+
+unsafe impl Allocator for ArenaAllocator<'_> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let start = self.start as usize;
+        let alloc_start = align_up(start + self.next.get(), layout.align());
+        let alloc_end =
+            alloc_start.checked_add(layout.size()).ok_or(AllocError)?;
+        if alloc_end > start + self.size {
+            return Err(AllocError);
+        }
+
+        self.next.set(alloc_end - start);
+        let ptr = ptr::slice_from_raw_parts_mut(
+            alloc_start as *mut u8,
+            layout.size(),
+        );
+        NonNull::new(ptr).ok_or(AllocError)
+    }
+
+    unsafe fn deallocate(&self, _ptr: NonNull<u8>, _layout: Layout) {
+        // Memory is only reclaimed by reset.
+    }
+}
+
+#[test_case]
+fn test_arena_allocator() {
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+
+    let mut buffer = [0; 256];
+    let mut arena = ArenaAllocator::new(&mut buffer);
+
+    {
+        let mut numbers = Vec::new_in(&arena);
+        numbers.extend_from_slice(&[1u64, 2, 3]);
+        let answer = Box::new_in(42u32, &arena);
+        assert_eq!(numbers.iter().sum::<u64>(), 6);
+        assert_eq!(*answer, 42);
+        assert!(arena.used() > 0);
+    }
+
+    arena.reset();
+    assert_eq!(arena.used(), 0);
+
+    // The entire buffer is available again.
+    let bytes = Vec::<u8, _>::with_capacity_in(256, &arena);
+    assert_eq!(bytes.capacity(), 256);
+    assert!(arena.allocate(Layout::new::<u8>()).is_err());
+}
@@ -0,0 +1,93 @@
+use super::{align_up, Locked};
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+
+/// The simplest possible allocator.
+///
+/// It hands out memory linearly, starting from the start of the heap,
+/// and only keeps track of where the next allocation should start and
+/// how many allocations are currently alive. Memory is never reused
+/// until every allocation has been freed, at which point the allocator
+/// resets to the start of the heap.
+///
+/// This makes allocation very fast, but a single long lived allocation
+/// means that the heap eventually runs out. It is mostly useful as a
+/// baseline to compare the other allocators against.
+pub struct BumpAllocator {
+    heap_start: usize,
+    heap_end: usize,
+    next: usize,
+    allocations: usize,
+}
+
+impl BumpAllocator {
+    /// Create an empty [BumpAllocator].
+    pub const fn new() -> Self {
+        BumpAllocator {
+            heap_start: 0,
+            heap_end: 0,
+            next: 0,
+            allocations: 0,
+        }
+    }
+
+    /// Initialize allocator with given heap bounds.
+    ///
+    /// This is unsafe because the caller must ensure that the given
+    /// memory range is unused. Additionally, this method must never be
+    /// called more than once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_start = heap_start;
+        self.heap_end = heap_start + heap_size;
+        self.next = heap_start;
+    }
+
+    /// Get the number of bytes after the last allocation.
+    pub fn remaining(&self) -> usize {
+        self.heap_end - self.next
+    }
+
+    /// Allocate like [GlobalAlloc::alloc], but without a lock. This is
+    /// what the global allocator uses while the kernel is single
+    /// threaded, see [super::begin_single_threaded].
+    ///
+    /// This is unsafe for the same reasons as [GlobalAlloc::alloc].
+    pub unsafe fn alloc_unlocked(&mut self, layout: Layout) -> *mut u8 {
+        let alloc_start = align_up(self.next, layout.align());
+        let alloc_end = match alloc_start.checked_add(layout.size()) {
+            Some(end) => end,
+            None => return ptr::null_mut(),
+        };
+
+        if alloc_end > self.heap_end {
+            // Out of memory
+            ptr::null_mut()
+        }
+        else {
+            self.next = alloc_end;
+            self.allocations += 1;
+            alloc_start as *mut u8
+        }
+    }
+
+    /// Free an allocation like [GlobalAlloc::dealloc], but without a
+    /// lock, see [BumpAllocator::alloc_unlocked].
+    ///
+    /// This is unsafe for the same reasons as [GlobalAlloc::dealloc].
+    pub unsafe fn dealloc_unlocked(&mut self, _ptr: *mut u8) {
+        self.allocations -= 1;
+        if self.allocations == 0 {
+            self.next = self.heap_start;
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<BumpAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock().alloc_unlocked(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        self.lock().dealloc_unlocked(ptr)
+    }
+}
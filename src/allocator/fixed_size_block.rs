@@ -1,4 +1,4 @@
-use super::{linked_list::LinkedListAllocator, Locked};
+use super::{linked_list::LinkedListAllocator, poison, Locked};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::mem;
 
@@ -9,7 +9,15 @@ use core::mem;
 /// than a 64-bit pointer. Beyond some size, it is best to use a
 /// fallback allocator. We have to arbitrarily choose this based on our
 /// expectactions on what is large enough to be infrequent.
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Number of buckets in [FixedSizeBlockAllocator::size_histogram]. One
+/// for each of the `BLOCK_SIZES` plus one for the fallback allocator.
+pub const HISTOGRAM_BUCKETS: usize = BLOCK_SIZES.len() + 1;
+
+/// Number of free blocks of each size that
+/// [FixedSizeBlockAllocator::reclaim] keeps in its list.
+pub const RECLAIM_KEEP: usize = 8;
 
 struct ListNode {
     next: Option<&'static mut ListNode>,
@@ -51,13 +59,20 @@ struct ListNode {
 ///     not a great deal because after a block is freed it is reused.
 ///     But if startup performance seems problematic we could improve it
 ///     by preallocating a bunch of blocks.
-///   - The allocator would greatly benefit from a more sophisticated
+///   - Allocations that are too large for the fixed size lists are
+///     rounded up to a power of two before going to the fallback
+///     allocator, see [fallback_layout]. This wastes some memory, but
+///     it means that freed large regions come in a few sizes that are
+///     likely to be requested again, which keeps fragmentation of the
+///     fallback allocator in check.
+///   - The allocator would still benefit from a more sophisticated
 ///     large size allocator to minimize fragmentation. This will
 ///     prevent performance degradation and even out-of-memory panics
 ///     when the kernel runs for too long.
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
     fallback_allocator: Locked<LinkedListAllocator>,
+    histogram: [u64; HISTOGRAM_BUCKETS],
 }
 
 impl FixedSizeBlockAllocator {
@@ -67,6 +82,7 @@ impl FixedSizeBlockAllocator {
         FixedSizeBlockAllocator {
             list_heads: [EMPTY; BLOCK_SIZES.len()],
             fallback_allocator: Locked::new(LinkedListAllocator::new()),
+            histogram: [0; HISTOGRAM_BUCKETS],
         }
     }
 
@@ -80,6 +96,107 @@ impl FixedSizeBlockAllocator {
         // will lazily get memory from it for our Self::list_heads.
         self.fallback_allocator.lock().init(heap_start, heap_size);
     }
+
+    /// Get the number of allocations that have been made for each block
+    /// size. The entry at index `i` is for `BLOCK_SIZES[i]`, and the
+    /// last entry is for allocations that were too large for any of
+    /// them and went to the fallback allocator.
+    pub fn size_histogram(&self) -> [u64; HISTOGRAM_BUCKETS] {
+        self.histogram
+    }
+
+    /// Get the number of free blocks in the list of each of the
+    /// `BLOCK_SIZES`.
+    pub fn free_blocks(&self) -> [usize; BLOCK_SIZES.len()] {
+        let mut counts = [0; BLOCK_SIZES.len()];
+        for (count, head) in counts.iter_mut().zip(&self.list_heads) {
+            let mut node = head.as_deref();
+            while let Some(current) = node {
+                *count += 1;
+                node = current.next.as_deref();
+            }
+        }
+        counts
+    }
+
+    /// Give the free blocks of each size beyond the first
+    /// [RECLAIM_KEEP] back to the fallback allocator, so that memory
+    /// left over from a burst of allocations of one size can be used
+    /// for others. Returns the number of bytes given back, as the
+    /// fallback allocator counts them. That is more than the size of
+    /// the blocks if it rounds them up, eg every 8 byte block takes up
+    /// 16 bytes of the fallback allocator.
+    ///
+    /// Blocks that are next to each other are given back as a single
+    /// region, because the fallback allocator doesn't merge regions and
+    /// could otherwise only reuse them for allocations as small as a
+    /// block. This has to sort the blocks, so it takes quadratic time
+    /// in their number. It's meant to be called once in a while, not on
+    /// every allocation.
+    pub fn reclaim(&mut self) -> usize {
+        let mut reclaimed = 0;
+        for (index, &block_size) in BLOCK_SIZES.iter().enumerate() {
+            let mut rest = &mut self.list_heads[index];
+            for _ in 0..RECLAIM_KEEP {
+                match rest {
+                    Some(node) => rest = &mut node.next,
+                    None => break,
+                }
+            }
+
+            // Sort the blocks we give back by address.
+            let mut excess = rest.take();
+            let mut sorted: Option<&'static mut ListNode> = None;
+            while let Some(node) = excess {
+                excess = node.next.take();
+                let mut position = &mut sorted;
+                while position
+                    .as_deref()
+                    .map_or(false, |next| addr(next) < addr(node))
+                {
+                    position = &mut position.as_mut().unwrap().next;
+                }
+                node.next = position.take();
+                *position = Some(node);
+            }
+
+            let mut next_run = sorted;
+            while let Some(first) = next_run {
+                let start = addr(first);
+                let mut end = start + block_size;
+                next_run = first.next.take();
+                while let Some(node) = next_run {
+                    if addr(node) != end {
+                        next_run = Some(node);
+                        break;
+                    }
+                    end += block_size;
+                    next_run = node.next.take();
+                }
+
+                let layout =
+                    Layout::from_size_align(end - start, block_size).unwrap();
+                unsafe {
+                    self.fallback_allocator.dealloc(start as *mut u8, layout)
+                };
+                reclaimed += LinkedListAllocator::size_align(layout).0;
+            }
+        }
+        reclaimed
+    }
+
+    /// Get the size of the largest free region of the fallback
+    /// allocator, or `None` if its lock is held.
+    pub fn largest_free_region(&self) -> Option<usize> {
+        self.fallback_allocator
+            .try_lock()
+            .map(|fallback| fallback.largest_free_region())
+    }
+}
+
+/// Get the address of the block `node` is stored in.
+fn addr(node: &ListNode) -> usize {
+    node as *const ListNode as usize
 }
 
 /// Find the appropriate block size for the given layout. This is the
@@ -92,14 +209,60 @@ fn list_index(layout: &Layout) -> Option<usize> {
     BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
 }
 
+/// Where an allocation with a given layout is served from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Route {
+    /// One of the fixed size lists, given as an index into `BLOCK_SIZES`.
+    Block(usize),
+    /// The fallback allocator, with the layout it should be passed.
+    Fallback(Layout),
+}
+
+/// Decide where an allocation with `layout` is served from.
+///
+/// Both `alloc` and `dealloc` go through this, so the decision only
+/// depends on the layout and they can't disagree. The caller of
+/// `dealloc` has to pass the same layout that was used for `alloc`,
+/// including the alignment. A size that fits a block but has a larger
+/// alignment than any block is routed to the fallback allocator in both
+/// cases.
+fn route(layout: Layout) -> Route {
+    match list_index(&layout) {
+        Some(index) => Route::Block(index),
+        None => Route::Fallback(fallback_layout(layout)),
+    }
+}
+
+/// Adjust the layout of an allocation that is too large for any of the
+/// `BLOCK_SIZES` before passing it to the fallback allocator.
+///
+/// The size is rounded up to the next power of two. So for instance
+/// requests for 3000 and 4000 bytes both end up as 4096 byte regions,
+/// and a region freed by one can be reused by the other. This must be
+/// used for both `alloc` and `dealloc` so that the fallback allocator
+/// always sees the same layout for a given allocation, see [route].
+fn fallback_layout(layout: Layout) -> Layout {
+    layout
+        .size()
+        .checked_next_power_of_two()
+        .and_then(|size| Layout::from_size_align(size, layout.align()).ok())
+        .unwrap_or(layout)
+}
+
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
 
         // First check if the requested size should be handled by the
         // primary or fallback allocator.
-        match list_index(&layout) {
-            Some(index) => {
+        let route = route(layout);
+        let bucket = match route {
+            Route::Block(index) => index,
+            Route::Fallback(_) => BLOCK_SIZES.len(),
+        };
+        allocator.histogram[bucket] += 1;
+        match route {
+            Route::Block(index) => {
                 // For cases that should be handled by the main
                 // allocator, see if there are any available nodes of
                 // the appropriate size.
@@ -126,7 +289,7 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                     }
                 }
             }
-            None => {
+            Route::Fallback(layout) => {
                 // Block is too large for main allocator
                 allocator.fallback_allocator.alloc(layout)
             }
@@ -139,8 +302,17 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
         // This match mirrors the one we did in Self::alloc. This is
         // important because its return value determines which list we
         // initially used, or if we used the fallback allocator.
-        match list_index(&layout) {
-            Some(index) => {
+        match route(layout) {
+            Route::Block(index) => {
+                // Every block is aligned to its size. If it isn't, the
+                // layout must be different from the one it was
+                // allocated with and we'd corrupt the wrong list.
+                debug_assert_eq!(ptr as usize % BLOCK_SIZES[index], 0);
+
+                // The node below overwrites the start of the poison, so
+                // this has to come first.
+                poison(ptr, BLOCK_SIZES[index]);
+
                 let new_node = ListNode {
                     next: allocator.list_heads[index].take(),
                 };
@@ -152,9 +324,164 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                 new_node_ptr.write(new_node);
                 allocator.list_heads[index] = Some(&mut *new_node_ptr);
             }
-            None => {
+            Route::Fallback(layout) => {
+                debug_assert_eq!(ptr as usize % layout.align(), 0);
                 allocator.fallback_allocator.dealloc(ptr, layout);
             }
         }
     }
 }
+
+#[test_case]
+fn test_route_boundary() {
+    let last = BLOCK_SIZES.len() - 1;
+    let layout = |size, align| Layout::from_size_align(size, align).unwrap();
+
+    assert_eq!(route(layout(2048, 8)), Route::Block(last));
+    assert_eq!(route(layout(2049, 8)), Route::Fallback(layout(4096, 8)));
+    assert_eq!(route(layout(4096, 8)), Route::Fallback(layout(4096, 8)));
+    // Small enough for a block, but no block is aligned enough.
+    assert_eq!(
+        route(layout(2048, 4096)),
+        Route::Fallback(layout(2048, 4096))
+    );
+}
+
+#[test_case]
+fn test_free_blocks() {
+    static mut HEAP: [u64; 512] = [0; 512];
+
+    let allocator = Locked::new(FixedSizeBlockAllocator::new());
+    unsafe {
+        allocator
+            .lock()
+            .init(HEAP.as_mut_ptr() as usize, mem::size_of_val(&HEAP))
+    };
+
+    let layout = Layout::from_size_align(16, 8).unwrap();
+    unsafe {
+        let first = allocator.alloc(layout);
+        let second = allocator.alloc(layout);
+        allocator.dealloc(first, layout);
+        allocator.dealloc(second, layout);
+    }
+    let mut expected = [0; BLOCK_SIZES.len()];
+    expected[1] = 2;
+    assert_eq!(allocator.lock().free_blocks(), expected);
+}
+
+#[test_case]
+fn test_reclaim() {
+    static mut HEAP: [u64; 1024] = [0; 1024];
+
+    let allocator = Locked::new(FixedSizeBlockAllocator::new());
+    unsafe {
+        allocator
+            .lock()
+            .init(HEAP.as_mut_ptr() as usize, mem::size_of_val(&HEAP))
+    };
+
+    // Use up the whole heap for 64 byte blocks and free them again.
+    let small = Layout::from_size_align(64, 8).unwrap();
+    let mut blocks = [core::ptr::null_mut(); 128];
+    let mut count = 0;
+    unsafe {
+        while count < blocks.len() {
+            let block = allocator.alloc(small);
+            if block.is_null() {
+                break;
+            }
+            blocks[count] = block;
+            count += 1;
+        }
+        for &block in &blocks[..count] {
+            allocator.dealloc(block, small);
+        }
+    }
+
+    let large = Layout::from_size_align(4096, 8).unwrap();
+    assert!(unsafe { allocator.alloc(large) }.is_null());
+
+    let reclaimed = allocator.lock().reclaim();
+    assert_eq!(reclaimed, (count - RECLAIM_KEEP) * 64);
+    assert_eq!(allocator.lock().free_blocks()[3], RECLAIM_KEEP);
+    assert!(!unsafe { allocator.alloc(large) }.is_null());
+}
+
+/// 8 byte blocks are rounded up to 16 bytes by the fallback allocator,
+/// and that is what reclaiming them gives back.
+#[test_case]
+fn test_reclaim_counts_fallback_bytes() {
+    static mut HEAP: [u64; 256] = [0; 256];
+
+    let allocator = Locked::new(FixedSizeBlockAllocator::new());
+    unsafe {
+        allocator
+            .lock()
+            .init(HEAP.as_mut_ptr() as usize, mem::size_of_val(&HEAP))
+    };
+
+    let tiny = Layout::from_size_align(8, 8).unwrap();
+    let mut blocks = [core::ptr::null_mut(); RECLAIM_KEEP + 2];
+    unsafe {
+        for block in blocks.iter_mut() {
+            *block = allocator.alloc(tiny);
+            assert!(!block.is_null());
+        }
+        for &block in &blocks {
+            allocator.dealloc(block, tiny);
+        }
+    }
+
+    assert_eq!(allocator.lock().reclaim(), 2 * 16);
+}
+
+#[cfg(debug_assertions)]
+#[test_case]
+fn test_dealloc_poisons_memory() {
+    use super::POISON;
+
+    static mut HEAP: [u64; 512] = [0; 512];
+
+    let allocator = Locked::new(FixedSizeBlockAllocator::new());
+    unsafe {
+        allocator
+            .lock()
+            .init(HEAP.as_mut_ptr() as usize, mem::size_of_val(&HEAP))
+    };
+
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    unsafe {
+        let ptr = allocator.alloc(layout);
+        ptr.write_bytes(0x11, 64);
+        allocator.dealloc(ptr, layout);
+
+        // The node of the free list is at the start of the block.
+        for i in mem::size_of::<ListNode>()..64 {
+            assert_eq!(ptr.add(i).read_volatile(), POISON, "Byte {}", i);
+        }
+    }
+}
+
+#[test_case]
+fn test_allocator_trait() {
+    use alloc::vec::Vec;
+
+    static mut HEAP: [u64; 2048] = [0; 2048];
+
+    let allocator = Locked::new(FixedSizeBlockAllocator::new());
+    unsafe {
+        allocator
+            .lock()
+            .init(HEAP.as_mut_ptr() as usize, mem::size_of_val(&HEAP))
+    };
+
+    let heap = unsafe { HEAP.as_ptr_range() };
+    let mut vec = Vec::new_in(&allocator);
+    // Grows from the small blocks into the fallback allocator.
+    for i in 0..300_u64 {
+        vec.push(i);
+        assert!(heap.contains(&vec.as_ptr()));
+    }
+    assert!(vec.iter().copied().eq(0..300));
+}
@@ -1,4 +1,4 @@
-use super::{align_up, Locked};
+use super::{align_up, poison, Locked};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::{mem, ptr};
 
@@ -65,6 +65,17 @@ impl LinkedListAllocator {
         self.add_free_region(heap_start, heap_size);
     }
 
+    /// Get the size of the largest free region, or 0 if there are none.
+    pub fn largest_free_region(&self) -> usize {
+        let mut largest = 0;
+        let mut current = &self.head;
+        while let Some(ref region) = current.next {
+            largest = largest.max(region.size);
+            current = region;
+        }
+        largest
+    }
+
     /// Adds the given memory region to the front of the list.
     unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
         assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
@@ -141,7 +152,7 @@ impl LinkedListAllocator {
     ///
     /// Returns the adjusted size and alignment as a (size, align)
     /// tuple.
-    fn size_align(layout: Layout) -> (usize, usize) {
+    pub(super) fn size_align(layout: Layout) -> (usize, usize) {
         let layout = layout
             .align_to(mem::align_of::<ListNode>())
             .expect("Adjusting alignment failed")
@@ -173,6 +184,83 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let (size, _) = LinkedListAllocator::size_align(layout);
 
+        // The node written by add_free_region overwrites the start of
+        // the poison, so this has to come first.
+        poison(ptr, size);
         self.lock().add_free_region(ptr as usize, size);
     }
 }
+
+#[test_case]
+fn test_largest_free_region() {
+    static mut HEAP: [u64; 64] = [0; 64];
+
+    let allocator = Locked::new(LinkedListAllocator::new());
+    unsafe {
+        allocator
+            .lock()
+            .init(HEAP.as_mut_ptr() as usize, mem::size_of_val(&HEAP))
+    };
+    assert_eq!(allocator.lock().largest_free_region(), 512);
+
+    let layout = Layout::from_size_align(128, 8).unwrap();
+    let ptr = unsafe { allocator.alloc(layout) };
+    assert_eq!(allocator.lock().largest_free_region(), 384);
+    unsafe { allocator.dealloc(ptr, layout) };
+    // Freed regions are not merged.
+    assert_eq!(allocator.lock().largest_free_region(), 384);
+}
+
+#[cfg(debug_assertions)]
+#[test_case]
+fn test_dealloc_poisons_memory() {
+    use super::POISON;
+
+    static mut HEAP: [u64; 64] = [0; 64];
+
+    let allocator = Locked::new(LinkedListAllocator::new());
+    unsafe {
+        allocator
+            .lock()
+            .init(HEAP.as_mut_ptr() as usize, mem::size_of_val(&HEAP))
+    };
+
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    unsafe {
+        let ptr = allocator.alloc(layout);
+        ptr.write_bytes(0x11, 64);
+        allocator.dealloc(ptr, layout);
+
+        // The node of the freed region is at its start.
+        for i in mem::size_of::<ListNode>()..64 {
+            assert_eq!(ptr.add(i).read_volatile(), POISON, "Byte {}", i);
+        }
+    }
+}
+
+#[test_case]
+fn test_allocator_trait() {
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+
+    static mut HEAP: [u64; 128] = [0; 128];
+
+    let allocator = Locked::new(LinkedListAllocator::new());
+    unsafe {
+        allocator
+            .lock()
+            .init(HEAP.as_mut_ptr() as usize, mem::size_of_val(&HEAP))
+    };
+
+    let heap = unsafe { HEAP.as_ptr_range() };
+    let mut vec = Vec::new_in(&allocator);
+    vec.extend(0..32_u64);
+    assert!(heap.contains(&vec.as_ptr()));
+    assert!(vec.iter().copied().eq(0..32));
+
+    // Zero sized allocations don't use the region.
+    let free = allocator.lock().largest_free_region();
+    let empty = Box::new_in((), &allocator);
+    assert_eq!(allocator.lock().largest_free_region(), free);
+    drop(empty);
+}
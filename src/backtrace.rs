@@ -0,0 +1,178 @@
+//! Stack backtraces
+//!
+//! The kernel is built with frame pointers (see `frame-pointer` in the
+//! target specification), so every function saves the `rbp` of its
+//! caller right below its return address and points `rbp` at it. That
+//! makes the frames a linked list, which [walk] follows to find the
+//! return addresses.
+//!
+//! Backtraces are mostly wanted when something has already gone wrong,
+//! so the stack may well be corrupted. [walk] checks every frame
+//! before reading it and stops at the first one that looks wrong,
+//! instead of faulting while we're trying to report a fault.
+
+use core::ops::Range;
+
+/// Most frames that [walk] follows. This also stops it if the chain
+/// is corrupted in a way that the other checks don't catch.
+pub const MAX_DEPTH: usize = 32;
+
+/// How far above the stack pointer [print] looks for the end of the
+/// stack.
+const MAX_STACK_SPAN: u64 = 512 * 1024;
+
+/// Why [walk] stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalkEnd {
+    /// Reached a null frame pointer, ie the outermost frame.
+    Done,
+    /// The frame pointer was not within the stack.
+    OutOfBounds(u64),
+    /// The frame pointer was not 8 byte aligned.
+    Misaligned(u64),
+    /// The frame pointer was not above the previous one. The stack
+    /// grows down, so callers' frames are always at higher addresses,
+    /// and anything else would be a loop.
+    NotIncreasing(u64),
+    /// Followed [MAX_DEPTH] frames.
+    MaxDepth,
+}
+
+/// Follow the chain of frame pointers starting at `rbp` and call `f`
+/// with each return address, innermost first. Only frames that are
+/// entirely within `stack` are read, so as long as all of `stack` is
+/// mapped, this never faults.
+pub fn walk(rbp: u64, stack: Range<u64>, mut f: impl FnMut(u64)) -> WalkEnd {
+    let mut frame = rbp;
+    let mut previous = None;
+
+    for _ in 0..MAX_DEPTH {
+        if frame == 0 {
+            return WalkEnd::Done;
+        }
+        if frame % 8 != 0 {
+            return WalkEnd::Misaligned(frame);
+        }
+        // Each frame is the saved rbp followed by the return address.
+        let in_bounds = frame >= stack.start
+            && frame.checked_add(16).map_or(false, |end| end <= stack.end);
+        if !in_bounds {
+            return WalkEnd::OutOfBounds(frame);
+        }
+        if previous.map_or(false, |previous| frame <= previous) {
+            return WalkEnd::NotIncreasing(frame);
+        }
+
+        // Safe because we just checked that the frame is on the stack.
+        let (next, return_address) = unsafe {
+            let ptr = frame as *const u64;
+            (ptr.read_volatile(), ptr.add(1).read_volatile())
+        };
+        f(return_address);
+
+        previous = Some(frame);
+        frame = next;
+    }
+
+    WalkEnd::MaxDepth
+}
+
+/// Print a backtrace of the caller over serial, as a list of return
+/// addresses. This is meant for panic and exception handlers.
+///
+/// The stack is assumed to extend from the current stack pointer up to
+/// the first unmapped page. That is checked with
+/// [crate::memory::try_page_flags], so nothing is printed if that
+/// isn't available, eg before [crate::boot_init].
+#[inline(always)]
+pub fn print() {
+    let rbp: u64;
+    let rsp: u64;
+    unsafe {
+        core::arch::asm!(
+            "mov {rbp}, rbp",
+            "mov {rsp}, rsp",
+            rbp = out(reg) rbp,
+            rsp = out(reg) rsp,
+        );
+    }
+
+    crate::serial_println!("--- backtrace ---");
+    let end = walk(rbp, rsp..mapped_end(rsp), |return_address| {
+        crate::serial_println!("  {:#018x}", return_address);
+    });
+    if end != WalkEnd::Done {
+        crate::serial_println!("  stopped: {:?}", end);
+    }
+}
+
+/// Find where the mapped memory starting at `start` ends, looking at
+/// most [MAX_STACK_SPAN] bytes ahead.
+fn mapped_end(start: u64) -> u64 {
+    use x86_64::VirtAddr;
+
+    let mut page = start & !0xfff;
+    let limit = start.saturating_add(MAX_STACK_SPAN);
+    while page < limit && VirtAddr::try_new(page).is_ok() {
+        if crate::memory::try_page_flags(VirtAddr::new(page)).is_none() {
+            return page.max(start);
+        }
+        page += 4096;
+    }
+    page.min(limit)
+}
+
+/// Build a fake chain of frames in `stack`, where entry `i` is the
+/// frame at index `links[i]` with return address `i + 1`.
+#[cfg(test)]
+fn fake_frames(stack: &mut [u64; 64], links: &[(usize, usize)]) -> u64 {
+    let base = stack.as_ptr() as u64;
+    for (i, &(frame, next)) in links.iter().enumerate() {
+        stack[frame] = base + 8 * next as u64;
+        stack[frame + 1] = i as u64 + 1;
+    }
+    base + 8 * links[0].0 as u64
+}
+
+#[test_case]
+fn test_walk_stops_on_corrupted_chain() {
+    let mut stack = [0; 64];
+    let base = stack.as_ptr() as u64;
+    let bounds = base..base + 8 * 64;
+    let mut count = 0;
+
+    // The third frame points back at the first one.
+    let rbp = fake_frames(&mut stack, &[(0, 4), (4, 10), (10, 0)]);
+    let end = walk(rbp, bounds.clone(), |_| count += 1);
+    assert_eq!(end, WalkEnd::NotIncreasing(base));
+    assert_eq!(count, 3);
+
+    // The second frame points outside of the stack.
+    let rbp = fake_frames(&mut stack, &[(0, 4)]);
+    stack[4] = 0x1000;
+    count = 0;
+    assert_eq!(
+        walk(rbp, bounds.clone(), |_| count += 1),
+        WalkEnd::OutOfBounds(0x1000)
+    );
+    assert_eq!(count, 2);
+
+    stack[4] = base + 3;
+    assert_eq!(walk(rbp, bounds, |_| {}), WalkEnd::Misaligned(base + 3));
+}
+
+#[test_case]
+fn test_walk_depth_is_limited() {
+    let mut stack = [0; 64];
+    let base = stack.as_ptr() as u64;
+    let mut links = [(0, 0); 31];
+    for (i, link) in links.iter_mut().enumerate() {
+        *link = (2 * i, 2 * i + 2);
+    }
+
+    let rbp = fake_frames(&mut stack, &links);
+    let mut return_addresses = 0;
+    let end = walk(rbp, base..base + 8 * 64, |_| return_addresses += 1);
+    assert_eq!(end, WalkEnd::MaxDepth);
+    assert_eq!(return_addresses, MAX_DEPTH);
+}
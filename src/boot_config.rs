@@ -0,0 +1,181 @@
+//! Kernel command line
+//!
+//! [crate::boot_init] reads its configuration from a string of
+//! whitespace separated `key=value` entries, eg `heap=200k log=debug`.
+//! The string is baked into the kernel from the `BLOG_OS_BOOT_CONFIG`
+//! environment variable at build time, see [BOOT_CONFIG]. The supported
+//! keys are:
+//!  - `heap`: heap size in bytes, optionally with a `k` or `m` suffix
+//!  - `allocator`: one of `bump`, `linked_list` or `fixed_size_block`
+//!  - `log`: one of `error`, `warn`, `info`, `debug` or `trace`, see
+//!    [crate::log_level]
+//!  - `acpi_pm1a`: IO port of the ACPI PM1a control register, in
+//!    decimal or hex with a `0x` prefix, eg `acpi_pm1a=0x604` for qemu.
+//!    This lets [crate::shutdown] power off, see [crate::power].
+
+use crate::allocator::{self, Backend};
+
+/// The configuration string used by [crate::boot_init].
+pub const BOOT_CONFIG: &str = match option_env!("BLOG_OS_BOOT_CONFIG") {
+    Some(config) => config,
+    None => "",
+};
+
+/// How much diagnostic output the kernel should produce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+/// Settings parsed from the kernel command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootConfig {
+    pub heap_size: usize,
+    /// The allocator to use, or `None` to keep whichever was selected
+    /// with [allocator::set_backend].
+    pub allocator: Option<Backend>,
+    pub log_level: LogLevel,
+    /// The port passed to [crate::power::set_acpi_poweroff], if any.
+    pub acpi_pm1a: Option<u16>,
+}
+
+impl Default for BootConfig {
+    fn default() -> Self {
+        BootConfig {
+            heap_size: allocator::HEAP_SIZE,
+            allocator: None,
+            log_level: LogLevel::Info,
+            acpi_pm1a: None,
+        }
+    }
+}
+
+/// Errors returned by [parse_boot_config]. Each holds the entry that
+/// could not be parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootConfigError<'a> {
+    /// The entry is not of the form `key=value`.
+    Malformed(&'a str),
+    /// The key is not one we know about.
+    UnknownKey(&'a str),
+    /// The value is not valid for its key.
+    InvalidValue(&'a str),
+}
+
+/// Parse a kernel command line. Keys that are not present keep their
+/// [Default] values.
+pub fn parse_boot_config(config: &str) -> Result<BootConfig, BootConfigError> {
+    let mut boot_config = BootConfig::default();
+
+    for entry in config.split_whitespace() {
+        let (key, value) = entry
+            .split_once('=')
+            .ok_or(BootConfigError::Malformed(entry))?;
+        let invalid = BootConfigError::InvalidValue(entry);
+
+        match key {
+            "heap" => {
+                boot_config.heap_size = parse_size(value).ok_or(invalid)?
+            }
+            "allocator" => {
+                let backend = match value {
+                    "bump" => Backend::Bump,
+                    "linked_list" => Backend::LinkedList,
+                    "fixed_size_block" => Backend::FixedSizeBlock,
+                    _ => return Err(invalid),
+                };
+                boot_config.allocator = Some(backend);
+            }
+            "log" => {
+                boot_config.log_level = match value {
+                    "error" => LogLevel::Error,
+                    "warn" => LogLevel::Warn,
+                    "info" => LogLevel::Info,
+                    "debug" => LogLevel::Debug,
+                    "trace" => LogLevel::Trace,
+                    _ => return Err(invalid),
+                };
+            }
+            "acpi_pm1a" => {
+                boot_config.acpi_pm1a = Some(parse_port(value).ok_or(invalid)?)
+            }
+            _ => return Err(BootConfigError::UnknownKey(entry)),
+        }
+    }
+
+    Ok(boot_config)
+}
+
+/// Parse a nonzero size in bytes with an optional `k` or `m` suffix.
+fn parse_size(value: &str) -> Option<usize> {
+    let (digits, multiplier) = match value.as_bytes().last()? {
+        b'k' | b'K' => (&value[..value.len() - 1], 1024),
+        b'm' | b'M' => (&value[..value.len() - 1], 1024 * 1024),
+        _ => (value, 1),
+    };
+
+    let size = digits.parse::<usize>().ok()?.checked_mul(multiplier)?;
+    if size == 0 {
+        None
+    }
+    else {
+        Some(size)
+    }
+}
+
+/// Parse an IO port number, in hex if it starts with `0x`.
+fn parse_port(value: &str) -> Option<u16> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+#[test_case]
+fn test_parse_boot_config() {
+    assert_eq!(
+        parse_boot_config("heap=200k log=debug"),
+        Ok(BootConfig {
+            heap_size: 200 * 1024,
+            allocator: None,
+            log_level: LogLevel::Debug,
+            acpi_pm1a: None,
+        })
+    );
+    assert_eq!(
+        parse_boot_config("acpi_pm1a=0x604").map(|config| config.acpi_pm1a),
+        Ok(Some(0x604))
+    );
+}
+
+#[test_case]
+fn test_parse_boot_config_errors() {
+    assert_eq!(parse_boot_config(""), Ok(BootConfig::default()));
+    assert_eq!(
+        parse_boot_config("allocator=bump"),
+        Ok(BootConfig {
+            allocator: Some(Backend::Bump),
+            ..BootConfig::default()
+        })
+    );
+    assert_eq!(
+        parse_boot_config("heap"),
+        Err(BootConfigError::Malformed("heap"))
+    );
+    assert_eq!(
+        parse_boot_config("heap=0"),
+        Err(BootConfigError::InvalidValue("heap=0"))
+    );
+    assert_eq!(
+        parse_boot_config("acpi_pm1a=0x10000"),
+        Err(BootConfigError::InvalidValue("acpi_pm1a=0x10000"))
+    );
+    assert_eq!(
+        parse_boot_config("colour=red"),
+        Err(BootConfigError::UnknownKey("colour=red"))
+    );
+}
@@ -0,0 +1,137 @@
+//! Named counters
+//!
+//! Diagnostics like the self test want to look up counters by name, eg
+//! `"timer"`, without every subsystem having its own accessor for each
+//! of them. [NamedCounters] is a fixed set of counters with names that
+//! are chosen when it is created. There's no hashing, lookups compare
+//! the names one by one, so it's meant for a handful of counters. Code
+//! that updates a counter often, like an interrupt handler, uses its
+//! index instead, see [NamedCounters::inc_at].
+//!
+//! See [crate::interrupts::counters] and [crate::allocator::counters].
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// A fixed set of `N` counters, each with a name.
+pub struct NamedCounters<const N: usize> {
+    counters: [(&'static str, AtomicU64); N],
+}
+
+impl<const N: usize> NamedCounters<N> {
+    /// Create counters with the given `names`, all starting at zero.
+    /// The names should be unique, lookups only find the first counter
+    /// with a name.
+    pub const fn new(names: [&'static str; N]) -> Self {
+        const UNNAMED: (&str, AtomicU64) = ("", AtomicU64::new(0));
+
+        let mut counters = [UNNAMED; N];
+        let mut i = 0;
+        while i < N {
+            counters[i].0 = names[i];
+            i += 1;
+        }
+        NamedCounters { counters }
+    }
+
+    fn find(&self, name: &str) -> Option<&AtomicU64> {
+        self.counters
+            .iter()
+            .find(|(counter_name, _)| *counter_name == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Add one to the counter called `name`. Returns `false` if there is
+    /// no such counter.
+    pub fn inc(&self, name: &str) -> bool {
+        self.add(name, 1)
+    }
+
+    /// Add `amount` to the counter called `name`. Returns `false` if
+    /// there is no such counter.
+    pub fn add(&self, name: &str, amount: u64) -> bool {
+        match self.find(name) {
+            Some(value) => {
+                value.fetch_add(amount, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Add one to the counter at `index`, ie the one named by
+    /// `names[index]` in [NamedCounters::new]. Unlike [NamedCounters::inc]
+    /// this doesn't search for the name, and a wrong index panics instead
+    /// of being ignored.
+    pub fn inc_at(&self, index: usize) {
+        self.add_at(index, 1)
+    }
+
+    /// Add `amount` to the counter at `index`, see
+    /// [NamedCounters::inc_at].
+    pub fn add_at(&self, index: usize, amount: u64) {
+        self.counters[index].1.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    /// Get the value of the counter at `index`, see
+    /// [NamedCounters::inc_at].
+    pub fn get_at(&self, index: usize) -> u64 {
+        self.counters[index].1.load(Ordering::Relaxed)
+    }
+
+    /// Get the value of the counter called `name`, or `None` if there is
+    /// no such counter.
+    pub fn get(&self, name: &str) -> Option<u64> {
+        self.find(name).map(|value| value.load(Ordering::Relaxed))
+    }
+
+    /// Get the name and value of every counter, in the order they were
+    /// given to [NamedCounters::new].
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        self.counters
+            .iter()
+            .map(|(name, value)| (*name, value.load(Ordering::Relaxed)))
+    }
+}
+
+/// Prints `name: value` pairs separated by commas.
+impl<const N: usize> fmt::Display for NamedCounters<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, (name, value)) in self.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}: {}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_named_counters() {
+    let counters = NamedCounters::new(["timer", "keyboard"]);
+
+    for _ in 0..3 {
+        assert!(counters.inc("timer"));
+    }
+    assert!(counters.inc("keyboard"));
+    assert!(counters.add("keyboard", 4));
+
+    assert_eq!(counters.get("timer"), Some(3));
+    assert_eq!(counters.get("keyboard"), Some(5));
+    assert_eq!(counters.get("mouse"), None);
+    assert!(!counters.inc("mouse"));
+}
+
+#[test_case]
+fn test_named_counters_by_index() {
+    let counters = NamedCounters::new(["timer", "keyboard"]);
+
+    counters.inc_at(1);
+    counters.add_at(1, 2);
+    counters.inc("keyboard");
+
+    assert_eq!(counters.get_at(0), 0);
+    assert_eq!(counters.get_at(1), 4);
+    assert_eq!(counters.get("keyboard"), Some(4));
+}
@@ -0,0 +1,143 @@
+//! CPU features and timing
+//!
+//! Reports CPU features that the rest of the kernel needs to know about,
+//! like [phys_addr_bits]. Also provides access to the time stamp
+//! counter (TSC) and short, precise busy-wait delays based on it. The
+//! TSC frequency is not known up front, so it is calibrated against the
+//! timer interrupt the first time it is needed. That requires
+//! interrupts to be enabled, ie [crate::init] must have been called.
+
+use crate::interrupts;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Timer ticks to measure over when calibrating. More ticks mean a more
+/// accurate result but a longer delay. 5 ticks are roughly 275ms at the
+/// default timer frequency.
+const CALIBRATION_TICKS: u64 = 5;
+
+/// TSC cycles per second, or zero if not calibrated yet.
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// CPUID leaf with the physical and linear address sizes.
+const CPUID_ADDRESS_SIZES: u32 = 0x8000_0008;
+
+/// Get the number of physical address bits supported by the CPU.
+/// Physical addresses must be below `1 << phys_addr_bits()`.
+///
+/// This comes from CPUID leaf `0x80000008`. CPUs that don't have that
+/// leaf support 36 bits.
+pub fn phys_addr_bits() -> u8 {
+    use core::arch::x86_64::__cpuid;
+
+    // Safe because every x86_64 CPU has CPUID, and leaf 0x80000000
+    // reports the highest supported extended leaf.
+    unsafe {
+        if __cpuid(0x8000_0000).eax < CPUID_ADDRESS_SIZES {
+            return 36;
+        }
+        __cpuid(CPUID_ADDRESS_SIZES).eax as u8
+    }
+}
+
+/// Read the time stamp counter.
+pub fn rdtsc() -> u64 {
+    // Safe because rdtsc has no side effects. Every x86_64 CPU has it.
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Get the TSC frequency in cycles per second, calibrating it first if
+/// this is the first call.
+///
+/// Panics if calibration is required but interrupts are disabled,
+/// because it would never finish.
+pub fn tsc_frequency() -> u64 {
+    match TSC_FREQUENCY.load(Ordering::Relaxed) {
+        0 => {
+            let frequency = calibrate_tsc();
+            TSC_FREQUENCY.store(frequency, Ordering::Relaxed);
+            frequency
+        }
+        frequency => frequency,
+    }
+}
+
+/// Measure how many TSC cycles go by during [CALIBRATION_TICKS] timer
+/// ticks.
+fn calibrate_tsc() -> u64 {
+    assert!(
+        x86_64::instructions::interrupts::are_enabled(),
+        "TSC calibration requires interrupts"
+    );
+
+    // Start right after a tick so that we measure whole ticks.
+    let start_tick = wait_for_tick(interrupts::ticks());
+    let start = rdtsc();
+    let mut tick = start_tick;
+    while tick - start_tick < CALIBRATION_TICKS {
+        tick = wait_for_tick(tick);
+    }
+    let cycles = rdtsc() - start;
+
+    // The timer frequency is the PIT frequency divided by the divisor.
+    let pit_cycles = u64::from(interrupts::timer_divisor()) * CALIBRATION_TICKS;
+    cycles * u64::from(interrupts::PIT_FREQUENCY) / pit_cycles
+}
+
+/// Halt until the tick count is different than `tick` and return the
+/// new value.
+fn wait_for_tick(tick: u64) -> u64 {
+    loop {
+        x86_64::instructions::hlt();
+        let now = interrupts::ticks();
+        if now != tick {
+            return now;
+        }
+    }
+}
+
+/// Convert a number of TSC cycles to nanoseconds.
+pub fn cycles_to_ns(cycles: u64) -> u64 {
+    (cycles as u128 * 1_000_000_000 / tsc_frequency() as u128) as u64
+}
+
+/// Convert nanoseconds to a number of TSC cycles.
+pub fn ns_to_cycles(ns: u64) -> u64 {
+    (ns as u128 * tsc_frequency() as u128 / 1_000_000_000) as u64
+}
+
+/// Wait for at least `cycles` TSC cycles.
+///
+/// This busy-waits, ie it keeps the CPU running instead of using `hlt`,
+/// which is what makes it precise. Only use it for short delays, like
+/// the ones required by device drivers.
+pub fn sleep_cycles(cycles: u64) {
+    let start = rdtsc();
+    while rdtsc() - start < cycles {
+        core::hint::spin_loop();
+    }
+}
+
+/// Wait for at least `ns` nanoseconds. Like [sleep_cycles], this
+/// busy-waits and is meant for delays in the microsecond range.
+pub fn sleep_ns(ns: u64) {
+    sleep_cycles(ns_to_cycles(ns));
+}
+
+#[test_case]
+fn test_phys_addr_bits() {
+    let bits = phys_addr_bits();
+    assert!((36..=52).contains(&bits), "{} physical address bits", bits);
+}
+
+#[test_case]
+fn test_sleep_ns() {
+    let ns = 50_000;
+    // Make sure we're calibrated, so that it's not part of the timing.
+    tsc_frequency();
+
+    let start = rdtsc();
+    sleep_ns(ns);
+    let elapsed = cycles_to_ns(rdtsc() - start);
+
+    assert!(elapsed >= ns, "Slept for {}ns instead of {}ns", elapsed, ns);
+}
@@ -3,7 +3,13 @@
 //!
 //! All you have to do is call [init] once. Normally you would do that
 //! through [crate::init].
+//!
+//! If the GDT, TSS or double fault stack are broken, the first double
+//! fault turns into a triple fault, which silently resets the machine.
+//! [check] looks for such problems up front. [crate::init] calls it and
+//! prints what it finds over serial.
 
+use crate::memory::{self, VirtRange};
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{
     Descriptor, GlobalDescriptorTable, SegmentSelector,
@@ -13,6 +19,12 @@ use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
+
+/// The smallest double fault stack that [check] accepts. The handler
+/// prints with `core::fmt`, which easily takes up a page of stack.
+const MIN_IST_STACK_SIZE: usize = 4096;
+
 lazy_static! {
     /// Task State Segment that creates a known clean stack for our
     /// double fault handler. This is useful because the double fault
@@ -23,11 +35,11 @@ lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+            static mut STACK: [u8; DOUBLE_FAULT_STACK_SIZE] =
+                [0; DOUBLE_FAULT_STACK_SIZE];
 
             let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
+            let stack_end = stack_start + DOUBLE_FAULT_STACK_SIZE;
             stack_end
         };
         tss
@@ -64,3 +76,126 @@ pub fn init() {
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// Problems found by [check]. Each of them means that a double fault
+/// would become a triple fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GdtError {
+    /// The GDT register doesn't point to our GDT.
+    GdtNotLoaded,
+    /// CS holds this selector instead of our code segment.
+    WrongCodeSegment(SegmentSelector),
+    /// The task register holds this selector instead of our TSS.
+    TssNotLoaded(SegmentSelector),
+    /// The IST entry of the double fault handler is null.
+    IstStackMissing,
+    /// The double fault stack is only this many bytes.
+    IstStackTooSmall(usize),
+    /// This address of the double fault stack is not mapped.
+    IstStackUnmapped(VirtAddr),
+}
+
+/// Check that our GDT and TSS are loaded, and that the double fault
+/// stack has a sensible size and is mapped.
+///
+/// The mapping can only be checked after [memory::install], before
+/// that it is assumed to be fine. The stack is a static, so the
+/// bootloader maps it with the rest of the kernel anyway.
+pub fn check() -> Result<(), GdtError> {
+    use x86_64::instructions::segmentation::{Segment, CS};
+    use x86_64::instructions::tables::sgdt;
+
+    if sgdt().base != VirtAddr::from_ptr(&GDT.0) {
+        return Err(GdtError::GdtNotLoaded);
+    }
+    let code_selector = CS::get_reg();
+    if code_selector != GDT.1.code_selector {
+        return Err(GdtError::WrongCodeSegment(code_selector));
+    }
+    let tss_selector = task_register();
+    if tss_selector != GDT.1.tss_selector {
+        return Err(GdtError::TssNotLoaded(tss_selector));
+    }
+
+    let installed = memory::is_installed();
+    check_ist_stack(
+        TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize],
+        DOUBLE_FAULT_STACK_SIZE,
+        |addr| !installed || memory::try_page_flags(addr).is_some(),
+    )
+}
+
+/// Read the task register, ie the selector of the loaded TSS.
+fn task_register() -> SegmentSelector {
+    let selector: u16;
+    // Safe because `str` only reads the task register.
+    unsafe {
+        core::arch::asm!(
+            "str {0:x}",
+            out(reg) selector,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+    SegmentSelector(selector)
+}
+
+/// Check an IST stack of `size` bytes that ends at `top`. `is_mapped`
+/// tells whether the page at the given address is mapped.
+fn check_ist_stack(
+    top: VirtAddr,
+    size: usize,
+    is_mapped: impl Fn(VirtAddr) -> bool,
+) -> Result<(), GdtError> {
+    if top.is_null() {
+        return Err(GdtError::IstStackMissing);
+    }
+    if size < MIN_IST_STACK_SIZE {
+        return Err(GdtError::IstStackTooSmall(size));
+    }
+
+    let bottom = top
+        .as_u64()
+        .checked_sub(size as u64)
+        .and_then(|bottom| VirtAddr::try_new(bottom).ok())
+        .ok_or(GdtError::IstStackUnmapped(VirtAddr::zero()))?;
+    for page in VirtRange::new(bottom, size as u64).pages() {
+        let addr = page.start_address().max(bottom);
+        if !is_mapped(addr) {
+            return Err(GdtError::IstStackUnmapped(addr));
+        }
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_check() {
+    assert_eq!(check(), Ok(()));
+}
+
+#[test_case]
+fn test_check_ist_stack() {
+    let top = VirtAddr::new(0x10_0000);
+    let mapped = |_| true;
+
+    assert_eq!(check_ist_stack(top, 4096 * 5, mapped), Ok(()));
+    assert_eq!(
+        check_ist_stack(VirtAddr::zero(), 4096 * 5, mapped),
+        Err(GdtError::IstStackMissing)
+    );
+    assert_eq!(
+        check_ist_stack(top, 0, mapped),
+        Err(GdtError::IstStackTooSmall(0))
+    );
+    assert_eq!(
+        check_ist_stack(top, 100, mapped),
+        Err(GdtError::IstStackTooSmall(100))
+    );
+
+    // The bottom page is missing, like a guard page that ended up
+    // inside the stack.
+    let bottom = top - 4096 * 5u64;
+    assert_eq!(
+        check_ist_stack(top, 4096 * 5, |addr| addr != bottom),
+        Err(GdtError::IstStackUnmapped(bottom))
+    );
+}
@@ -0,0 +1,154 @@
+//! Hex dumps of memory
+//!
+//! [hexdump] prints a region of memory over serial in the classic
+//! format of `hexdump -C`: the offset, 16 bytes in hex and the same
+//! bytes as ASCII, with '.' for anything that isn't printable.
+//!
+//! ```text
+//! hexdump of 0x1000 bytes at 0x444444440000
+//! 00000000  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 0a 00 00  |Hello, world!...|
+//! ```
+
+use crate::memory::{self, VirtRange};
+use core::fmt;
+use x86_64::VirtAddr;
+
+/// Number of bytes shown per row.
+const ROW_SIZE: usize = 16;
+
+/// Reasons why [hexdump] refuses to read a region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HexdumpError {
+    /// The region wraps around the address space or is not canonical.
+    InvalidRange,
+    /// This address is not mapped, or we couldn't check because
+    /// [memory::install] hasn't been called or the mapper is locked.
+    Unmapped(VirtAddr),
+}
+
+/// Print `len` bytes starting at `addr` over serial.
+///
+/// Every page of the region is checked with [memory::try_page_flags]
+/// before anything is read, so passing an unmapped address prints an
+/// error instead of causing a page fault. The bytes are read with
+/// volatile reads, so this is fine for memory that is concurrently
+/// modified, but reading device memory may still have side effects.
+pub fn hexdump(addr: *const u8, len: usize) -> Result<(), HexdumpError> {
+    check_mapped(addr, len)?;
+
+    crate::serial_println!("hexdump of {:#x} bytes at {:?}", len, addr);
+    for offset in (0..len).step_by(ROW_SIZE) {
+        let mut bytes = [0; ROW_SIZE];
+        let row_len = ROW_SIZE.min(len - offset);
+        for (i, byte) in bytes[..row_len].iter_mut().enumerate() {
+            // Safe because check_mapped made sure that the whole region
+            // is mapped.
+            *byte = unsafe { addr.add(offset + i).read_volatile() };
+        }
+        crate::serial_println!(
+            "{}",
+            HexRow {
+                offset,
+                bytes: &bytes[..row_len],
+            }
+        );
+    }
+    Ok(())
+}
+
+/// Make sure that every page of `len` bytes at `addr` is mapped.
+fn check_mapped(addr: *const u8, len: usize) -> Result<(), HexdumpError> {
+    let start = VirtAddr::try_new(addr as u64)
+        .map_err(|_| HexdumpError::InvalidRange)?;
+    let range = VirtRange::try_new(start, len as u64)
+        .ok_or(HexdumpError::InvalidRange)?;
+
+    for page in range.pages() {
+        let page_addr = page.start_address().max(start);
+        if memory::try_page_flags(page_addr).is_none() {
+            return Err(HexdumpError::Unmapped(page_addr));
+        }
+    }
+    Ok(())
+}
+
+/// A single row of a hex dump. Rows shorter than [ROW_SIZE] are padded
+/// so that the ASCII column lines up.
+struct HexRow<'a> {
+    offset: usize,
+    bytes: &'a [u8],
+}
+
+impl fmt::Display for HexRow<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:08x} ", self.offset)?;
+        for i in 0..ROW_SIZE {
+            // An extra space in the middle, for readability.
+            if i == ROW_SIZE / 2 {
+                f.write_str(" ")?;
+            }
+            match self.bytes.get(i) {
+                Some(byte) => write!(f, " {:02x}", byte)?,
+                None => f.write_str("   ")?,
+            }
+        }
+
+        f.write_str("  |")?;
+        for &byte in self.bytes {
+            let c = if byte.is_ascii_graphic() || byte == b' ' {
+                char::from(byte)
+            }
+            else {
+                '.'
+            };
+            write!(f, "{}", c)?;
+        }
+        f.write_str("|")
+    }
+}
+
+#[test_case]
+fn test_hex_row_format() {
+    use core::fmt::Write;
+
+    let buffer = b"Hello, world!\n\x00\xffabc";
+
+    let mut out = crate::Capture::<80>::new();
+    let row = HexRow {
+        offset: 0,
+        bytes: &buffer[..16],
+    };
+    write!(out, "{}", row).unwrap();
+    assert_eq!(
+        out.as_bytes(),
+        &b"00000000  48 65 6c 6c 6f 2c 20 77  \
+           6f 72 6c 64 21 0a 00 ff  |Hello, world!...|"[..]
+    );
+
+    let mut out = crate::Capture::<80>::new();
+    let row = HexRow {
+        offset: 16,
+        bytes: &buffer[16..],
+    };
+    write!(out, "{}", row).unwrap();
+    assert_eq!(
+        out.as_bytes(),
+        &b"00000010  61 62 63                                         \
+           \x20|abc|"[..]
+    );
+}
+
+#[test_case]
+fn test_hexdump_checks_range() {
+    assert_eq!(
+        hexdump(usize::MAX as *const u8, 2),
+        Err(HexdumpError::InvalidRange)
+    );
+    // The test kernel doesn't install the mapper, so nothing can be
+    // checked.
+    let buffer = [0u8; 4];
+    assert_eq!(
+        hexdump(buffer.as_ptr(), buffer.len()),
+        Err(HexdumpError::Unmapped(VirtAddr::from_ptr(buffer.as_ptr())))
+    );
+}
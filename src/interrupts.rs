@@ -3,12 +3,25 @@
 //! Defines and enables handlers for hardware interrupts, eg getting
 //! input from a keyboard. Just call [init_idt] to register the
 //! callbacks. Normally, you would rely on [crate::init] to do this.
+//!
+//! Other code can install handlers of its own with [register] and
+//! [register_with_error_code], either before or after [init_idt].
+//!
+//! With the `latency-stats` feature, the time spent in handlers and
+//! with interrupts disabled is measured, see [latency_stats].
 
-use crate::{gdt, hlt_loop, print, println};
+use crate::counters::NamedCounters;
+use crate::{gdt, hlt_loop, print, try_println};
+use core::fmt;
+use core::panic::PanicInfo;
+use core::sync::atomic::{
+    AtomicBool, AtomicU16, AtomicU32, AtomicU64, AtomicUsize, Ordering,
+};
 use pic8259::ChainedPics;
 use spin;
 use x86_64::structures::idt::{
-    InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode,
+    HandlerFunc, HandlerFuncWithErrCode, InterruptDescriptorTable,
+    InterruptStackFrame, PageFaultErrorCode,
 };
 
 use lazy_static::lazy_static;
@@ -21,6 +34,8 @@ const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 32;
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    /// The first serial port, see [crate::serial].
+    Serial = PIC_1_OFFSET + 4,
 }
 
 impl InterruptIndex {
@@ -34,9 +49,24 @@ impl InterruptIndex {
 }
 
 lazy_static! {
-    static ref IDT: InterruptDescriptorTable = {
+    /// By default, every handler is registered as an interrupt gate,
+    /// which means that the CPU disables interrupts on entry. Passing
+    /// `false` to `disable_interrupts` on the options returned by
+    /// `set_handler_fn` makes it a trap gate instead, which leaves them
+    /// enabled. A trap gate handler can be interrupted at any point, so
+    /// it must not take any lock that the other handlers take, unless
+    /// it disables interrupts while holding it.
+    ///
+    /// The lock is only there so that [register] can modify the table.
+    /// The CPU reads it without taking the lock, so handlers never
+    /// need it.
+    static ref IDT: spin::Mutex<InterruptDescriptorTable> = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        // A trap gate, so that the timer and keyboard keep working
+        // while we are paused at a breakpoint.
+        idt.breakpoint
+            .set_handler_fn(breakpoint_handler)
+            .disable_interrupts(false);
         unsafe {
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
@@ -46,25 +76,694 @@ lazy_static! {
             .set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()]
             .set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Serial.as_usize()]
+            .set_handler_fn(serial_interrupt_handler);
         idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.general_protection_fault
+            .set_handler_fn(general_protection_fault_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.overflow.set_handler_fn(overflow_handler);
+        idt.bound_range_exceeded
+            .set_handler_fn(bound_range_exceeded_handler);
+        idt.alignment_check.set_handler_fn(alignment_check_handler);
+        idt.machine_check.set_handler_fn(machine_check_handler);
 
-        idt
+        spin::Mutex::new(idt)
     };
 }
 
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+/// Unmask the serial interrupt, which the firmware leaves masked.
+/// [crate::init] calls this after initializing the [PICS].
+pub(crate) fn unmask_serial_interrupt() {
+    use x86_64::instructions::port::Port;
+
+    /// Data port of the primary PIC, which holds its interrupt mask.
+    const PIC_1_DATA: u16 = 0x21;
+
+    let irq = InterruptIndex::Serial.as_u8() - PIC_1_OFFSET;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // Hold the lock so that nobody else talks to the PICs while we
+        // change the mask.
+        let _pics = PICS.lock();
+        let mut mask = Port::<u8>::new(PIC_1_DATA);
+        unsafe {
+            let value = mask.read();
+            mask.write(value & !(1 << irq));
+        }
+    });
+}
+
+/// Number of timer interrupts since the PICs were initialized.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
 /// Initialize the interrupt descriptor table, ie register interrupt
 /// handlers.
 pub fn init_idt() {
-    IDT.load();
+    let idt = IDT.lock();
+    // Safe because the table is in a static, so it is never moved or
+    // dropped while loaded.
+    unsafe { idt.load_unsafe() };
+}
+
+/// Get a copy of the kernel's IDT, eg as the starting point of a custom
+/// one for [crate::with_custom_idt].
+pub fn kernel_idt() -> InterruptDescriptorTable {
+    x86_64::instructions::interrupts::without_interrupts(|| IDT.lock().clone())
+}
+
+/// Install `handler` for the interrupt with the given `index`. Only the
+/// vectors after the CPU exceptions, ie 32 and up, can be registered
+/// this way. This includes the ones used by the [PICS], so it can
+/// replace the handlers of this module.
+///
+/// Panics if `index` is below 32.
+pub fn register(index: u8, handler: HandlerFunc) {
+    assert!(index >= 32, "Vector {} is reserved for exceptions", index);
+    // The CPU must never see a half written entry.
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        IDT.lock()[usize::from(index)].set_handler_fn(handler);
+    });
+}
+
+/// CPU exceptions that push an error code, for
+/// [register_with_error_code].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCodeException {
+    InvalidTss,
+    SegmentNotPresent,
+    StackSegmentFault,
+    GeneralProtectionFault,
+    AlignmentCheck,
+}
+
+/// Install `handler` for one of the exceptions that push an error code.
+/// The handler gets the error code as its second argument.
+pub fn register_with_error_code(
+    exception: ErrorCodeException,
+    handler: HandlerFuncWithErrCode,
+) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut idt = IDT.lock();
+        let entry = match exception {
+            ErrorCodeException::InvalidTss => &mut idt.invalid_tss,
+            ErrorCodeException::SegmentNotPresent => {
+                &mut idt.segment_not_present
+            }
+            ErrorCodeException::StackSegmentFault => {
+                &mut idt.stack_segment_fault
+            }
+            ErrorCodeException::GeneralProtectionFault => {
+                &mut idt.general_protection_fault
+            }
+            ErrorCodeException::AlignmentCheck => &mut idt.alignment_check,
+        };
+        entry.set_handler_fn(handler);
+    });
+}
+
+/// Get the number of timer interrupts that have fired so far. The timer
+/// runs at the default PIT frequency of roughly 18.2 Hz, unless it was
+/// changed with [set_timer_frequency].
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Frequency of the PIT oscillator in Hz. The timer fires at this
+/// frequency divided by the divisor of channel 0.
+pub const PIT_FREQUENCY: u32 = 1_193_182;
+
+/// The divisor the PIT starts with. It's programmed as 0.
+const DEFAULT_TIMER_DIVISOR: u32 = 65536;
+
+/// The divisor of PIT channel 0, see [timer_divisor].
+static TIMER_DIVISOR: AtomicU32 = AtomicU32::new(DEFAULT_TIMER_DIVISOR);
+
+/// PIT cycles that have passed in all ticks so far. Each tick adds the
+/// divisor it was counted with, so that [uptime_ms] stays right when
+/// the frequency changes.
+static TIMER_CYCLES: AtomicU64 = AtomicU64::new(0);
+
+/// Returned by [set_timer_frequency] for frequencies the PIT can't
+/// produce. Holds the requested frequency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerFrequencyError(pub u32);
+
+/// Get the PIT divisor for a timer frequency of `hz`, if there is one.
+/// The divisor is 16 bits, with 0 meaning 65536, so frequencies from 19
+/// Hz up to [PIT_FREQUENCY] are possible.
+fn pit_divisor(hz: u32) -> Option<u32> {
+    let divisor = PIT_FREQUENCY.checked_div(hz)?;
+    if (1..=DEFAULT_TIMER_DIVISOR).contains(&divisor) {
+        Some(divisor)
+    }
+    else {
+        None
+    }
+}
+
+/// Make the timer interrupt fire at roughly `hz` times per second, by
+/// reprogramming channel 0 of the PIT. The actual frequency is
+/// [PIT_FREQUENCY] divided by [timer_divisor].
+pub fn set_timer_frequency(hz: u32) -> Result<(), TimerFrequencyError> {
+    let divisor = pit_divisor(hz).ok_or(TimerFrequencyError(hz))?;
+    set_timer_divisor(divisor);
+    Ok(())
+}
+
+fn set_timer_divisor(divisor: u32) {
+    use x86_64::instructions::port::Port;
+
+    /// Channel 0, low byte then high byte, square wave generator.
+    const CHANNEL_0_SQUARE_WAVE: u8 = 0x36;
+
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel_0: Port<u8> = Port::new(0x40);
+
+    // The divisor has to be written in two parts, so the timer must not
+    // tick in the middle. 65536 becomes 0, which the PIT expects.
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        command.write(CHANNEL_0_SQUARE_WAVE);
+        channel_0.write(divisor as u8);
+        channel_0.write((divisor >> 8) as u8);
+        TIMER_DIVISOR.store(divisor, Ordering::Relaxed);
+    });
+}
+
+/// Get the divisor of PIT channel 0. It's 65536 unless it was changed
+/// with [set_timer_frequency].
+pub fn timer_divisor() -> u32 {
+    TIMER_DIVISOR.load(Ordering::Relaxed)
+}
+
+/// Convert a number of timer ticks at the current frequency to
+/// milliseconds, rounding down.
+pub fn ticks_to_ms(ticks: u64) -> u64 {
+    let cycles = ticks as u128 * u128::from(timer_divisor());
+    (cycles * 1000 / u128::from(PIT_FREQUENCY)) as u64
+}
+
+/// Get the number of milliseconds since the PICs were initialized, with
+/// the precision of one timer tick.
+pub fn uptime_ms() -> u64 {
+    let cycles = TIMER_CYCLES.load(Ordering::Relaxed);
+    (cycles as u128 * 1000 / u128::from(PIT_FREQUENCY)) as u64
+}
+
+/// Number of slow ticks so far, see [slow_ticks].
+static SLOW_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// The callback registered with [set_slow_tick_callback], as a function
+/// pointer cast to `usize`. Zero means that there is none.
+static SLOW_TICK_CALLBACK: AtomicUsize = AtomicUsize::new(0);
+
+/// Get the number of slow ticks so far. There is one slow tick per
+/// second of [uptime_ms], whatever the timer frequency is.
+pub fn slow_ticks() -> u64 {
+    SLOW_TICKS.load(Ordering::Relaxed)
+}
+
+/// Call `callback` on every slow tick, ie once per second, eg for
+/// housekeeping like refreshing a status bar. This replaces the
+/// previous callback, if any.
+///
+/// The callback runs in the timer interrupt handler with interrupts
+/// disabled, so it must be short and must not wait for locks that
+/// normal code may hold.
+pub fn set_slow_tick_callback(callback: fn()) {
+    SLOW_TICK_CALLBACK.store(callback as usize, Ordering::Relaxed);
+}
+
+/// Remove the callback registered with [set_slow_tick_callback].
+pub fn clear_slow_tick_callback() {
+    SLOW_TICK_CALLBACK.store(0, Ordering::Relaxed);
+}
+
+/// Count a slow tick and run the callback if going from `before` to
+/// `after` elapsed PIT cycles crossed a whole second.
+fn slow_tick(before: u64, after: u64) {
+    let second = u64::from(PIT_FREQUENCY);
+    if after / second == before / second {
+        return;
+    }
+
+    SLOW_TICKS.fetch_add(1, Ordering::Relaxed);
+    match SLOW_TICK_CALLBACK.load(Ordering::Relaxed) {
+        0 => {}
+        callback => {
+            // Safe because set_slow_tick_callback is the only thing
+            // storing nonzero values, and those always come from a
+            // `fn()`.
+            let callback: fn() = unsafe { core::mem::transmute(callback) };
+            callback();
+        }
+    }
+}
+
+/// Maximum number of handlers registered with [add_tick_handler] at a
+/// time.
+pub const TICK_HANDLER_CAPACITY: usize = 8;
+
+/// The handlers registered with [add_tick_handler], as function pointers
+/// cast to `usize`. Zero means that the slot is free.
+static TICK_HANDLERS: [AtomicUsize; TICK_HANDLER_CAPACITY] = {
+    const FREE: AtomicUsize = AtomicUsize::new(0);
+    [FREE; TICK_HANDLER_CAPACITY]
+};
+
+/// Identifies a handler registered with [add_tick_handler], for
+/// [remove_tick_handler].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerId {
+    slot: usize,
+    handler: usize,
+}
+
+/// Error returned by [add_tick_handler] when all
+/// [TICK_HANDLER_CAPACITY] slots are taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TickHandlersFull;
+
+/// Call `handler` on every timer tick, in addition to any other tick
+/// handlers. Handlers run in the order of their slots, which is the
+/// order they were added in, except that a new handler may take the
+/// slot of one that was removed.
+///
+/// Like the callback of [set_slow_tick_callback], handlers run in the
+/// timer interrupt handler with interrupts disabled. They hold up the
+/// other handlers and the rest of the kernel, so they must be short,
+/// and they must not wait for locks that normal code may hold.
+pub fn add_tick_handler(handler: fn()) -> Result<HandlerId, TickHandlersFull> {
+    let handler = handler as usize;
+    TICK_HANDLERS
+        .iter()
+        .position(|slot| {
+            slot.compare_exchange(
+                0,
+                handler,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            )
+            .is_ok()
+        })
+        .map(|slot| HandlerId { slot, handler })
+        .ok_or(TickHandlersFull)
+}
+
+/// Stop calling a handler registered with [add_tick_handler]. It is not
+/// called on any tick after this returns. Removing a handler that was
+/// already removed does nothing.
+pub fn remove_tick_handler(id: HandlerId) {
+    // If the handler was already removed, the slot is either free or
+    // belongs to another handler, which we must leave alone.
+    let _ = TICK_HANDLERS[id.slot].compare_exchange(
+        id.handler,
+        0,
+        Ordering::SeqCst,
+        Ordering::SeqCst,
+    );
+}
+
+/// Call each handler registered with [add_tick_handler].
+fn run_tick_handlers() {
+    for slot in &TICK_HANDLERS {
+        match slot.load(Ordering::SeqCst) {
+            0 => {}
+            handler => {
+                // Safe because add_tick_handler is the only thing
+                // storing nonzero values, and those always come from a
+                // `fn()`.
+                let handler: fn() = unsafe { core::mem::transmute(handler) };
+                handler();
+            }
+        }
+    }
+}
+
+/// Halt until [uptime_ms] has advanced by `ms`. Uptime only changes
+/// once per tick, so the actual delay can be off by up to a tick. This
+/// needs the timer interrupt, so it must not be called with interrupts
+/// disabled.
+pub fn sleep_ms(ms: u64) {
+    let end = uptime_ms() + ms;
+    while uptime_ms() < end {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Check whether maskable interrupts are enabled, ie whether the
+/// interrupt flag of RFLAGS is set. [crate::init] enables them.
+pub fn are_enabled() -> bool {
+    use x86_64::registers::rflags::{self, RFlags};
+
+    rflags::read().contains(RFlags::INTERRUPT_FLAG)
+}
+
+/// Disables interrupts until it is dropped, see [disable_guard].
+#[must_use = "interrupts are enabled again when the guard is dropped"]
+pub struct InterruptGuard {
+    was_enabled: bool,
+}
+
+/// Disable interrupts until the returned guard is dropped. This works
+/// like `without_interrupts`, but for critical sections that don't fit
+/// in a closure, eg because they return early.
+///
+/// Dropping the guard restores the state from before, so interrupts are
+/// only enabled again if they were enabled when it was created. Nested
+/// guards therefore keep interrupts disabled until the outermost one is
+/// dropped, as long as they are dropped in reverse order.
+pub fn disable_guard() -> InterruptGuard {
+    let was_enabled = are_enabled();
+    if was_enabled {
+        x86_64::instructions::interrupts::disable();
+        #[cfg(feature = "latency-stats")]
+        latency::start_disabled();
+    }
+    InterruptGuard { was_enabled }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        if self.was_enabled {
+            #[cfg(feature = "latency-stats")]
+            latency::end_disabled();
+            x86_64::instructions::interrupts::enable();
+        }
+    }
+}
+
+#[cfg(feature = "latency-stats")]
+pub use latency::{latency_stats, HandlerLatency, LatencyStats};
+
+/// Latency instrumentation, only built with the `latency-stats`
+/// feature. Times are measured with rdtsc, see [crate::cpu::rdtsc].
+#[cfg(feature = "latency-stats")]
+mod latency {
+    use crate::cpu::rdtsc;
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    /// Time spent in the handler of one vector, in TSC cycles.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct HandlerLatency {
+        /// How many times the handler ran.
+        pub count: u64,
+        pub total_cycles: u64,
+        /// The longest a single run took.
+        pub max_cycles: u64,
+    }
+
+    /// Latency measurements since boot or the last [LatencyStats::reset],
+    /// see [latency_stats].
+    pub struct LatencyStats {
+        count: [AtomicU64; 256],
+        total_cycles: [AtomicU64; 256],
+        max_cycles: [AtomicU64; 256],
+        max_disabled_cycles: AtomicU64,
+    }
+
+    impl LatencyStats {
+        const fn new() -> Self {
+            const ZERO: AtomicU64 = AtomicU64::new(0);
+            LatencyStats {
+                count: [ZERO; 256],
+                total_cycles: [ZERO; 256],
+                max_cycles: [ZERO; 256],
+                max_disabled_cycles: ZERO,
+            }
+        }
+
+        /// Get the time spent in the handler of `vector`. This only
+        /// covers handlers that run in [super::isolate_panics], which
+        /// is every handler of this module except the double fault
+        /// one. The time of a handler includes that of any handler
+        /// that interrupted it.
+        pub fn handler(&self, vector: u8) -> HandlerLatency {
+            let vector = usize::from(vector);
+            HandlerLatency {
+                count: self.count[vector].load(Ordering::Relaxed),
+                total_cycles: self.total_cycles[vector].load(Ordering::Relaxed),
+                max_cycles: self.max_cycles[vector].load(Ordering::Relaxed),
+            }
+        }
+
+        /// Get the longest time interrupts were disabled by an
+        /// [super::InterruptGuard], from the outermost guard being
+        /// created until it was dropped. Sections that disable
+        /// interrupts some other way, eg with `without_interrupts`, are
+        /// not measured.
+        pub fn max_disabled_cycles(&self) -> u64 {
+            self.max_disabled_cycles.load(Ordering::Relaxed)
+        }
+
+        /// Forget everything measured so far.
+        pub fn reset(&self) {
+            let all = self.count.iter().chain(&self.total_cycles);
+            for value in all.chain(&self.max_cycles) {
+                value.store(0, Ordering::Relaxed);
+            }
+            self.max_disabled_cycles.store(0, Ordering::Relaxed);
+        }
+
+        pub(super) fn record_handler(&self, vector: u8, cycles: u64) {
+            let vector = usize::from(vector);
+            self.count[vector].fetch_add(1, Ordering::Relaxed);
+            self.total_cycles[vector].fetch_add(cycles, Ordering::Relaxed);
+            self.max_cycles[vector].fetch_max(cycles, Ordering::Relaxed);
+        }
+    }
+
+    static STATS: LatencyStats = LatencyStats::new();
+
+    /// When the outermost [super::InterruptGuard] disabled interrupts.
+    static DISABLED_SINCE: AtomicU64 = AtomicU64::new(0);
+
+    /// Get the latency measurements of the interrupt handlers, eg to
+    /// find the ones that keep interrupts disabled for too long.
+    pub fn latency_stats() -> &'static LatencyStats {
+        &STATS
+    }
+
+    pub(super) fn start_disabled() {
+        DISABLED_SINCE.store(rdtsc(), Ordering::Relaxed);
+    }
+
+    pub(super) fn end_disabled() {
+        let cycles = rdtsc() - DISABLED_SINCE.load(Ordering::Relaxed);
+        STATS
+            .max_disabled_cycles
+            .fetch_max(cycles, Ordering::Relaxed);
+    }
+}
+
+// The exception handlers print with try_println, because if the
+// exception happened while the writer was locked, waiting for it would
+// hang forever.
+
+// Indices of the handlers in COUNTERS, so that they don't look up their
+// counter by name on every interrupt.
+const TIMER_COUNTER: usize = 0;
+const KEYBOARD_COUNTER: usize = 1;
+const SERIAL_COUNTER: usize = 2;
+const BREAKPOINT_COUNTER: usize = 3;
+const PAGE_FAULT_COUNTER: usize = 4;
+
+/// How many times each of the handlers ran, see [counters].
+static COUNTERS: NamedCounters<5> = {
+    let mut names = [""; 5];
+    names[TIMER_COUNTER] = "timer";
+    names[KEYBOARD_COUNTER] = "keyboard";
+    names[SERIAL_COUNTER] = "serial";
+    names[BREAKPOINT_COUNTER] = "breakpoint";
+    names[PAGE_FAULT_COUNTER] = "page_fault";
+    NamedCounters::new(names)
+};
+
+/// Get the number of times each of the interrupt handlers of the kernel
+/// ran, by name: `"timer"`, `"keyboard"`, `"serial"`, `"breakpoint"`
+/// and `"page_fault"`.
+pub fn counters() -> &'static NamedCounters<5> {
+    &COUNTERS
+}
+
+/// Get the number of breakpoint exceptions that have been handled so
+/// far.
+pub fn breakpoints() -> u64 {
+    COUNTERS.get_at(BREAKPOINT_COUNTER)
+}
+
+/// How many timer ticks [breakpoint_handler] waits before returning.
+static BREAKPOINT_PAUSE_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Make every breakpoint pause execution for `ticks` timer ticks, eg to
+/// have time to look at the screen. Interrupts are still handled during
+/// the pause. The default is 0, ie no pause.
+pub fn set_breakpoint_pause(ticks: u64) {
+    BREAKPOINT_PAUSE_TICKS.store(ticks, Ordering::Relaxed);
+}
+
+/// Value of [HANDLER_VECTOR] outside of [isolate_panics].
+const NO_VECTOR: u16 = u16::MAX;
+
+/// Vector of the innermost handler that is running in [isolate_panics].
+static HANDLER_VECTOR: AtomicU16 = AtomicU16::new(NO_VECTOR);
+
+// Vectors of the exceptions whose handlers run in isolate_panics.
+const BREAKPOINT_VECTOR: u8 = 3;
+const OVERFLOW_VECTOR: u8 = 4;
+const BOUND_RANGE_EXCEEDED_VECTOR: u8 = 5;
+const INVALID_OPCODE_VECTOR: u8 = 6;
+const DOUBLE_FAULT_VECTOR: u8 = 8;
+const GENERAL_PROTECTION_FAULT_VECTOR: u8 = 13;
+const PAGE_FAULT_VECTOR: u8 = 14;
+const ALIGNMENT_CHECK_VECTOR: u8 = 17;
+const MACHINE_CHECK_VECTOR: u8 = 18;
+
+/// Run `f`, the body of the handler for `vector`, so that a panic in it
+/// is reported by [report_handler_panic]. Every handler of this module
+/// does this, except for the double fault handler, which panics on
+/// purpose.
+///
+/// A handler can interrupt code that holds the print locks, so the
+/// panic handler could otherwise hang as soon as it tries to print.
+pub fn isolate_panics<R>(vector: u8, f: impl FnOnce() -> R) -> R {
+    let outer = HANDLER_VECTOR.swap(u16::from(vector), Ordering::SeqCst);
+    #[cfg(feature = "latency-stats")]
+    let start = crate::cpu::rdtsc();
+    let result = f();
+    #[cfg(feature = "latency-stats")]
+    latency_stats().record_handler(vector, crate::cpu::rdtsc() - start);
+    HANDLER_VECTOR.store(outer, Ordering::SeqCst);
+    result
+}
+
+/// Get the vector of the handler that is running in [isolate_panics],
+/// if any.
+pub fn handler_vector() -> Option<u8> {
+    match HANDLER_VECTOR.load(Ordering::SeqCst) {
+        NO_VECTOR => None,
+        vector => Some(vector as u8),
+    }
+}
+
+/// Report a panic that happened in a handler run by [isolate_panics].
+/// Panic handlers call this before anything else that prints. It
+/// returns `false` and does nothing if the panic happened elsewhere.
+///
+/// Otherwise it disables interrupts, forcibly unlocks the print locks,
+/// and prints "panic in interrupt handler" with the vector and `info`
+/// on serial and on screen. The interrupted code is never going to
+/// resume, so nothing else can print after it anyway. The caller must
+/// not return to the handler, ie it should halt, see [crate::abort].
+pub fn report_handler_panic(info: &PanicInfo) -> bool {
+    let vector = match handler_vector() {
+        Some(vector) => vector,
+        None => return false,
+    };
+
+    x86_64::instructions::interrupts::disable();
+    unsafe {
+        crate::vga_buffer::force_unlock();
+        crate::serial::force_unlock();
+    }
+    crate::serial_println!(
+        "panic in interrupt handler (vector {}): {}",
+        vector,
+        info
+    );
+    try_println!("panic in interrupt handler (vector {}): {}", vector, info);
+    true
+}
+
+/// The state of the CPU when an exception happened, as the exception
+/// handlers of this module print it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExceptionReport {
+    pub vector: u8,
+    /// The error code, for the exceptions that push one.
+    pub error_code: Option<u64>,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    /// The accessed address, for page faults.
+    pub cr2: Option<u64>,
+}
+
+impl ExceptionReport {
+    /// Build the report of exception `vector` from the stack frame the
+    /// CPU pushed for it.
+    pub fn new(
+        vector: u8,
+        stack_frame: &InterruptStackFrame,
+        error_code: Option<u64>,
+    ) -> Self {
+        ExceptionReport {
+            vector,
+            error_code,
+            rip: stack_frame.instruction_pointer.as_u64(),
+            cs: stack_frame.code_segment,
+            rflags: stack_frame.cpu_flags,
+            rsp: stack_frame.stack_pointer.as_u64(),
+            cr2: None,
+        }
+    }
+
+    /// Build the report of a page fault, including the accessed address
+    /// from CR2. Call this before anything that could fault again.
+    pub fn page_fault(
+        stack_frame: &InterruptStackFrame,
+        error_code: PageFaultErrorCode,
+    ) -> Self {
+        use x86_64::registers::control::Cr2;
+
+        ExceptionReport {
+            cr2: Some(Cr2::read().as_u64()),
+            ..Self::new(PAGE_FAULT_VECTOR, stack_frame, Some(error_code.bits()))
+        }
+    }
+}
+
+/// Formats the report as a single line of `key=value` pairs, with
+/// numbers in hex, eg `vector=13 error_code=0x0 rip=0x2049d1 cs=0x8
+/// rflags=0x10086 rsp=0x10000201f28`. Missing fields are left out.
+impl fmt::Display for ExceptionReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "vector={}", self.vector)?;
+        if let Some(error_code) = self.error_code {
+            write!(f, " error_code={:#x}", error_code)?;
+        }
+        write!(
+            f,
+            " rip={:#x} cs={:#x} rflags={:#x} rsp={:#x}",
+            self.rip, self.cs, self.rflags, self.rsp
+        )?;
+        if let Some(cr2) = self.cr2 {
+            write!(f, " cr2={:#x}", cr2)?;
+        }
+        Ok(())
+    }
 }
 
 /// Handler for breakpoint interrupt. Notify the user of the breakpoint
-/// and print the call stack.
+/// and print the call stack, then pause if [set_breakpoint_pause] asked
+/// for it.
+///
+/// This is registered as a trap gate, so interrupts stay enabled. That
+/// is fine for printing, because try_println disables interrupts while
+/// it holds the lock of the writer.
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    isolate_panics(BREAKPOINT_VECTOR, || {
+        COUNTERS.inc_at(BREAKPOINT_COUNTER);
+        try_println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+
+        let end = ticks() + BREAKPOINT_PAUSE_TICKS.load(Ordering::Relaxed);
+        while ticks() < end {
+            x86_64::instructions::hlt();
+        }
+    })
 }
 
 /// Handler for double fault. The situation is unsalvageable because
@@ -72,52 +771,112 @@ extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
 /// happened and panic.
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
-    _error_code: u64,
+    error_code: u64,
 ) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    let report = ExceptionReport::new(
+        DOUBLE_FAULT_VECTOR,
+        &stack_frame,
+        Some(error_code),
+    );
+    panic!("EXCEPTION: DOUBLE FAULT\n{}", report);
 }
 
 /// Print a dot on the screen every time the timer fires off.
 extern "x86-interrupt" fn timer_interrupt_handler(
     _stack_frame: InterruptStackFrame,
 ) {
-    print!(".");
+    isolate_panics(InterruptIndex::Timer.as_u8(), || {
+        let ticks = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+        COUNTERS.inc_at(TIMER_COUNTER);
+        let divisor = u64::from(timer_divisor());
+        let cycles = TIMER_CYCLES.fetch_add(divisor, Ordering::Relaxed);
+        slow_tick(cycles, cycles + divisor);
+        run_tick_handlers();
+        print!(".");
+        crate::vga_buffer::cursor_tick(ticks);
+        crate::test_heartbeat(ticks);
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+        unsafe {
+            PICS.lock()
+                .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+        }
+    })
+}
+
+/// Turn the lock key LEDs of the keyboard on or off. After a lock key
+/// is pressed, [update_keyboard_leds] does this with the new lock state,
+/// so you only need it to override that. See [crate::ps2::set_leds].
+///
+/// This waits for the keyboard with interrupts disabled, which may take
+/// milliseconds, so don't call it from an interrupt handler.
+pub fn set_keyboard_leds(
+    caps: bool,
+    num: bool,
+    scroll: bool,
+) -> Result<(), crate::ps2::Ps2Error> {
+    crate::ps2::set_leds(caps, num, scroll)
+}
+
+/// Set by the keyboard interrupt handler when a lock key was pressed,
+/// so that [update_keyboard_leds] sends the new lock state.
+static LEDS_OUTDATED: AtomicBool = AtomicBool::new(false);
+
+/// Show the current lock state on the keyboard LEDs, if a lock key was
+/// pressed since the last time. [crate::hlt_loop] calls this before
+/// each halt.
+///
+/// The keyboard interrupt handler only records that this is needed,
+/// because [set_keyboard_leds] would stall every other interrupt while
+/// it waits for the keyboard.
+pub fn update_keyboard_leds() {
+    if LEDS_OUTDATED.swap(false, Ordering::Relaxed) {
+        let locks = crate::keyboard::locks();
+        // Nothing we can do if the keyboard doesn't respond, and the
+        // lock state itself is correct either way.
+        let _ = set_keyboard_leds(locks.caps, locks.num, locks.scroll);
     }
 }
 
+/// Make held keys repeat at most every `rate_ms` milliseconds, starting
+/// `initial_ms` after they were pressed. Until this is called, every
+/// repeat the keyboard sends is delivered. See
+/// [crate::keyboard::set_repeat].
+pub fn set_key_repeat(initial_ms: u64, rate_ms: u64) {
+    crate::keyboard::set_repeat(Some(crate::keyboard::KeyRepeat {
+        initial_delay_ms: initial_ms,
+        rate_ms,
+    }));
+}
+
+/// Read the scancode from the keyboard controller and pass it on to
+/// [crate::keyboard]. Decoded keys are also echoed to the screen.
 extern "x86-interrupt" fn keyboard_interrupt_handler(
     _stack_frame: InterruptStackFrame,
 ) {
-    use pc_keyboard::{
-        layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1,
-    };
-    use spin::Mutex;
-    use x86_64::instructions::port::Port;
+    isolate_panics(InterruptIndex::Keyboard.as_u8(), handle_keyboard)
+}
 
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(
-                layouts::Us104Key,
-                ScancodeSet1,
-                HandleControl::Ignore
-            ));
-    }
+fn handle_keyboard() {
+    use crate::keyboard::{self, KeyCode, KeyEvent};
 
-    let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60);
+    COUNTERS.inc_at(KEYBOARD_COUNTER);
 
-    let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
-            }
-        }
+    // The ACKs that set_keyboard_leds reads with interrupts disabled
+    // still raise this interrupt once they are enabled again. By then
+    // the output buffer is empty, and the port would only repeat the
+    // ACK, so there is no scancode to decode.
+    let scancode = crate::ps2::read_output();
+    match scancode.and_then(keyboard::handle_scancode) {
+        Some(KeyEvent::Special {
+            code: KeyCode::CapsLock | KeyCode::NumLock | KeyCode::ScrollLock,
+            pressed: true,
+        }) => LEDS_OUTDATED.store(true, Ordering::Relaxed),
+        Some(KeyEvent::Unicode(character)) => print!("{}", character),
+        Some(KeyEvent::Special {
+            code,
+            pressed: true,
+        }) => print!("{:?}", code),
+        _ => {}
     }
 
     unsafe {
@@ -126,15 +885,398 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(
     }
 }
 
+extern "x86-interrupt" fn serial_interrupt_handler(
+    _stack_frame: InterruptStackFrame,
+) {
+    isolate_panics(InterruptIndex::Serial.as_u8(), || {
+        COUNTERS.inc_at(SERIAL_COUNTER);
+        crate::serial::handle_interrupt();
+
+        unsafe {
+            PICS.lock()
+                .notify_end_of_interrupt(InterruptIndex::Serial.as_u8());
+        }
+    })
+}
+
 extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
-    use x86_64::registers::control::Cr2;
+    isolate_panics(PAGE_FAULT_VECTOR, || {
+        use x86_64::registers::control::Cr2;
+
+        COUNTERS.inc_at(PAGE_FAULT_COUNTER);
+        let report = ExceptionReport::page_fault(&stack_frame, error_code);
+        try_println!("EXCEPTION: PAGE FAULT\n{}", report);
+        try_println!("Error Code: {:?}", error_code);
+        if let Some(flags) = crate::memory::try_page_flags(Cr2::read()) {
+            try_println!("Page Flags: {}", crate::memory::format_flags(flags));
+        }
+    });
+    hlt_loop();
+}
+
+/// Handler for general protection fault, eg from a non-canonical
+/// address or a bad segment selector. The error code is the selector,
+/// if a selector caused it. Print what happened and halt.
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    isolate_panics(GENERAL_PROTECTION_FAULT_VECTOR, || {
+        let report = ExceptionReport::new(
+            GENERAL_PROTECTION_FAULT_VECTOR,
+            &stack_frame,
+            Some(error_code),
+        );
+        try_println!("EXCEPTION: GENERAL PROTECTION FAULT\n{}", report);
+    });
+    hlt_loop();
+}
+
+/// Handler for invalid opcode, eg from `ud2`. Returning would run the
+/// same instruction again, so print what happened and halt.
+extern "x86-interrupt" fn invalid_opcode_handler(
+    stack_frame: InterruptStackFrame,
+) {
+    isolate_panics(INVALID_OPCODE_VECTOR, || {
+        let report =
+            ExceptionReport::new(INVALID_OPCODE_VECTOR, &stack_frame, None);
+        try_println!("EXCEPTION: INVALID OPCODE\n{}", report);
+    });
+    hlt_loop();
+}
+
+/// Handler for overflow. In 32-bit code this is raised by `into` when
+/// the overflow flag is set. `into` is invalid in long mode, so here it
+/// can only come from `int 4` or from 32-bit code. Either way it's a bug
+/// we can't recover from, so print what happened and halt.
+extern "x86-interrupt" fn overflow_handler(stack_frame: InterruptStackFrame) {
+    isolate_panics(OVERFLOW_VECTOR, || {
+        let report = ExceptionReport::new(OVERFLOW_VECTOR, &stack_frame, None);
+        try_println!("EXCEPTION: OVERFLOW\n{}", report);
+    });
+    hlt_loop();
+}
+
+/// Handler for bound range exceeded. Like with [overflow_handler], the
+/// `bound` instruction that raises this doesn't exist in long mode, so
+/// print what happened and halt.
+extern "x86-interrupt" fn bound_range_exceeded_handler(
+    stack_frame: InterruptStackFrame,
+) {
+    isolate_panics(BOUND_RANGE_EXCEEDED_VECTOR, || {
+        let report = ExceptionReport::new(
+            BOUND_RANGE_EXCEEDED_VECTOR,
+            &stack_frame,
+            None,
+        );
+        try_println!("EXCEPTION: BOUND RANGE EXCEEDED\n{}", report);
+    });
+    hlt_loop();
+}
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
+/// Handler for alignment check. This is only raised for misaligned
+/// accesses when both CR0.AM and RFLAGS.AC are set, and only in ring 3.
+/// We can't do anything about the access, so print what happened and
+/// halt.
+extern "x86-interrupt" fn alignment_check_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    isolate_panics(ALIGNMENT_CHECK_VECTOR, || {
+        let report = ExceptionReport::new(
+            ALIGNMENT_CHECK_VECTOR,
+            &stack_frame,
+            Some(error_code),
+        );
+        try_println!("EXCEPTION: ALIGNMENT CHECK\n{}", report);
+    });
+    hlt_loop();
+}
+
+/// Handler for machine check. This means the CPU detected an internal
+/// or bus error. It is not possible to return from it, so print what
+/// happened and halt.
+extern "x86-interrupt" fn machine_check_handler(
+    stack_frame: InterruptStackFrame,
+) -> ! {
+    isolate_panics(MACHINE_CHECK_VECTOR, || {
+        let report =
+            ExceptionReport::new(MACHINE_CHECK_VECTOR, &stack_frame, None);
+        try_println!("EXCEPTION: MACHINE CHECK\n{}", report);
+    });
     hlt_loop();
 }
+
+/// The breakpoint handler is a trap gate, so the timer must keep firing
+/// while it pauses. With an interrupt gate this would hang forever.
+#[test_case]
+fn test_timer_fires_during_breakpoint() {
+    set_breakpoint_pause(2);
+    let start = ticks();
+    x86_64::instructions::interrupts::int3();
+    set_breakpoint_pause(0);
+
+    assert!(ticks() >= start + 2);
+}
+
+/// The test runner calls [crate::init] before running the tests, so
+/// the timer and the keyboard depend on this.
+#[test_case]
+fn test_enabled_after_init() {
+    assert!(are_enabled());
+}
+
+#[test_case]
+fn test_without_interrupts_restores_state() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| assert!(!are_enabled()));
+    assert!(are_enabled());
+
+    interrupts::disable();
+    interrupts::without_interrupts(|| assert!(!are_enabled()));
+    assert!(!are_enabled());
+    interrupts::enable();
+}
+
+#[test_case]
+fn test_pit_divisor() {
+    assert_eq!(pit_divisor(1000), Some(1193));
+    assert_eq!(pit_divisor(19), Some(62799));
+    assert_eq!(pit_divisor(PIT_FREQUENCY), Some(1));
+    assert_eq!(pit_divisor(18), None);
+    assert_eq!(pit_divisor(PIT_FREQUENCY + 1), None);
+    assert_eq!(pit_divisor(0), None);
+}
+
+#[test_case]
+fn test_set_timer_frequency() {
+    assert_eq!(set_timer_frequency(0), Err(TimerFrequencyError(0)));
+
+    assert_eq!(set_timer_frequency(1000), Ok(()));
+    assert_eq!(timer_divisor(), 1193);
+    assert_eq!(ticks_to_ms(1000), 999);
+
+    let start_ticks = ticks();
+    let start_ms = uptime_ms();
+    sleep_ms(20);
+    let elapsed_ticks = ticks() - start_ticks;
+    let elapsed_ms = uptime_ms() - start_ms;
+
+    // The other tests expect the default frequency.
+    set_timer_divisor(DEFAULT_TIMER_DIVISOR);
+    assert_eq!(ticks_to_ms(1), 54);
+
+    // At 18.2 Hz, 20ms would be a single tick.
+    assert!(elapsed_ticks >= 20, "{} ticks", elapsed_ticks);
+    assert!(elapsed_ms >= 20, "{} ms", elapsed_ms);
+}
+
+/// At 1000 Hz there are 1000 fast ticks per slow tick, give or take one
+/// because the divisor doesn't divide the PIT frequency exactly.
+#[test_case]
+fn test_slow_tick() {
+    static CALLBACKS: AtomicU64 = AtomicU64::new(0);
+
+    fn count() {
+        CALLBACKS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn wait_for_slow_tick() -> u64 {
+        let start = slow_ticks();
+        while slow_ticks() == start {
+            x86_64::instructions::hlt();
+        }
+        ticks()
+    }
+
+    // Otherwise we'd get long test reports every 91ms.
+    crate::set_test_heartbeat(false);
+    set_timer_frequency(1000).unwrap();
+    set_slow_tick_callback(count);
+
+    let first = wait_for_slow_tick();
+    let callbacks = CALLBACKS.load(Ordering::Relaxed);
+    let second = wait_for_slow_tick();
+    let callbacks = CALLBACKS.load(Ordering::Relaxed) - callbacks;
+
+    clear_slow_tick_callback();
+    set_timer_divisor(DEFAULT_TIMER_DIVISOR);
+    crate::set_test_heartbeat(true);
+
+    assert_eq!(callbacks, 1);
+    assert!(
+        (999..=1001).contains(&(second - first)),
+        "{}",
+        second - first
+    );
+}
+
+#[test_case]
+fn test_tick_handlers() {
+    static FIRST: AtomicU64 = AtomicU64::new(0);
+    static SECOND: AtomicU64 = AtomicU64::new(0);
+
+    fn first() {
+        FIRST.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn second() {
+        SECOND.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn wait_for_ticks(n: u64) {
+        let end = ticks() + n;
+        while ticks() < end {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    // Without interrupts, no tick can come between the calls, so both
+    // handlers run on the same ticks.
+    let (first_id, second_id) = without_interrupts(|| {
+        (
+            add_tick_handler(first).unwrap(),
+            add_tick_handler(second).unwrap(),
+        )
+    });
+    wait_for_ticks(3);
+    let calls = without_interrupts(|| {
+        remove_tick_handler(first_id);
+        let calls = FIRST.load(Ordering::SeqCst);
+        assert_eq!(SECOND.load(Ordering::SeqCst), calls);
+        calls
+    });
+    assert!(calls >= 3);
+
+    wait_for_ticks(2);
+    remove_tick_handler(second_id);
+    assert_eq!(FIRST.load(Ordering::SeqCst), calls);
+    assert!(SECOND.load(Ordering::SeqCst) >= calls + 2);
+
+    // Removing a handler again doesn't free the slot for someone else.
+    let id = add_tick_handler(second).unwrap();
+    remove_tick_handler(first_id);
+    let slot = TICK_HANDLERS[id.slot].load(Ordering::SeqCst);
+    remove_tick_handler(id);
+    assert_eq!(slot, second as usize);
+}
+
+#[test_case]
+fn test_slow_tick_boundaries() {
+    let second = u64::from(PIT_FREQUENCY);
+    // The timer must not add slow ticks of its own in the meantime.
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let start = slow_ticks();
+        slow_tick(0, 1);
+        slow_tick(second - 1, second - 1);
+        assert_eq!(slow_ticks(), start);
+        slow_tick(second - 1, second);
+        assert_eq!(slow_ticks(), start + 1);
+    });
+}
+
+#[test_case]
+fn test_counters() {
+    let before = counters().get("breakpoint").unwrap();
+    x86_64::instructions::interrupts::int3();
+    assert_eq!(counters().get("breakpoint"), Some(before + 1));
+    assert!(counters().get("timer").unwrap() > 0);
+}
+
+/// A lock key press is only recorded, and the LEDs are updated later.
+#[test_case]
+fn test_update_keyboard_leds() {
+    LEDS_OUTDATED.store(true, Ordering::Relaxed);
+    update_keyboard_leds();
+    assert!(!LEDS_OUTDATED.load(Ordering::Relaxed));
+}
+
+#[test_case]
+fn test_nested_disable_guards() {
+    let outer = disable_guard();
+    let inner = disable_guard();
+    assert!(!are_enabled());
+    drop(inner);
+    assert!(!are_enabled());
+    drop(outer);
+    assert!(are_enabled());
+}
+
+#[test_case]
+fn test_disable_guard_keeps_disabled_state() {
+    x86_64::instructions::interrupts::disable();
+    {
+        let _guard = disable_guard();
+        assert!(!are_enabled());
+    }
+    assert!(!are_enabled());
+    x86_64::instructions::interrupts::enable();
+}
+
+/// Vector used by [test_register], which nothing else uses.
+#[cfg(test)]
+const TEST_VECTOR: u8 = 200;
+
+// `int` only takes a literal, so test_register hard-codes the vector.
+// This makes sure that it stays in sync with TEST_VECTOR.
+#[cfg(test)]
+const _: () = assert!(TEST_VECTOR == 200, "Update int 200 in test_register");
+
+/// Set by [test_vector_handler].
+#[cfg(test)]
+static TEST_VECTOR_HITS: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(test)]
+extern "x86-interrupt" fn test_vector_handler(
+    _stack_frame: InterruptStackFrame,
+) {
+    TEST_VECTOR_HITS.fetch_add(1, Ordering::Relaxed);
+}
+
+#[test_case]
+fn test_register() {
+    register(TEST_VECTOR, test_vector_handler);
+    // This is TEST_VECTOR, see the assertion next to it.
+    unsafe { core::arch::asm!("int 200") };
+    assert_eq!(TEST_VECTOR_HITS.load(Ordering::Relaxed), 1);
+}
+
+#[cfg(all(test, feature = "latency-stats"))]
+extern "x86-interrupt" fn fast_test_handler(_stack_frame: InterruptStackFrame) {
+    isolate_panics(201, || {});
+}
+
+#[cfg(all(test, feature = "latency-stats"))]
+extern "x86-interrupt" fn slow_test_handler(_stack_frame: InterruptStackFrame) {
+    isolate_panics(202, || crate::cpu::sleep_cycles(1_000_000));
+}
+
+#[cfg(feature = "latency-stats")]
+#[test_case]
+fn test_latency_stats() {
+    register(201, fast_test_handler);
+    register(202, slow_test_handler);
+    // The vectors have to be literals.
+    unsafe {
+        core::arch::asm!("int 201");
+        core::arch::asm!("int 202");
+    }
+
+    let fast = latency_stats().handler(201);
+    let slow = latency_stats().handler(202);
+    assert_eq!((fast.count, slow.count), (1, 1));
+    assert!(slow.max_cycles >= 1_000_000);
+    assert!(slow.max_cycles > fast.max_cycles);
+
+    {
+        let _guard = disable_guard();
+        crate::cpu::sleep_cycles(1_000_000);
+    }
+    assert!(latency_stats().max_disabled_cycles() >= 1_000_000);
+}
@@ -0,0 +1,396 @@
+//! Keyboard input
+//!
+//! The keyboard interrupt handler feeds every scancode it reads to
+//! [handle_scancode]. The scancodes are decoded into [KeyEvent]s, which
+//! are pushed to a fixed size queue. Use [pop_key] to read them. Keys
+//! that produce a character are delivered as [KeyEvent::Unicode]. Other
+//! keys we care about, like arrows and function keys, are delivered as
+//! [KeyEvent::Special] for both presses and releases, so that you can
+//! build keyboard navigation on top of them.
+//!
+//! Holding a key makes the keyboard send its make code over and over.
+//! By default each of those is delivered as a new press. Use
+//! [set_repeat] to ignore them for an initial delay and then pass them
+//! on at a fixed rate instead, eg for menu navigation.
+//!
+//! The decoder also keeps track of which lock keys are on, see [locks].
+//! The keyboard interrupt handler updates the keyboard LEDs to match
+//! whenever one of them is pressed.
+
+use crate::ring::Ring;
+use lazy_static::lazy_static;
+use pc_keyboard::{
+    layouts, DecodedKey, HandleControl, KeyState, Keyboard, ScancodeSet1,
+};
+use spin::Mutex;
+
+/// Non-character keys that are reported as [KeyEvent::Special].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCode {
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Insert,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    CapsLock,
+    NumLock,
+    ScrollLock,
+}
+
+impl KeyCode {
+    /// Map a key code of the `pc_keyboard` crate to our own. Returns
+    /// `None` for keys that we don't report as special keys.
+    fn from_pc_keyboard(code: pc_keyboard::KeyCode) -> Option<Self> {
+        use pc_keyboard::KeyCode as Pc;
+
+        let code = match code {
+            Pc::ArrowUp => KeyCode::Up,
+            Pc::ArrowDown => KeyCode::Down,
+            Pc::ArrowLeft => KeyCode::Left,
+            Pc::ArrowRight => KeyCode::Right,
+            Pc::Home => KeyCode::Home,
+            Pc::End => KeyCode::End,
+            Pc::PageUp => KeyCode::PageUp,
+            Pc::PageDown => KeyCode::PageDown,
+            Pc::Insert => KeyCode::Insert,
+            Pc::F1 => KeyCode::F1,
+            Pc::F2 => KeyCode::F2,
+            Pc::F3 => KeyCode::F3,
+            Pc::F4 => KeyCode::F4,
+            Pc::F5 => KeyCode::F5,
+            Pc::F6 => KeyCode::F6,
+            Pc::F7 => KeyCode::F7,
+            Pc::F8 => KeyCode::F8,
+            Pc::F9 => KeyCode::F9,
+            Pc::F10 => KeyCode::F10,
+            Pc::F11 => KeyCode::F11,
+            Pc::F12 => KeyCode::F12,
+            Pc::CapsLock => KeyCode::CapsLock,
+            Pc::NumpadLock => KeyCode::NumLock,
+            Pc::ScrollLock => KeyCode::ScrollLock,
+            _ => return None,
+        };
+        Some(code)
+    }
+}
+
+/// A decoded keyboard event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyEvent {
+    /// A key press that produced a character. Releases of these keys
+    /// are not reported.
+    Unicode(char),
+    /// A press or release of a non-character key.
+    Special { code: KeyCode, pressed: bool },
+}
+
+/// Which lock keys are currently on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Locks {
+    pub caps: bool,
+    pub num: bool,
+    pub scroll: bool,
+}
+
+/// How a held key repeats, see [set_repeat]. The times are compared to
+/// [crate::interrupts::uptime_ms], so they are only as precise as a
+/// timer tick.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyRepeat {
+    /// How long after the first press repeats start.
+    pub initial_delay_ms: u64,
+    /// How long to wait between repeats after that.
+    pub rate_ms: u64,
+}
+
+/// The key that is being held down, with the time at which it can
+/// repeat next. The keyboard only repeats the key that was pressed
+/// last, so that's the only one we need to track.
+#[derive(Debug, Clone, Copy)]
+struct HeldKey {
+    code: pc_keyboard::KeyCode,
+    next_repeat_ms: u64,
+}
+
+/// Turns a stream of raw scancodes into [KeyEvent]s. This keeps the
+/// state required by multi-byte scancodes and modifier keys.
+pub struct KeyDecoder {
+    keyboard: Keyboard<layouts::Us104Key, ScancodeSet1>,
+    locks: Locks,
+    repeat: Option<KeyRepeat>,
+    held: Option<HeldKey>,
+}
+
+impl KeyDecoder {
+    pub fn new() -> Self {
+        KeyDecoder {
+            keyboard: Keyboard::new(
+                layouts::Us104Key,
+                ScancodeSet1,
+                HandleControl::Ignore,
+            ),
+            // The same initial state that pc_keyboard uses.
+            locks: Locks {
+                caps: false,
+                num: true,
+                scroll: false,
+            },
+            repeat: None,
+            held: None,
+        }
+    }
+
+    /// Limit how fast held keys repeat, or deliver every repeated press
+    /// if `repeat` is `None`, which is the default.
+    pub fn set_repeat(&mut self, repeat: Option<KeyRepeat>) {
+        self.repeat = repeat;
+        self.held = None;
+    }
+
+    /// Get the state of the lock keys. Each one is toggled when its key
+    /// is pressed.
+    pub fn locks(&self) -> Locks {
+        self.locks
+    }
+
+    /// Feed a single scancode byte to the decoder. Returns an event if
+    /// the byte completed one. Note that extended keys are made of
+    /// more than one byte, so `None` does not mean the byte was
+    /// invalid.
+    pub fn add_byte(&mut self, scancode: u8) -> Option<KeyEvent> {
+        self.add_byte_at(scancode, crate::interrupts::uptime_ms())
+    }
+
+    /// Like [KeyDecoder::add_byte], but with the current time given as
+    /// `now_ms` for repeat handling, see [KeyDecoder::set_repeat].
+    pub fn add_byte_at(
+        &mut self,
+        scancode: u8,
+        now_ms: u64,
+    ) -> Option<KeyEvent> {
+        let key_event = match self.keyboard.add_byte(scancode) {
+            Ok(Some(key_event)) => key_event,
+            _ => return None,
+        };
+
+        let code = key_event.code;
+        let pressed = key_event.state == KeyState::Down;
+        if pressed && !self.accept_press(code, now_ms) {
+            return None;
+        }
+        if !pressed && self.held.map(|held| held.code) == Some(code) {
+            self.held = None;
+        }
+
+        if pressed {
+            use pc_keyboard::KeyCode as Pc;
+
+            match code {
+                Pc::CapsLock => self.locks.caps = !self.locks.caps,
+                Pc::NumpadLock => self.locks.num = !self.locks.num,
+                Pc::ScrollLock => self.locks.scroll = !self.locks.scroll,
+                _ => {}
+            }
+        }
+        match self.keyboard.process_keyevent(key_event) {
+            Some(DecodedKey::Unicode(character)) => {
+                Some(KeyEvent::Unicode(character))
+            }
+            _ => KeyCode::from_pc_keyboard(code)
+                .map(|code| KeyEvent::Special { code, pressed }),
+        }
+    }
+
+    /// Decide whether a press of `code` at `now_ms` should be delivered
+    /// or is a repeat that comes too early.
+    fn accept_press(
+        &mut self,
+        code: pc_keyboard::KeyCode,
+        now_ms: u64,
+    ) -> bool {
+        let repeat = match self.repeat {
+            Some(repeat) => repeat,
+            None => return true,
+        };
+
+        match &mut self.held {
+            Some(held) if held.code == code => {
+                if now_ms < held.next_repeat_ms {
+                    return false;
+                }
+                held.next_repeat_ms = now_ms + repeat.rate_ms;
+            }
+            // A new key, which replaces the one that was repeating.
+            _ => {
+                self.held = Some(HeldKey {
+                    code,
+                    next_repeat_ms: now_ms + repeat.initial_delay_ms,
+                })
+            }
+        }
+        true
+    }
+}
+
+/// Number of events that can be waiting in the queue. When it is full,
+/// new events are dropped.
+const QUEUE_SIZE: usize = 32;
+
+lazy_static! {
+    static ref DECODER: Mutex<KeyDecoder> = Mutex::new(KeyDecoder::new());
+}
+
+/// Filled by the keyboard interrupt handler. It's a [Ring] because we
+/// can't use the heap there.
+static QUEUE: Ring<KeyEvent, QUEUE_SIZE> = Ring::new();
+
+/// Decode a scancode read from the keyboard controller and push the
+/// resulting event, if any, to the key queue. The event is also
+/// returned, so that the caller can echo it.
+///
+/// This is meant to be called from the keyboard interrupt handler, so
+/// it assumes that interrupts are disabled.
+pub fn handle_scancode(scancode: u8) -> Option<KeyEvent> {
+    let event = DECODER.lock().add_byte(scancode)?;
+    // If nobody reads the queue, it will eventually fill up. Dropping
+    // the newest events is all we can do in that case.
+    let _ = QUEUE.push(event);
+    Some(event)
+}
+
+/// Get the state of the lock keys, as tracked by the decoder of the
+/// keyboard interrupt handler.
+pub fn locks() -> Locks {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        DECODER.lock().locks()
+    })
+}
+
+/// Set how held keys repeat for the decoder of the keyboard interrupt
+/// handler, see [KeyDecoder::set_repeat].
+pub fn set_repeat(repeat: Option<KeyRepeat>) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        DECODER.lock().set_repeat(repeat)
+    })
+}
+
+/// Get the oldest event from the key queue, if there is one.
+pub fn pop_key() -> Option<KeyEvent> {
+    QUEUE.pop()
+}
+
+#[test_case]
+fn test_extended_arrow_up() {
+    let mut decoder = KeyDecoder::new();
+
+    assert_eq!(decoder.add_byte(0xe0), None);
+    assert_eq!(
+        decoder.add_byte(0x48),
+        Some(KeyEvent::Special {
+            code: KeyCode::Up,
+            pressed: true
+        })
+    );
+
+    assert_eq!(decoder.add_byte(0xe0), None);
+    assert_eq!(
+        decoder.add_byte(0xc8),
+        Some(KeyEvent::Special {
+            code: KeyCode::Up,
+            pressed: false
+        })
+    );
+}
+
+#[test_case]
+fn test_unicode_key() {
+    let mut decoder = KeyDecoder::new();
+
+    // Press and release 'a'. Only the press is reported.
+    assert_eq!(decoder.add_byte(0x1e), Some(KeyEvent::Unicode('a')));
+    assert_eq!(decoder.add_byte(0x9e), None);
+}
+
+#[test_case]
+fn test_key_queue_order() {
+    let queue = Ring::<_, QUEUE_SIZE>::new();
+    for i in 0..QUEUE_SIZE {
+        let c = char::from(b'a' + (i % 26) as u8);
+        assert!(queue.push(KeyEvent::Unicode(c)).is_ok());
+    }
+    assert!(queue.push(KeyEvent::Unicode('z')).is_err());
+
+    for i in 0..QUEUE_SIZE {
+        let c = char::from(b'a' + (i % 26) as u8);
+        assert_eq!(queue.pop(), Some(KeyEvent::Unicode(c)));
+    }
+    assert_eq!(queue.pop(), None);
+}
+
+#[test_case]
+fn test_lock_keys_toggle() {
+    let mut decoder = KeyDecoder::new();
+    let initial = decoder.locks();
+
+    // Press and release Caps Lock twice.
+    for &scancode in &[0x3a, 0xba] {
+        decoder.add_byte(scancode);
+    }
+    assert!(decoder.locks().caps);
+    for &scancode in &[0x3a, 0xba] {
+        decoder.add_byte(scancode);
+    }
+    assert_eq!(decoder.locks(), initial);
+}
+
+/// Feed the make code of a held key every 30ms, like the keyboard's
+/// typematic repeat does, and count which presses get through.
+#[test_case]
+fn test_key_repeat() {
+    let mut decoder = KeyDecoder::new();
+    decoder.set_repeat(Some(KeyRepeat {
+        initial_delay_ms: 500,
+        rate_ms: 100,
+    }));
+
+    let mut accepted = [0; 8];
+    let mut count = 0;
+    for now in (0..1000).step_by(30) {
+        if decoder.add_byte_at(0x1e, now) == Some(KeyEvent::Unicode('a')) {
+            accepted[count] = now;
+            count += 1;
+        }
+    }
+    assert_eq!(&accepted[..count], &[0, 510, 630, 750, 870, 990]);
+
+    // Releasing resets the delay, the next press goes through.
+    assert_eq!(decoder.add_byte_at(0x9e, 1000), None);
+    assert_eq!(
+        decoder.add_byte_at(0x1e, 1010),
+        Some(KeyEvent::Unicode('a'))
+    );
+    assert_eq!(decoder.add_byte_at(0x1e, 1040), None);
+
+    // Another key takes over.
+    assert_eq!(
+        decoder.add_byte_at(0x30, 1050),
+        Some(KeyEvent::Unicode('b'))
+    );
+    assert_eq!(decoder.add_byte_at(0x30, 1080), None);
+}
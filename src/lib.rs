@@ -3,6 +3,7 @@
 #![feature(custom_test_frameworks)]
 #![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]
+#![feature(allocator_api)]
 #![feature(const_mut_refs)]
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
@@ -10,36 +11,492 @@
 extern crate alloc;
 
 pub mod allocator;
+pub mod backtrace;
+pub mod boot_config;
+pub mod counters;
+pub mod cpu;
 pub mod gdt;
+pub mod hexdump;
 pub mod interrupts;
+pub mod keyboard;
+pub mod log_buffer;
 pub mod memory;
+pub mod mmio;
+pub mod output_limit;
+pub mod pci;
+pub mod power;
+pub mod ps2;
+pub mod ring;
+pub mod rng;
 pub mod serial;
+pub mod sink;
 pub mod vga_buffer;
 
+pub use boot_config::{parse_boot_config, BootConfig};
 #[cfg(test)]
-use bootloader::{entry_point, BootInfo};
+use bootloader::entry_point;
+use bootloader::BootInfo;
 pub use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+pub use hexdump::hexdump;
+use x86_64::structures::idt::InterruptDescriptorTable;
 
 /// Initialize all structures required by the kernel.
 pub fn init() {
+    init_without_interrupts();
+    allocator::end_single_threaded();
+    x86_64::instructions::interrupts::enable();
+}
+
+/// Everything [init] does except for enabling interrupts, so that
+/// [boot_init] can set up the heap while nothing else runs.
+fn init_without_interrupts() {
     interrupts::init_idt();
     gdt::init();
+    report_gdt_problems();
+    report_serial_problems();
+    ps2::flush();
     unsafe { interrupts::PICS.lock().initialize() };
+    interrupts::unmask_serial_interrupt();
+}
+
+/// The log level from the kernel command line, as an `u8` so that it
+/// can be atomic.
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(boot_config::LogLevel::Info as u8);
+
+/// Get the log level that was configured on the kernel command line.
+/// Before [boot_init], this is always `Info`. At `Debug` and above,
+/// [boot_init] prints the config and the [allocator::stats] of the new
+/// heap over serial.
+pub fn log_level() -> boot_config::LogLevel {
+    use boot_config::LogLevel;
+
+    match LOG_LEVEL.load(Ordering::Relaxed) {
+        0 => LogLevel::Error,
+        1 => LogLevel::Warn,
+        2 => LogLevel::Info,
+        3 => LogLevel::Debug,
+        _ => LogLevel::Trace,
+    }
+}
+
+/// Initialize the kernel like [init] does, and additionally set up
+/// paging and the heap using the information passed by the bootloader.
+/// After this, heap allocations and [memory::with_mapper] can be used.
+///
+/// The settings of [boot_config::BOOT_CONFIG] are applied here. If it
+/// can't be parsed, we print a warning and use the defaults.
+///
+/// Interrupts are only enabled at the end. With
+/// [allocator::Backend::Bump], the heap is set up without the lock of
+/// the allocator in the meantime, see [allocator::begin_single_threaded].
+///
+/// Panics if called more than once.
+pub fn boot_init(boot_info: &'static BootInfo) {
+    use memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
+    static INITIALIZED: AtomicBool = AtomicBool::new(false);
+    assert!(
+        !INITIALIZED.swap(true, Ordering::SeqCst),
+        "boot_init called more than once"
+    );
+
+    init_without_interrupts();
+    boot_banner();
+    let locks = keyboard::locks();
+    let leds =
+        interrupts::set_keyboard_leds(locks.caps, locks.num, locks.scroll);
+    report_init("keyboard", leds.is_ok());
+
+    let config =
+        parse_boot_config(boot_config::BOOT_CONFIG).unwrap_or_else(|error| {
+            println!("Invalid boot config, using defaults: {:?}", error);
+            BootConfig::default()
+        });
+    LOG_LEVEL.store(config.log_level as u8, Ordering::Relaxed);
+    if let Some(backend) = config.allocator {
+        allocator::set_backend(backend);
+    }
+    if let Some(port) = config.acpi_pm1a {
+        power::set_acpi_poweroff(port, 0);
+    }
+    // Safe because interrupts are still disabled and we only boot one
+    // CPU, so nothing else runs until end_single_threaded below.
+    if allocator::backend() == allocator::Backend::Bump {
+        unsafe { allocator::begin_single_threaded() };
+    }
+
+    // Safe because of the check above. This is the only place where we
+    // create a mapper or frame allocator, and we only do it once.
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper =
+        unsafe { memory::try_init(phys_mem_offset, &boot_info.memory_map) };
+    report_init("memory", mapper.is_ok());
+    let mut mapper = mapper.expect("Invalid physical memory offset");
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+
+    let heap = allocator::init_heap_with_size(
+        &mut mapper,
+        &mut frame_allocator,
+        config.heap_size,
+    );
+    report_init("heap", heap.is_ok());
+    heap.expect("Heap initialization failed");
+    if log_level() >= boot_config::LogLevel::Debug {
+        serial_println!("{:?}", config);
+        serial_print!("{}", allocator::stats());
+    }
+
+    memory::install(mapper, frame_allocator);
+    // Now that we have the mapper we can also check the mapping of the
+    // double fault stack.
+    report_gdt_problems();
+    report_init("gdt", gdt::check().is_ok());
+
+    allocator::end_single_threaded();
     x86_64::instructions::interrupts::enable();
 }
 
+/// Print a warning over serial if [gdt::check] finds a problem.
+fn report_gdt_problems() {
+    if let Err(error) = gdt::check() {
+        serial_println!(
+            "WARNING: {:?}, a double fault will reset the machine",
+            error
+        );
+    }
+}
+
+/// Title of the banner printed by [boot_banner].
+const BOOT_BANNER: &str = "blog_os";
+
+/// Print a framed banner with the name of the kernel, as a heading for
+/// the [report_init] lines. [boot_init] starts with this.
+pub fn boot_banner() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        vga_buffer::WRITER.lock().write_banner(BOOT_BANNER);
+    });
+}
+
+/// Print a line on screen that says whether subsystem `name` came up,
+/// with a green "[ OK ]" or a red "[FAIL]" at the right edge, see
+/// [vga_buffer::Writer::write_status]. [boot_init] calls this for each
+/// subsystem it initializes.
+pub fn report_init(name: &str, ok: bool) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        vga_buffer::WRITER.lock().write_status(name, ok);
+    });
+}
+
+/// Run the serial self test if [serial::set_test_on_init] asked for it.
+/// The warning goes to the screen, since serial may not work.
+fn report_serial_problems() {
+    if serial::test_on_init() && !serial::loopback_test() {
+        println!("WARNING: serial loopback test failed");
+    }
+}
+
 /// Loop endlessly, calling `hlt` on every iteration. This should be
 /// used in every place where we want an empty infinite loop to keep the
 /// kernel running and reacting to interrupts, but we don't really have
 /// anything to do in the current thread.
+///
+/// Before each halt, this does the work that interrupt handlers leave
+/// for later, see [interrupts::update_keyboard_leds].
 pub fn hlt_loop() -> ! {
     loop {
+        interrupts::update_keyboard_leds();
         x86_64::instructions::hlt();
     }
 }
 
+/// Halt until the next interrupt other than the timer, or until
+/// [interrupts::ticks] reaches `deadline_ticks`, whichever comes first.
+/// Returns right away if the deadline has already passed.
+///
+/// This relies on the periodic timer to wake up, so it may return up to
+/// a tick late. It's meant for an idle loop that still has to do some
+/// housekeeping every now and then. It enables interrupts while halting,
+/// but leaves them as they were when it returns.
+pub fn hlt_until(deadline_ticks: u64) {
+    use x86_64::instructions::interrupts as cpu_interrupts;
+
+    let was_enabled = cpu_interrupts::are_enabled();
+    loop {
+        // With interrupts disabled, no tick can happen between reading
+        // the ticks and halting, so we can't miss the wake-up.
+        cpu_interrupts::disable();
+        let ticks = interrupts::ticks();
+        if ticks >= deadline_ticks {
+            break;
+        }
+        cpu_interrupts::enable_and_hlt();
+        if interrupts::ticks() == ticks {
+            // Something other than the timer woke us up.
+            break;
+        }
+    }
+    if was_enabled {
+        cpu_interrupts::enable();
+    }
+}
+
+/// Print `s` on its own line, both on screen and over serial, for
+/// diagnostics from the earliest part of boot, eg before [init].
+///
+/// This writes to the VGA buffer and the serial port directly, without
+/// taking any locks or depending on anything being initialized. That
+/// also means it's unsynchronized: output from other code, including
+/// [println], can end up on top of it or in the middle of it. Once the
+/// kernel is initialized, use the normal print macros instead.
+pub fn early_print(s: &str) {
+    vga_buffer::early_write(s);
+    serial::early_write(s);
+}
+
+/// Turn the machine off, eg for a "poweroff" command.
+///
+/// This tries ACPI first, if it's configured, see [power]. Then it
+/// tries qemu's `isa-debug-exit` device with [QemuExitCode::Success].
+/// If neither works, interrupts are disabled and we halt forever, which
+/// is as close to off as we can get.
+pub fn shutdown() -> ! {
+    power::acpi_poweroff();
+    exit_qemu(QemuExitCode::Success);
+
+    x86_64::instructions::interrupts::disable();
+    hlt_loop()
+}
+
+/// Stop the kernel after an unrecoverable error, eg at the end of the
+/// panic handler.
+///
+/// Normally this just calls [hlt_loop], so that whatever was printed
+/// stays on screen. With the `qemu-exit-on-panic` feature it exits qemu
+/// with [QemuExitCode::Failed] instead, so that an automated run of the
+/// kernel terminates and reports the failure. This needs the
+/// `isa-debug-exit` device, if it is missing we fall back to halting.
+/// The exit code can be changed with [set_abort_exit_code].
+pub fn abort() -> ! {
+    if cfg!(feature = "qemu-exit-on-panic") {
+        if ABORT_SUCCEEDS.load(Ordering::Relaxed) {
+            exit_qemu(QemuExitCode::Success);
+        }
+        else {
+            exit_qemu(QemuExitCode::Failed);
+        }
+    }
+    hlt_loop()
+}
+
+/// Whether [abort] exits with [QemuExitCode::Success], see
+/// [set_abort_exit_code].
+static ABORT_SUCCEEDS: AtomicBool = AtomicBool::new(false);
+
+/// Make [abort] exit qemu with `exit_code` instead of
+/// [QemuExitCode::Failed]. This is for tests of [abort] itself, which
+/// have to exit with [QemuExitCode::Success] to pass.
+pub fn set_abort_exit_code(exit_code: QemuExitCode) {
+    ABORT_SUCCEEDS.store(exit_code == QemuExitCode::Success, Ordering::Relaxed);
+}
+
+/// The general purpose registers, stack and instruction pointers and
+/// flags, as captured by [Registers::capture].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+}
+
+impl Registers {
+    /// Read the registers. This is always inlined, so the values are
+    /// those of the caller at the point of the call. To see as much of
+    /// the state that led somewhere as possible, eg a panic, call this
+    /// before doing anything else.
+    ///
+    /// Note that the register that holds the address of the result
+    /// reads as that address. `rip` points into the capturing code.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let mut registers = Registers::default();
+        let ptr: *mut Registers = &mut registers;
+        // The offsets must match the order of the fields.
+        unsafe {
+            core::arch::asm!(
+                "mov [{ptr} + 0x00], rax",
+                "mov [{ptr} + 0x08], rbx",
+                "mov [{ptr} + 0x10], rcx",
+                "mov [{ptr} + 0x18], rdx",
+                "mov [{ptr} + 0x20], rsi",
+                "mov [{ptr} + 0x28], rdi",
+                "mov [{ptr} + 0x30], rbp",
+                "mov [{ptr} + 0x38], rsp",
+                "mov [{ptr} + 0x40], r8",
+                "mov [{ptr} + 0x48], r9",
+                "mov [{ptr} + 0x50], r10",
+                "mov [{ptr} + 0x58], r11",
+                "mov [{ptr} + 0x60], r12",
+                "mov [{ptr} + 0x68], r13",
+                "mov [{ptr} + 0x70], r14",
+                "mov [{ptr} + 0x78], r15",
+                "lea {tmp}, [rip]",
+                "mov [{ptr} + 0x80], {tmp}",
+                "pushfq",
+                "pop {tmp}",
+                "mov [{ptr} + 0x88], {tmp}",
+                ptr = in(reg) ptr,
+                tmp = out(reg) _,
+            );
+        }
+        registers
+    }
+}
+
+impl core::fmt::Display for Registers {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let registers = [
+            ("RAX", self.rax),
+            ("RBX", self.rbx),
+            ("RCX", self.rcx),
+            ("RDX", self.rdx),
+            ("RSI", self.rsi),
+            ("RDI", self.rdi),
+            ("RBP", self.rbp),
+            ("RSP", self.rsp),
+            ("R8", self.r8),
+            ("R9", self.r9),
+            ("R10", self.r10),
+            ("R11", self.r11),
+            ("R12", self.r12),
+            ("R13", self.r13),
+            ("R14", self.r14),
+            ("R15", self.r15),
+            ("RIP", self.rip),
+            ("RFLAGS", self.rflags),
+        ];
+        for (i, (name, value)) in registers.iter().enumerate() {
+            let separator = if i % 4 == 3 { "\n" } else { " " };
+            write!(f, "{:>6}={:016x}{}", name, value, separator)?;
+        }
+        Ok(())
+    }
+}
+
+/// Capture the registers with [Registers::capture] and print them over
+/// serial. Like [Registers::capture], this is inlined so that it can be
+/// called first thing in a panic handler. The captured values are
+/// returned as well.
+#[inline(always)]
+pub fn dump_registers() -> Registers {
+    let registers = Registers::capture();
+    serial_println!("--- registers ---\n{}", registers);
+    registers
+}
+
+/// Print a snapshot of the kernel's state over serial: the registers,
+/// the [allocator::stats], the memory map and the recent output kept by
+/// [log_buffer]. This is what the host gets when it sends
+/// [serial::DUMP_REQUEST].
+///
+/// Parts that are locked, or not set up yet, are reported as
+/// unavailable instead of waiting, so this can run in an interrupt
+/// handler.
+pub fn dump_diagnostics() {
+    serial_println!("\n=== diagnostic dump ===");
+    dump_registers();
+    serial_println!("{}", allocator::stats());
+
+    serial_println!("--- memory map ---");
+    match memory::try_memory_map() {
+        Some(memory_map) => {
+            for region in memory_map.iter() {
+                serial_println!(
+                    "{:#014x}..{:#014x} {:?}",
+                    region.range.start_addr(),
+                    region.range.end_addr(),
+                    region.region_type
+                );
+            }
+        }
+        None => serial_println!("unavailable"),
+    }
+
+    serial_println!("--- recent output ---");
+    if !log_buffer::try_dump(serial::send_byte) {
+        serial_print!("unavailable");
+    }
+    serial_println!("\n=== end of diagnostic dump ===");
+}
+
+/// Run a quick check of each subsystem and report the result of each
+/// over serial. Unlike the tests, this is part of the kernel, so it can
+/// be used to check that a release build works on a given machine.
+/// Returns whether every check passed.
+///
+/// This needs the heap, so call [boot_init] first. Interrupts must be
+/// enabled, otherwise the timer check fails.
+pub fn self_test() -> bool {
+    use alloc::boxed::Box;
+
+    fn report(subsystem: &str, passed: bool) -> bool {
+        let result = if passed { "pass" } else { "FAIL" };
+        serial_println!("self test {}: {}", subsystem, result);
+        passed
+    }
+
+    let mut passed = true;
+
+    let vga = vga_buffer::try_write_fmt(format_args!("self test\n"));
+    passed &= report("vga", vga.is_ok());
+
+    let serial = serial::try_write_fmt(format_args!("self test\n"));
+    passed &= report("serial", serial.is_ok());
+    passed &= report("serial loopback", serial::loopback_test());
+
+    let value = Box::new(0x_5e1f_7e57_u64);
+    let heap = *value == 0x_5e1f_7e57;
+    drop(value);
+    passed &= report("heap", heap);
+
+    let breakpoints = interrupts::breakpoints();
+    x86_64::instructions::interrupts::int3();
+    passed &= report("breakpoint", interrupts::breakpoints() > breakpoints);
+
+    // Without interrupts, waiting for a tick would hang.
+    let timer = x86_64::instructions::interrupts::are_enabled();
+    if timer {
+        let start = interrupts::ticks();
+        while interrupts::ticks() == start {
+            x86_64::instructions::hlt();
+        }
+    }
+    passed &= report("timer", timer);
+
+    passed
+}
+
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    allocator::report_alloc_error(layout);
     panic!("Allocation error: {:?}", layout)
 }
 
@@ -54,8 +511,12 @@ fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
 }
 
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    let registers = Registers::capture();
+    allocator::set_panicking();
+    interrupts::report_handler_panic(info);
     serial_println!("[failed]\n");
     serial_println!("Error: {}", info);
+    serial_println!("--- registers ---\n{}", registers);
     exit_qemu(QemuExitCode::Failed);
     hlt_loop()
 }
@@ -80,13 +541,16 @@ pub enum QemuExitCode {
 
 /// Wrapper type to use for unit tests.
 pub trait Testable {
+    /// The name of the test, as printed by [test_runner].
+    fn name(&self) -> &'static str;
+
     /// Simple wrapper to eliminate unit test boilerplate.
-    ///  - print object's name
     ///  - run `self`
     ///  - print "\[ok]\"
     ///
     /// This never prints "\[failed\]" or similar, because if a test
-    /// fails, the panic handler does that.
+    /// fails, the panic handler does that. The name of the test is
+    /// printed by [test_runner] before this is called.
     fn run(&self) -> ();
 }
 
@@ -94,8 +558,11 @@ impl<T> Testable for T
 where
     T: Fn(),
 {
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+
     fn run(&self) {
-        serial_print!("{}...\t", core::any::type_name::<T>());
         self();
         serial_println!("[ok]");
     }
@@ -112,15 +579,193 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
     }
 }
 
+/// A flag that is set when a [DropGuard] created from it is dropped.
+/// This lets tests observe whether destructors ran, eg to check that a
+/// panic aborts instead of unwinding the stack.
+pub struct DropFlag {
+    dropped: AtomicBool,
+}
+
+impl DropFlag {
+    pub const fn new() -> Self {
+        DropFlag {
+            dropped: AtomicBool::new(false),
+        }
+    }
+
+    /// Create a guard that sets the flag when it is dropped.
+    pub fn guard(&self) -> DropGuard {
+        DropGuard(self)
+    }
+
+    /// Whether a guard of this flag has been dropped.
+    pub fn dropped(&self) -> bool {
+        self.dropped.load(Ordering::SeqCst)
+    }
+}
+
+/// Sets its [DropFlag] when dropped, see [DropFlag::guard].
+pub struct DropGuard<'a>(&'a DropFlag);
+
+impl Drop for DropGuard<'_> {
+    fn drop(&mut self) {
+        self.0.dropped.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Run `body` with an IDT in which `build` has replaced some handlers,
+/// eg to catch an exception that a test provokes on purpose. The IDT
+/// that was loaded before is loaded again afterwards.
+///
+/// `build` gets a copy of the kernel's IDT, so the handlers it doesn't
+/// touch, like the timer and keyboard, keep working. If `body` never
+/// returns, eg because the custom handler exits qemu, the custom IDT
+/// simply stays loaded.
+pub fn with_custom_idt<R>(
+    build: impl FnOnce(&mut InterruptDescriptorTable),
+    body: impl FnOnce() -> R,
+) -> R {
+    use x86_64::instructions::tables::{lidt, sidt};
+
+    let mut idt = interrupts::kernel_idt();
+    build(&mut idt);
+
+    let original = sidt();
+    // Safe because `idt` outlives the time it is loaded. It is only
+    // dropped after we restore the original below, and there is no
+    // unwinding that could skip that.
+    unsafe { idt.load_unsafe() };
+    let result = body();
+    // Safe because the original table was loaded before, so it must
+    // still be valid.
+    unsafe { lidt(&original) };
+
+    result
+}
+
+/// Name of the test that is currently being run by [test_runner].
+static CURRENT_TEST: spin::Mutex<Option<&'static str>> = spin::Mutex::new(None);
+
+/// Timer tick at which the current test started.
+static CURRENT_TEST_START: AtomicU64 = AtomicU64::new(0);
+
+/// Whether [test_heartbeat] should report long running tests.
+static HEARTBEAT_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// How many timer ticks a test may run before we report that it is
+/// still running. This is roughly 5 seconds at the default PIT
+/// frequency, and the report is repeated with the same period.
+const HEARTBEAT_INTERVAL_TICKS: u64 = 91;
+
+/// Enable or disable the "still running" reports for long tests. They
+/// are enabled by default. Note that they rely on the timer interrupt,
+/// so they are never printed by tests that don't call [init].
+pub fn set_test_heartbeat(enabled: bool) {
+    HEARTBEAT_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Get the name of the test that [test_runner] is currently running, if
+/// any.
+pub fn current_test() -> Option<&'static str> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *CURRENT_TEST.lock()
+    })
+}
+
+/// Called from the timer interrupt with the current tick count. If a
+/// test has been running for a multiple of [HEARTBEAT_INTERVAL_TICKS],
+/// print a line saying so. Otherwise a hanging test would be
+/// indistinguishable from a slow one, because nothing is printed until
+/// it finishes.
+#[doc(hidden)]
+pub fn test_heartbeat(ticks: u64) {
+    if !HEARTBEAT_ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    // We're in an interrupt handler, so we must not wait for the lock.
+    // If it is taken, the runner is in the middle of switching tests
+    // anyway.
+    let name = match CURRENT_TEST.try_lock() {
+        Some(current) => match *current {
+            Some(name) => name,
+            None => return,
+        },
+        None => return,
+    };
+
+    let elapsed =
+        ticks.saturating_sub(CURRENT_TEST_START.load(Ordering::Relaxed));
+    if elapsed > 0 && elapsed % HEARTBEAT_INTERVAL_TICKS == 0 {
+        serial_println!("\n    {} still running ({} ticks)", name, elapsed);
+    }
+}
+
 pub fn test_runner(tests: &[&dyn Testable]) {
     serial_println!("Running {} tests", tests.len());
     for test in tests {
+        // Print the name before running the test, so that we know which
+        // test is to blame if it never finishes.
+        serial_print!("{}...\t", test.name());
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            *CURRENT_TEST.lock() = Some(test.name());
+            CURRENT_TEST_START.store(interrupts::ticks(), Ordering::Relaxed);
+        });
+
         test.run();
+
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            *CURRENT_TEST.lock() = None;
+        });
     }
 
     exit_qemu(QemuExitCode::Success);
 }
 
+/// A [core::fmt::Write] that stores up to `N` bytes written to it, for
+/// testing formatting code. Writing more than that panics.
+#[cfg(test)]
+pub(crate) struct Capture<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+}
+
+#[cfg(test)]
+impl<const N: usize> Capture<N> {
+    pub(crate) fn new() -> Self {
+        Capture {
+            bytes: [0; N],
+            len: 0,
+        }
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+#[cfg(test)]
+impl<const N: usize> core::fmt::Write for Capture<N> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.len + s.len();
+        self.bytes[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// A value that always fails to format, for testing that errors from
+/// `Display` implementations are passed on.
+#[cfg(test)]
+pub(crate) struct FailingDisplay;
+
+#[cfg(test)]
+impl core::fmt::Display for FailingDisplay {
+    fn fmt(&self, _f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        Err(core::fmt::Error)
+    }
+}
+
 /// Throw a breakpoint exception to verify that it works. Note that this
 /// does not check its behavior. But the fact that the function returns
 /// instead of panicking at leats verifies that we register the
@@ -129,3 +774,95 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 fn test_breakpoint_exception() {
     x86_64::instructions::interrupts::int3();
 }
+
+#[test_case]
+fn test_with_custom_idt() {
+    use x86_64::instructions::interrupts::int3;
+    use x86_64::structures::idt::InterruptStackFrame;
+
+    static CUSTOM_BREAKPOINTS: AtomicU64 = AtomicU64::new(0);
+
+    extern "x86-interrupt" fn custom_breakpoint_handler(
+        _stack_frame: InterruptStackFrame,
+    ) {
+        CUSTOM_BREAKPOINTS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    let before = interrupts::breakpoints();
+    with_custom_idt(
+        |idt| {
+            idt.breakpoint.set_handler_fn(custom_breakpoint_handler);
+        },
+        int3,
+    );
+    assert_eq!(CUSTOM_BREAKPOINTS.load(Ordering::SeqCst), 1);
+    assert_eq!(interrupts::breakpoints(), before);
+
+    // The kernel's handler is back.
+    int3();
+    assert_eq!(CUSTOM_BREAKPOINTS.load(Ordering::SeqCst), 1);
+    assert_eq!(interrupts::breakpoints(), before + 1);
+}
+
+#[test_case]
+fn test_report_init() {
+    use vga_buffer::{Color, ColorCode, BUFFER_HEIGHT, BUFFER_WIDTH, WRITER};
+
+    report_init("memory", true);
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let writer = WRITER.lock();
+        let row = BUFFER_HEIGHT - 2;
+        let text = |cols: core::ops::Range<usize>| {
+            cols.map(|col| writer.char_at(row, col).ascii())
+        };
+        assert!(text(0..6).eq(*b"memory"));
+        assert!(text(6..BUFFER_WIDTH - 6).all(|byte| byte == b' '));
+        assert!(text(BUFFER_WIDTH - 6..BUFFER_WIDTH).eq(*b"[ OK ]"));
+
+        let green = ColorCode::new(Color::LightGreen, Color::Black);
+        for col in BUFFER_WIDTH - 6..BUFFER_WIDTH {
+            assert_eq!(writer.char_at(row, col).color(), green);
+        }
+    });
+}
+
+#[test_case]
+fn test_hlt_until() {
+    let deadline = interrupts::ticks() + 3;
+    hlt_until(deadline);
+    let ticks = interrupts::ticks();
+    assert!(
+        ticks >= deadline && ticks <= deadline + 1,
+        "Woke at {}",
+        ticks
+    );
+    assert!(x86_64::instructions::interrupts::are_enabled());
+
+    // A deadline in the past doesn't halt at all. If it did, it would
+    // wait for a tick.
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let before = interrupts::ticks();
+        hlt_until(0);
+        assert_eq!(interrupts::ticks(), before);
+    });
+}
+
+#[test_case]
+fn test_drop_flag() {
+    let flag = DropFlag::new();
+    let guard = flag.guard();
+    assert!(!flag.dropped());
+    drop(guard);
+    assert!(flag.dropped());
+}
+
+/// The runner must have registered (and therefore printed) the name of
+/// the test before the body starts.
+#[test_case]
+fn test_name_is_set_before_body() {
+    assert_eq!(
+        current_test(),
+        Some("blog_os::test_name_is_set_before_body")
+    );
+}
@@ -0,0 +1,140 @@
+//! Ring buffer of recent output
+//!
+//! Everything printed with [crate::print] and [crate::println] is also
+//! copied into a fixed size buffer. When the buffer is full, the oldest
+//! bytes are overwritten. This doesn't need the heap, so it works from
+//! the very start and even if the allocator is broken. The intended use
+//! is to call [dump_to_serial] from the panic handler, so that we can
+//! see what led to the panic even if it has scrolled off screen.
+
+use core::fmt;
+use spin::Mutex;
+
+/// How many bytes of output are kept.
+const LOG_BUFFER_SIZE: usize = 4096;
+
+/// A fixed size byte buffer that overwrites its oldest contents when it
+/// is full.
+pub struct LogBuffer<const N: usize> {
+    bytes: [u8; N],
+    start: usize,
+    len: usize,
+}
+
+impl<const N: usize> LogBuffer<N> {
+    pub const fn new() -> Self {
+        LogBuffer {
+            bytes: [0; N],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    /// Append `bytes` to the buffer, overwriting the oldest bytes if
+    /// there's not enough room.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            let end = (self.start + self.len) % N;
+            self.bytes[end] = byte;
+            if self.len < N {
+                self.len += 1;
+            }
+            else {
+                self.start = (self.start + 1) % N;
+            }
+        }
+    }
+
+    /// Get the contents of the buffer, from oldest to newest. Because
+    /// the data wraps around, they are returned as two slices that must
+    /// be read one after the other.
+    pub fn contents(&self) -> (&[u8], &[u8]) {
+        let end = self.start + self.len;
+        if end <= N {
+            (&self.bytes[self.start..end], &[])
+        }
+        else {
+            (&self.bytes[self.start..], &self.bytes[..end - N])
+        }
+    }
+}
+
+impl<const N: usize> fmt::Write for LogBuffer<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+static LOG: Mutex<LogBuffer<LOG_BUFFER_SIZE>> = Mutex::new(LogBuffer::new());
+
+// This is not really intended to be a part of the public API, but it
+// has to be since the print macros use it through vga_buffer::_print.
+#[doc(hidden)]
+pub fn _log(args: fmt::Arguments) {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        // Writing to the buffer can't fail.
+        let _ = LOG.lock().write_fmt(args);
+    });
+}
+
+/// Pass every byte of the log to `sink`, from oldest to newest.
+///
+/// If the log is locked, it is forcibly unlocked. This is meant to be
+/// used after a panic, when whoever held the lock is never going to
+/// resume. Don't use it in other situations.
+pub fn dump(mut sink: impl FnMut(u8)) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if LOG.is_locked() {
+            unsafe { LOG.force_unlock() };
+        }
+        let log = LOG.lock();
+        let (first, second) = log.contents();
+        for &byte in first.iter().chain(second) {
+            sink(byte);
+        }
+    });
+}
+
+/// Like [dump], but return `false` without calling `sink` if the log
+/// is locked, instead of unlocking it. This can be used at any time.
+pub fn try_dump(mut sink: impl FnMut(u8)) -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let log = match LOG.try_lock() {
+            Some(log) => log,
+            None => return false,
+        };
+        let (first, second) = log.contents();
+        for &byte in first.iter().chain(second) {
+            sink(byte);
+        }
+        true
+    })
+}
+
+/// Print the log to the serial port, with a header and footer to make
+/// it easy to spot. See [dump] for the caveats.
+pub fn dump_to_serial() {
+    crate::serial_println!("\n--- recent output ---");
+    dump(|byte| crate::serial::send_byte(byte));
+    crate::serial_println!("\n--- end of recent output ---");
+}
+
+#[test_case]
+fn test_log_buffer_wraps_around() {
+    let mut log = LogBuffer::<8>::new();
+
+    log.write_bytes(b"abc");
+    assert_eq!(log.contents(), (&b"abc"[..], &b""[..]));
+
+    log.write_bytes(b"defghij");
+    let (first, second) = log.contents();
+    assert_eq!(first.len() + second.len(), 8);
+    let mut contents = [0; 8];
+    contents[..first.len()].copy_from_slice(first);
+    contents[first.len()..].copy_from_slice(second);
+    assert_eq!(&contents, b"cdefghij");
+}
@@ -19,10 +19,10 @@ use core::panic::PanicInfo;
 
 entry_point!(kernel_main);
 
-fn kernel_main(_boot_info: &'static BootInfo) -> ! {
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
     println!("Hello {}!", "world");
 
-    blog_os::init();
+    blog_os::boot_init(boot_info);
 
     #[cfg(test)]
     test_main();
@@ -33,13 +33,28 @@ fn kernel_main(_boot_info: &'static BootInfo) -> ! {
 }
 
 /// Custom panic handler. This is a requirement for no_std. We can't do
-/// something truly meaningful at this time. Just loop forever, ie
-/// freeze the system.
+/// something truly meaningful at this time. Just print the info so that
+/// it stands out, dump the registers, a backtrace and the recent output
+/// over serial, and [abort](blog_os::abort), ie freeze the system or
+/// exit qemu. A panic in an interrupt handler only gets the short report
+/// of [report_handler_panic](blog_os::interrupts::report_handler_panic),
+/// since the handler might have interrupted code in the middle of
+/// printing. None of this allocates, see
+/// [set_panicking](blog_os::allocator::set_panicking).
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
-    blog_os::hlt_loop();
+    // This must come first, before the rest overwrites the registers.
+    let registers = blog_os::Registers::capture();
+    blog_os::allocator::set_panicking();
+    if blog_os::interrupts::report_handler_panic(info) {
+        blog_os::abort();
+    }
+    blog_os::serial_println!("--- registers ---\n{}", registers);
+    blog_os::backtrace::print();
+    blog_os::vga_buffer::print_panic(info);
+    blog_os::log_buffer::dump_to_serial();
+    blog_os::abort();
 }
 
 #[cfg(test)]
@@ -1,23 +1,101 @@
 //! Memory paging
+//!
+//! [crate::boot_init] creates the kernel's only [OffsetPageTable] and
+//! [BootInfoFrameAllocator] and hands them over to this module with
+//! [install]. From then on, use [with_mapper] to get access to them
+//! instead of passing them around.
+//!
+//! For range math, like which pages a buffer touches, use [VirtRange]
+//! and [PhysRange], or [frames_for_range] and [pages_for_range] for
+//! just the frames or pages.
+//!
+//! Before dereferencing a pointer that you only assume is mapped, you
+//! can check it with [crate::assert_mapped]. A mistake then panics at
+//! the assertion with the address, instead of page faulting somewhere
+//! further down.
+//!
+//! Stacks for kernel tasks come from [allocate_stack], which leaves a
+//! guard page below each one to catch overflows.
 
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+pub mod range;
+pub mod stack_allocator;
+
+use crate::allocator::Locked;
+use alloc::vec::Vec;
+use bootloader::bootinfo::{MemoryMap, MemoryRegion, MemoryRegionType};
+use x86_64::structures::paging::mapper::{
+    FlagUpdateError, MapToError, Translate,
+};
 use x86_64::structures::paging::{
-    FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB,
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize,
+    PageTable, PageTableFlags, PhysFrame, Size4KiB,
 };
 use x86_64::{PhysAddr, VirtAddr};
 
-/// Initialize a new [OffsetPageTable].
+pub use range::{PhysRange, VirtRange};
+pub use stack_allocator::{allocate_stack, StackBounds};
+
+/// Initialize a new [OffsetPageTable]. See [try_init] for a version
+/// that checks the offset against the memory map.
 ///
 /// It is unsafe because the caller must guarantee that the entire
 /// physical address is mapped at the offset given as the argument.
 /// Additionally, it must never be called more than once.
+///
+/// Panics if the offset is 0, which means that the bootloader didn't
+/// map physical memory. Carrying on would only fault later, at some
+/// unrelated access to a page table.
 pub unsafe fn init(
     physical_memory_offset: VirtAddr,
 ) -> OffsetPageTable<'static> {
+    assert!(
+        !physical_memory_offset.is_null(),
+        "Physical memory offset is 0, is the bootloader configured to map \
+         physical memory?"
+    );
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+/// Returned by [try_init] for a physical memory offset that can't be
+/// right.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OffsetError {
+    /// The offset is 0, ie the bootloader didn't map physical memory.
+    Zero,
+    /// Physical memory would be mapped on top of itself. Holds the
+    /// offset and the end of the highest region of the memory map.
+    BelowMemoryEnd { offset: u64, memory_end: u64 },
+}
+
+/// Like [init], but first check that `physical_memory_offset` is
+/// plausible for `memory_map`, ie that it is not 0 and that all of
+/// physical memory fits below it. The bootloader always puts the
+/// mapping above physical memory, so anything else means that it was
+/// misconfigured or that the offset got mixed up on the way.
+///
+/// This is unsafe for the same reasons as [init], except that nothing
+/// is created if it returns an error.
+pub unsafe fn try_init(
+    physical_memory_offset: VirtAddr,
+    memory_map: &MemoryMap,
+) -> Result<OffsetPageTable<'static>, OffsetError> {
+    let offset = physical_memory_offset.as_u64();
+    if offset == 0 {
+        return Err(OffsetError::Zero);
+    }
+    let memory_end = memory_map
+        .iter()
+        .map(|region| region.range.end_addr())
+        .max()
+        .unwrap_or(0);
+    if offset < memory_end {
+        return Err(OffsetError::BelowMemoryEnd { offset, memory_end });
+    }
+
+    Ok(init(physical_memory_offset))
+}
+
 /// Get a reference to the level 4 page table.
 ///
 /// It is unsafe because the caller must guarantee that the entire
@@ -58,6 +136,11 @@ impl BootInfoFrameAllocator {
         }
     }
 
+    /// The memory map this allocator was created from.
+    pub fn memory_map(&self) -> &'static MemoryMap {
+        self.memory_map
+    }
+
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
         // Get only the usable regions
         let usable_regions = self
@@ -83,3 +166,565 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
         frame
     }
 }
+
+impl BootInfoFrameAllocator {
+    /// Turn this into a [CachedFrameAllocator] holding up to `capacity`
+    /// of the frames that have not been allocated yet. Any frames
+    /// beyond that are never handed out.
+    ///
+    /// This requires the heap. Every frame takes up 8 bytes, so pick a
+    /// capacity that fits comfortably.
+    pub fn into_cached(self, capacity: usize) -> CachedFrameAllocator {
+        let mut frames = Vec::with_capacity(capacity);
+        frames.extend(self.usable_frames().skip(self.next).take(capacity));
+        CachedFrameAllocator { frames, capacity }
+    }
+}
+
+/// Frame allocator that keeps its free frames in a [Vec].
+///
+/// [BootInfoFrameAllocator] has to walk the memory map for every
+/// allocation and can't take frames back. Once the heap is available,
+/// convert it with [BootInfoFrameAllocator::into_cached] to get
+/// constant time allocation and deallocation.
+pub struct CachedFrameAllocator {
+    frames: Vec<PhysFrame>,
+    capacity: usize,
+}
+
+impl CachedFrameAllocator {
+    /// Number of frames that are currently available.
+    pub fn free_frames(&self) -> usize {
+        self.frames.len()
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for CachedFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.frames.pop()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for CachedFrameAllocator {
+    /// Return `frame` to the allocator.
+    ///
+    /// The [Vec] never grows beyond the capacity it was created with,
+    /// so this never allocates. If it is full, which means that more
+    /// frames were returned than were handed out, the frame is dropped.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        if self.frames.len() < self.capacity {
+            self.frames.push(frame);
+        }
+    }
+}
+
+/// Map `page` to a newly allocated frame with the given `flags`, eg to
+/// create read-only, user accessible or uncached mappings. The frame is
+/// not zeroed.
+///
+/// This is safe because the frame is unused and the mapping fails with
+/// [MapToError::PageAlreadyMapped] instead of replacing an existing
+/// one.
+pub fn create_mapping(
+    page: Page,
+    flags: PageTableFlags,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    // Safe because nothing else refers to the new frame.
+    unsafe { mapper.map_to(page, frame, flags, frame_allocator) }?.flush();
+    Ok(())
+}
+
+/// Map `page` as present and writable, like the example mapping of the
+/// paging demo. See [create_mapping].
+pub fn create_example_mapping(
+    page: Page,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    create_mapping(page, flags, mapper, frame_allocator)
+}
+
+/// Replace the flags of the existing mapping of `page` with `flags`,
+/// eg to make it read-only, and flush it from the TLB. The page keeps
+/// its frame.
+///
+/// This is unsafe because code may rely on the old flags. For instance
+/// removing [PageTableFlags::PRESENT] from a page that still holds live
+/// data makes every reference into it dangling.
+pub unsafe fn update_flags(
+    page: Page,
+    flags: PageTableFlags,
+    mapper: &mut impl Mapper<Size4KiB>,
+) -> Result<(), FlagUpdateError> {
+    mapper.update_flags(page, flags)?.flush();
+    Ok(())
+}
+
+/// Copy the contents of `src_frame` to a newly allocated frame and
+/// return that, eg to give a copy-on-write page its own copy. Returns
+/// `None` if there are no frames left.
+///
+/// Both frames are accessed through the mapping of physical memory at
+/// `physical_memory_offset`. This is unsafe because the caller must
+/// guarantee that all physical memory is mapped there, like for [init],
+/// and that nothing writes to `src_frame` while it is copied.
+pub unsafe fn clone_page(
+    src_frame: PhysFrame,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    physical_memory_offset: VirtAddr,
+) -> Option<PhysFrame> {
+    let frame = frame_allocator.allocate_frame()?;
+    let src = physical_memory_offset + src_frame.start_address().as_u64();
+    let dst = physical_memory_offset + frame.start_address().as_u64();
+    core::ptr::copy_nonoverlapping(
+        src.as_ptr::<u8>(),
+        dst.as_mut_ptr::<u8>(),
+        Size4KiB::SIZE as usize,
+    );
+    Some(frame)
+}
+
+/// Errors returned by [identity_map].
+#[derive(Debug)]
+pub enum IdentityMapError {
+    /// The physical range overlaps a region of the given type, which
+    /// must not be mapped without forcing it. See [check_phys_range].
+    ProtectedRegion(MemoryRegionType),
+    /// The physical range goes beyond the addresses supported by the
+    /// CPU. See [check_phys_width].
+    AddressTooWide(PhysAddr),
+    /// Creating the mapping failed.
+    MapTo(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for IdentityMapError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        IdentityMapError::MapTo(error)
+    }
+}
+
+/// Check that the physical range of `size` bytes starting at `start`
+/// does not overlap any of the `regions` that are reserved by the
+/// firmware or marked as bad. Writing to these, eg through DMA, could
+/// corrupt memory that the firmware relies on.
+///
+/// Parts of the range that are not described by any region are
+/// allowed, because that's where memory mapped devices usually are.
+pub fn check_phys_range(
+    regions: &[MemoryRegion],
+    start: PhysAddr,
+    size: u64,
+) -> Result<(), IdentityMapError> {
+    // Clamp ranges that go beyond the physical address space. They
+    // would be rejected with check_phys_width anyway.
+    let max_size = (1u64 << 52) - start.as_u64();
+    let range = PhysRange::new(start, size.min(max_size));
+
+    let protected = regions.iter().find(|r| {
+        let region = PhysRange::new(
+            PhysAddr::new(r.range.start_addr()),
+            r.range.end_addr() - r.range.start_addr(),
+        );
+        matches!(
+            r.region_type,
+            MemoryRegionType::Reserved
+                | MemoryRegionType::AcpiNvs
+                | MemoryRegionType::BadMemory
+        ) && range.overlaps(&region)
+    });
+
+    match protected {
+        Some(region) => {
+            Err(IdentityMapError::ProtectedRegion(region.region_type))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Check that the physical range of `size` bytes starting at `start`
+/// fits in the physical address width of the CPU, see
+/// [crate::cpu::phys_addr_bits]. The error holds the first address that
+/// doesn't. Mapping such an address would not fail, but every access
+/// through the mapping would fault.
+pub fn check_phys_width(
+    start: PhysAddr,
+    size: u64,
+) -> Result<(), IdentityMapError> {
+    let limit = 1u64 << crate::cpu::phys_addr_bits();
+    let start = start.as_u64();
+    if start >= limit || size > limit - start {
+        let first_invalid = start.max(limit);
+        return Err(IdentityMapError::AddressTooWide(PhysAddr::new_truncate(
+            first_invalid,
+        )));
+    }
+    Ok(())
+}
+
+/// Map the physical range of `size` bytes starting at `start` to the
+/// same virtual addresses, eg for DMA or memory mapped IO.
+///
+/// The range is checked with [check_phys_width] and against the memory
+/// map of `frame_allocator` with [check_phys_range]. The latter is
+/// skipped if `force` is set. Only set it if you know that you need to
+/// access a reserved region.
+///
+/// This is unsafe because the caller must make sure that the virtual
+/// range is unused and that accessing the physical range is safe.
+pub unsafe fn identity_map(
+    start: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+    force: bool,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> Result<(), IdentityMapError> {
+    if size == 0 {
+        return Ok(());
+    }
+    check_phys_width(start, size)?;
+    if !force {
+        check_phys_range(frame_allocator.memory_map(), start, size)?;
+    }
+
+    // check_phys_width made sure that the range is valid.
+    for frame in frames_for_range(start, size as usize) {
+        mapper.identity_map(frame, flags, frame_allocator)?.flush();
+    }
+
+    Ok(())
+}
+
+/// Every frame that contains part of the `len` bytes at `start`, ie
+/// from the frame containing `start` up to the one containing the last
+/// byte. Yields nothing if `len` is 0. This is [PhysRange::frames]
+/// without building the range first.
+///
+/// Panics if the range goes beyond the 52 bits of physical address that
+/// x86_64 allows.
+pub fn frames_for_range(
+    start: PhysAddr,
+    len: usize,
+) -> impl Iterator<Item = PhysFrame<Size4KiB>> {
+    PhysRange::new(start, len as u64).frames()
+}
+
+/// Every page that contains part of the `len` bytes at `start`, like
+/// [frames_for_range] does for frames. See [VirtRange::pages].
+///
+/// Panics if the range is not canonical all the way through.
+pub fn pages_for_range(
+    start: VirtAddr,
+    len: usize,
+) -> impl Iterator<Item = Page<Size4KiB>> {
+    VirtRange::new(start, len as u64).pages()
+}
+
+/// Errors returned by [frame_from_addr] and [page_from_addr]. Each
+/// holds the address that was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddrError {
+    /// The address is not at the start of a frame or page.
+    Misaligned(u64),
+    /// The virtual address is not canonical, ie bits 48 to 63 are not
+    /// all copies of bit 47.
+    NonCanonical(u64),
+    /// The physical address is wider than the CPU supports, see
+    /// [crate::cpu::phys_addr_bits].
+    TooWide(u64),
+}
+
+/// Get the frame that starts at `addr`, eg for an address that was
+/// typed in by the user. Unlike the constructors of [PhysFrame], this
+/// returns an error for invalid input instead of panicking.
+pub fn frame_from_addr(addr: u64) -> Result<PhysFrame, AddrError> {
+    if addr >> crate::cpu::phys_addr_bits() != 0 {
+        return Err(AddrError::TooWide(addr));
+    }
+    let addr = PhysAddr::try_new(addr).map_err(|_| AddrError::TooWide(addr))?;
+    PhysFrame::from_start_address(addr)
+        .map_err(|_| AddrError::Misaligned(addr.as_u64()))
+}
+
+/// Get the page that starts at `addr`. See [frame_from_addr].
+pub fn page_from_addr(addr: u64) -> Result<Page, AddrError> {
+    let addr =
+        VirtAddr::try_new(addr).map_err(|_| AddrError::NonCanonical(addr))?;
+    Page::from_start_address(addr)
+        .map_err(|_| AddrError::Misaligned(addr.as_u64()))
+}
+
+/// Number of flags shown by [format_flags].
+const FLAG_CHARS: usize = 10;
+
+/// Compact representation of [PageTableFlags], see [format_flags].
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct FlagString([u8; FLAG_CHARS]);
+
+impl FlagString {
+    pub fn as_bytes(&self) -> &[u8; FLAG_CHARS] {
+        &self.0
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ever contains ASCII letters and dashes.
+        core::str::from_utf8(&self.0).unwrap()
+    }
+}
+
+impl core::fmt::Display for FlagString {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl core::fmt::Debug for FlagString {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+/// Format `flags` as a fixed width string with one letter for each flag
+/// that is set and a dash for each one that isn't, eg `PW--------` for
+/// a present and writable page. The positions are, in order:
+///  - `P`: present
+///  - `W`: writable
+///  - `U`: user accessible
+///  - `T`: write through
+///  - `C`: no cache
+///  - `A`: accessed
+///  - `D`: dirty
+///  - `H`: huge page
+///  - `G`: global
+///  - `X`: no execute
+///
+/// Flags that are not listed, like the ones available to the OS, are
+/// not shown.
+pub fn format_flags(flags: PageTableFlags) -> FlagString {
+    const FLAGS: [(PageTableFlags, u8); FLAG_CHARS] = [
+        (PageTableFlags::PRESENT, b'P'),
+        (PageTableFlags::WRITABLE, b'W'),
+        (PageTableFlags::USER_ACCESSIBLE, b'U'),
+        (PageTableFlags::WRITE_THROUGH, b'T'),
+        (PageTableFlags::NO_CACHE, b'C'),
+        (PageTableFlags::ACCESSED, b'A'),
+        (PageTableFlags::DIRTY, b'D'),
+        (PageTableFlags::HUGE_PAGE, b'H'),
+        (PageTableFlags::GLOBAL, b'G'),
+        (PageTableFlags::NO_EXECUTE, b'X'),
+    ];
+
+    let mut string = [b'-'; FLAG_CHARS];
+    for (c, &(flag, letter)) in string.iter_mut().zip(FLAGS.iter()) {
+        if flags.contains(flag) {
+            *c = letter;
+        }
+    }
+    FlagString(string)
+}
+
+/// The mapper and frame allocator that are shared by the entire kernel.
+struct KernelMemory {
+    mapper: OffsetPageTable<'static>,
+    frame_allocator: BootInfoFrameAllocator,
+}
+
+static KERNEL_MEMORY: Locked<Option<KernelMemory>> = Locked::new(None);
+
+/// Store the kernel's mapper and frame allocator so that they can be
+/// accessed through [with_mapper].
+///
+/// Because [init] must never be called more than once, there is only
+/// ever one [OffsetPageTable] for the active level 4 table. Moving it
+/// here means nobody else holds a reference to it, and the lock makes
+/// sure that [with_mapper] never hands out aliasing `&mut`s.
+///
+/// Panics if called more than once.
+pub fn install(
+    mapper: OffsetPageTable<'static>,
+    frame_allocator: BootInfoFrameAllocator,
+) {
+    let mut kernel_memory = KERNEL_MEMORY.lock();
+    assert!(kernel_memory.is_none(), "Kernel memory already installed");
+    *kernel_memory = Some(KernelMemory {
+        mapper,
+        frame_allocator,
+    });
+}
+
+/// Check whether [install] has been called.
+pub fn is_installed() -> bool {
+    KERNEL_MEMORY.lock().is_some()
+}
+
+/// Check whether `addr` is mapped by `mapper`.
+pub fn is_mapped(addr: VirtAddr, mapper: &impl Translate) -> bool {
+    mapper.translate_addr(addr).is_some()
+}
+
+/// Panic if `addr` is not mapped, in debug builds. In release builds
+/// this does nothing, so it costs nothing.
+///
+/// This uses the kernel's mapper, so it can't be used before [install]
+/// or from code that holds the lock of [with_mapper].
+#[macro_export]
+macro_rules! assert_mapped {
+    ($addr:expr) => {
+        if cfg!(debug_assertions) {
+            $crate::memory::_assert_mapped($addr, stringify!($addr));
+        }
+    };
+}
+
+// This is not really intended to be a part of the public API, but it
+// has to be since assert_mapped uses it.
+#[doc(hidden)]
+#[track_caller]
+pub fn _assert_mapped(addr: VirtAddr, expr: &str) {
+    if !with_mapper(|mapper, _| is_mapped(addr, mapper)) {
+        panic!("assertion failed: {} ({:#x}) is not mapped", expr, addr);
+    }
+}
+
+/// Get the flags of the page table entry that maps `addr`, if any.
+///
+/// Unlike [with_mapper], this never waits for the lock, so it can be
+/// used from exception handlers. It returns `None` if the lock is taken
+/// or [install] has not been called yet, as well as when `addr` is not
+/// mapped.
+pub fn try_page_flags(addr: VirtAddr) -> Option<PageTableFlags> {
+    use x86_64::structures::paging::mapper::TranslateResult;
+
+    let kernel_memory = KERNEL_MEMORY.try_lock()?;
+    match kernel_memory.as_ref()?.mapper.translate(addr) {
+        TranslateResult::Mapped { flags, .. } => Some(flags),
+        _ => None,
+    }
+}
+
+/// Get the memory map that the frame allocator of [install] uses.
+///
+/// Like [try_page_flags], this never waits for the lock. It returns
+/// `None` if the lock is taken or [install] has not been called yet.
+pub fn try_memory_map() -> Option<&'static MemoryMap> {
+    let kernel_memory = KERNEL_MEMORY.try_lock()?;
+    Some(kernel_memory.as_ref()?.frame_allocator.memory_map())
+}
+
+/// Call `f` with the kernel's mapper and frame allocator. Both are
+/// locked for the duration of the call.
+///
+/// Don't call this from interrupt handlers, or from within `f`. Either
+/// could deadlock on the lock.
+///
+/// Panics if [install] has not been called yet.
+pub fn with_mapper<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut OffsetPageTable<'static>, &mut BootInfoFrameAllocator) -> R,
+{
+    let mut kernel_memory = KERNEL_MEMORY.lock();
+    let kernel_memory = kernel_memory
+        .as_mut()
+        .expect("memory::with_mapper called before memory::install");
+    f(
+        &mut kernel_memory.mapper,
+        &mut kernel_memory.frame_allocator,
+    )
+}
+
+#[test_case]
+fn test_format_flags() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    assert_eq!(format_flags(flags).as_str(), "PW--------");
+    assert_eq!(format_flags(PageTableFlags::all()).as_str(), "PWUTCADHGX");
+}
+
+#[test_case]
+fn test_check_phys_width() {
+    let bits = crate::cpu::phys_addr_bits();
+    let limit = 1u64 << bits;
+    assert!(check_phys_width(PhysAddr::new(0), 4096).is_ok());
+    assert!(check_phys_width(PhysAddr::new(limit - 4096), 4096).is_ok());
+    assert!(matches!(
+        check_phys_width(PhysAddr::new(limit - 4096), 4097),
+        Err(IdentityMapError::AddressTooWide(addr)) if addr.as_u64() == limit
+    ));
+}
+
+#[test_case]
+fn test_frame_and_page_from_addr() {
+    let limit = 1u64 << crate::cpu::phys_addr_bits();
+    assert_eq!(
+        frame_from_addr(0x1000),
+        Ok(PhysFrame::containing_address(PhysAddr::new(0x1000)))
+    );
+    assert_eq!(frame_from_addr(0x1234), Err(AddrError::Misaligned(0x1234)));
+    assert_eq!(frame_from_addr(limit), Err(AddrError::TooWide(limit)));
+    assert_eq!(
+        frame_from_addr(u64::MAX & !0xfff),
+        Err(AddrError::TooWide(u64::MAX & !0xfff))
+    );
+
+    let page = page_from_addr(0x_ffff_8000_0000_0000).unwrap();
+    assert_eq!(page.start_address().as_u64(), 0x_ffff_8000_0000_0000);
+    assert_eq!(
+        page_from_addr(0x_0000_8000_0000_0000),
+        Err(AddrError::NonCanonical(0x_0000_8000_0000_0000))
+    );
+    assert_eq!(
+        page_from_addr(0x_4444_4444_0008),
+        Err(AddrError::Misaligned(0x_4444_4444_0008))
+    );
+}
+
+#[test_case]
+fn test_check_phys_range() {
+    use bootloader::bootinfo::FrameRange;
+
+    let regions = [
+        MemoryRegion {
+            range: FrameRange::new(0x0, 0x9f000),
+            region_type: MemoryRegionType::Usable,
+        },
+        MemoryRegion {
+            range: FrameRange::new(0x9f000, 0xa0000),
+            region_type: MemoryRegionType::Reserved,
+        },
+    ];
+
+    let check =
+        |start, size| check_phys_range(&regions, PhysAddr::new(start), size);
+
+    assert!(check(0x1000, 0x2000).is_ok());
+    assert!(matches!(
+        check(0x9f000, 0x1000),
+        Err(IdentityMapError::ProtectedRegion(
+            MemoryRegionType::Reserved
+        ))
+    ));
+    // Partial overlap is still an overlap.
+    assert!(check(0x9e000, 0x2000).is_err());
+    // Ends right where the reserved region starts.
+    assert!(check(0x9e000, 0x1000).is_ok());
+}
+
+#[test_case]
+fn test_frames_for_range() {
+    let starts = |start, len| {
+        frames_for_range(PhysAddr::new(start), len)
+            .map(|frame| frame.start_address().as_u64())
+    };
+    assert!(starts(0xff8, 16).eq([0x0, 0x1000]));
+    assert!(starts(0x1000, 0x1000).eq([0x1000]));
+    assert_eq!(starts(0x1000, 0).count(), 0);
+
+    let pages = pages_for_range(VirtAddr::new(0x_4444_0fff), 2)
+        .map(|page| page.start_address().as_u64());
+    assert!(pages.eq([0x_4444_0000, 0x_4444_1000]));
+}
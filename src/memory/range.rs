@@ -0,0 +1,242 @@
+//! Ranges of virtual and physical addresses
+//!
+//! [VirtRange] and [PhysRange] describe `size` bytes starting at some
+//! address and do the range math that is easy to get off by one, like
+//! finding the pages that a range touches or whether two ranges
+//! overlap. Both are checked when they are created, so none of their
+//! methods can overflow.
+
+use x86_64::structures::paging::{Page, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// A range of `size` bytes of virtual memory starting at `start`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtRange {
+    start: VirtAddr,
+    size: u64,
+}
+
+impl VirtRange {
+    /// Create a range of `size` bytes starting at `start`.
+    ///
+    /// Panics if the range wraps around the address space or crosses
+    /// the hole of non-canonical addresses. See [Self::try_new].
+    pub fn new(start: VirtAddr, size: u64) -> Self {
+        Self::try_new(start, size).expect("Invalid virtual range")
+    }
+
+    /// Like [Self::new], but return `None` instead of panicking.
+    pub fn try_new(start: VirtAddr, size: u64) -> Option<Self> {
+        if size > 0 {
+            let start = start.as_u64();
+            let last = start.checked_add(size - 1)?;
+            VirtAddr::try_new(last).ok()?;
+            // Canonical addresses are either all in the lower half or
+            // all in the upper half, distinguished by bits 47 to 63.
+            if start >> 47 != last >> 47 {
+                return None;
+            }
+        }
+        Some(VirtRange { start, size })
+    }
+
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// The last address in the range, or `None` if it is empty.
+    pub fn last(&self) -> Option<VirtAddr> {
+        match self.size {
+            0 => None,
+            size => Some(self.start + (size - 1)),
+        }
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        contains(self.start.as_u64(), self.size, addr.as_u64())
+    }
+
+    /// Check whether the ranges have at least one address in common.
+    /// Ranges that are merely adjacent don't overlap.
+    pub fn overlaps(&self, other: &VirtRange) -> bool {
+        overlaps(
+            (self.start.as_u64(), self.size),
+            (other.start.as_u64(), other.size),
+        )
+    }
+
+    /// Every page that contains part of the range, including partially
+    /// covered pages at either end.
+    pub fn pages(&self) -> impl Iterator<Item = Page<Size4KiB>> {
+        let first = Page::containing_address(self.start);
+        self.last()
+            .map(|last| {
+                Page::range_inclusive(first, Page::containing_address(last))
+            })
+            .into_iter()
+            .flatten()
+    }
+}
+
+/// A range of `size` bytes of physical memory starting at `start`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhysRange {
+    start: PhysAddr,
+    size: u64,
+}
+
+impl PhysRange {
+    /// Create a range of `size` bytes starting at `start`.
+    ///
+    /// Panics if the range goes beyond the 52 bits of physical address
+    /// that x86_64 allows. See [Self::try_new].
+    pub fn new(start: PhysAddr, size: u64) -> Self {
+        Self::try_new(start, size).expect("Invalid physical range")
+    }
+
+    /// Like [Self::new], but return `None` instead of panicking.
+    pub fn try_new(start: PhysAddr, size: u64) -> Option<Self> {
+        if size > 0 {
+            let last = start.as_u64().checked_add(size - 1)?;
+            PhysAddr::try_new(last).ok()?;
+        }
+        Some(PhysRange { start, size })
+    }
+
+    pub fn start(&self) -> PhysAddr {
+        self.start
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// The last address in the range, or `None` if it is empty.
+    pub fn last(&self) -> Option<PhysAddr> {
+        match self.size {
+            0 => None,
+            size => Some(self.start + (size - 1)),
+        }
+    }
+
+    pub fn contains(&self, addr: PhysAddr) -> bool {
+        contains(self.start.as_u64(), self.size, addr.as_u64())
+    }
+
+    /// Check whether the ranges have at least one address in common.
+    /// Ranges that are merely adjacent don't overlap.
+    pub fn overlaps(&self, other: &PhysRange) -> bool {
+        overlaps(
+            (self.start.as_u64(), self.size),
+            (other.start.as_u64(), other.size),
+        )
+    }
+
+    /// Every frame that contains part of the range, including partially
+    /// covered frames at either end.
+    pub fn frames(&self) -> impl Iterator<Item = PhysFrame<Size4KiB>> {
+        let first = PhysFrame::containing_address(self.start);
+        self.last()
+            .map(|last| {
+                PhysFrame::range_inclusive(
+                    first,
+                    PhysFrame::containing_address(last),
+                )
+            })
+            .into_iter()
+            .flatten()
+    }
+}
+
+// These work with offsets from the start of a range instead of its end,
+// because the end of a range that reaches the top of the address space
+// doesn't fit in a u64.
+
+fn contains(start: u64, size: u64, addr: u64) -> bool {
+    addr >= start && addr - start < size
+}
+
+fn overlaps(
+    (a_start, a_size): (u64, u64),
+    (b_start, b_size): (u64, u64),
+) -> bool {
+    if a_start <= b_start {
+        b_start - a_start < a_size && b_size > 0
+    }
+    else {
+        a_start - b_start < b_size && a_size > 0
+    }
+}
+
+#[test_case]
+fn test_range_overlaps() {
+    let range = |start, size| VirtRange::new(VirtAddr::new(start), size);
+
+    let a = range(0x1000, 0x1000);
+    // Adjacent on either side.
+    assert!(!a.overlaps(&range(0x2000, 0x1000)));
+    assert!(!a.overlaps(&range(0x0, 0x1000)));
+    // Sharing a single byte.
+    assert!(a.overlaps(&range(0x1fff, 0x1000)));
+    assert!(a.overlaps(&range(0x0, 0x1001)));
+    // Containing each other.
+    assert!(a.overlaps(&range(0x1800, 0x10)));
+    assert!(range(0x1800, 0x10).overlaps(&a));
+    // Empty ranges never overlap, even inside another range.
+    assert!(!a.overlaps(&range(0x1800, 0)));
+    assert!(!range(0x1800, 0).overlaps(&a));
+
+    let b = PhysRange::new(PhysAddr::new(0x1000), 0x1000);
+    assert!(b.contains(PhysAddr::new(0x1fff)));
+    assert!(!b.contains(PhysAddr::new(0x2000)));
+    assert!(!b.overlaps(&PhysRange::new(PhysAddr::new(0x2000), 1)));
+}
+
+#[test_case]
+fn test_range_pages() {
+    let pages =
+        |start, size| VirtRange::new(VirtAddr::new(start), size).pages();
+
+    assert_eq!(pages(0x1000, 0x1000).count(), 1);
+    assert_eq!(pages(0x1000, 0x1001).count(), 2);
+    // Two bytes that straddle a page boundary.
+    let mut straddling = pages(0x1fff, 2);
+    assert_eq!(
+        straddling.next().map(|page| page.start_address()),
+        Some(VirtAddr::new(0x1000))
+    );
+    assert_eq!(
+        straddling.next().map(|page| page.start_address()),
+        Some(VirtAddr::new(0x2000))
+    );
+    assert_eq!(straddling.next(), None);
+    assert_eq!(pages(0x1000, 0).count(), 0);
+
+    let frames = PhysRange::new(PhysAddr::new(0x1800), 0x1000).frames();
+    assert_eq!(frames.count(), 2);
+}
+
+#[test_case]
+fn test_range_limits() {
+    let top = VirtAddr::new(0xffff_ffff_ffff_f000);
+    assert!(VirtRange::try_new(top, 0x1000).is_some());
+    assert!(VirtRange::try_new(top, 0x1001).is_none());
+    // Crosses from the lower half into the non-canonical hole.
+    assert!(
+        VirtRange::try_new(VirtAddr::new(0x7fff_ffff_f000), 0x2000).is_none()
+    );
+    assert!(PhysRange::try_new(PhysAddr::new(0), 1 << 52).is_some());
+    assert!(PhysRange::try_new(PhysAddr::new(1), 1 << 52).is_none());
+}
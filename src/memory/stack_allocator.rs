@@ -0,0 +1,79 @@
+//! Kernel stacks with guard pages
+//!
+//! [allocate_stack] maps a new stack in a region of virtual memory that
+//! is reserved for stacks, with an unmapped guard page below it. Stacks
+//! grow down, so overflowing one writes to the guard page and page
+//! faults right away, instead of silently overwriting whatever happens
+//! to be mapped below.
+//!
+//! Stacks are never freed, and the virtual memory for a stack is not
+//! reused even if mapping it fails.
+
+use super::VirtRange;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, Page, PageSize, PageTableFlags, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+/// Start of the virtual memory that stacks are allocated from. This is
+/// far from the heaps of [crate::allocator], so that stacks never
+/// collide with them.
+pub const STACK_REGION_START: u64 = 0x_6fff_0000_0000;
+
+/// Start of the guard page of the next stack.
+static NEXT_STACK: AtomicU64 = AtomicU64::new(STACK_REGION_START);
+
+/// The mapped part of a stack returned by [allocate_stack].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackBounds {
+    bottom: VirtAddr,
+    top: VirtAddr,
+}
+
+impl StackBounds {
+    /// The lowest address of the stack, right above the guard page.
+    pub fn bottom(&self) -> VirtAddr {
+        self.bottom
+    }
+
+    /// The address right after the end of the stack. This is the
+    /// initial value of the stack pointer, since it is decremented
+    /// before every push.
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    /// The unmapped page right below the stack.
+    pub fn guard_page(&self) -> Page {
+        Page::containing_address(self.bottom - 1u64)
+    }
+}
+
+/// Map a writable stack of `size_pages` pages, with an unmapped guard
+/// page below it.
+///
+/// If mapping fails halfway, the pages that were already mapped stay
+/// mapped.
+pub fn allocate_stack(
+    size_pages: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<StackBounds, MapToError<Size4KiB>> {
+    let page_size = Size4KiB::SIZE;
+    let guard =
+        NEXT_STACK.fetch_add((size_pages + 1) * page_size, Ordering::Relaxed);
+    let bottom = VirtAddr::new(guard + page_size);
+    let stack = VirtRange::new(bottom, size_pages * page_size);
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    for page in stack.pages() {
+        super::create_mapping(page, flags, mapper, frame_allocator)?;
+    }
+
+    Ok(StackBounds {
+        bottom,
+        top: bottom + stack.size(),
+    })
+}
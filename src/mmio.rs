@@ -0,0 +1,122 @@
+//! Memory mapped IO
+//!
+//! Device registers that are mapped into the physical address space
+//! must be accessed with volatile reads and writes, otherwise the
+//! compiler is free to merge, reorder or drop them. [MmioRegion] maps
+//! the registers of a device and hands out [Mmio] handles, which only
+//! ever access them with volatile operations.
+//!
+//! ```ignore
+//! let region = unsafe { MmioRegion::map(PhysAddr::new(0xfee0_0000), 4096) }?;
+//! let mut version = region.at::<u32>(0x30);
+//! let value = version.read();
+//! ```
+
+use crate::memory::{self, IdentityMapError};
+use core::marker::PhantomData;
+use core::mem;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+/// A device register of type `T` at a fixed virtual address.
+pub struct Mmio<T> {
+    addr: VirtAddr,
+    _register: PhantomData<T>,
+}
+
+impl<T: Copy> Mmio<T> {
+    /// Create a handle for the register at `addr`.
+    ///
+    /// This is unsafe because the caller must guarantee that `addr` is
+    /// mapped, suitably aligned for `T` and that reading or writing a
+    /// `T` there is safe for as long as the handle is used. Prefer
+    /// [MmioRegion::at], which checks what it can.
+    pub unsafe fn new(addr: VirtAddr) -> Self {
+        Mmio {
+            addr,
+            _register: PhantomData,
+        }
+    }
+
+    /// The virtual address of the register.
+    pub fn addr(&self) -> VirtAddr {
+        self.addr
+    }
+
+    /// Read the register with a single volatile read.
+    pub fn read(&self) -> T {
+        unsafe { self.addr.as_ptr::<T>().read_volatile() }
+    }
+
+    /// Write the register with a single volatile write.
+    pub fn write(&mut self, value: T) {
+        unsafe { self.addr.as_mut_ptr::<T>().write_volatile(value) }
+    }
+}
+
+/// A physical range of device registers that is mapped to the same
+/// virtual addresses.
+pub struct MmioRegion {
+    start: VirtAddr,
+    size: u64,
+}
+
+impl MmioRegion {
+    /// Map the `size` bytes of physical memory starting at `start` with
+    /// caching disabled, using [memory::identity_map] through
+    /// [memory::with_mapper].
+    ///
+    /// This is unsafe because the caller must guarantee that the range
+    /// belongs to a device, or is otherwise unused, and that it is not
+    /// mapped by anyone else.
+    pub unsafe fn map(
+        start: PhysAddr,
+        size: u64,
+    ) -> Result<Self, IdentityMapError> {
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_CACHE;
+        memory::with_mapper(|mapper, frame_allocator| {
+            memory::identity_map(
+                start,
+                size,
+                flags,
+                false,
+                mapper,
+                frame_allocator,
+            )
+        })?;
+
+        Ok(MmioRegion {
+            start: VirtAddr::new(start.as_u64()),
+            size,
+        })
+    }
+
+    /// The size of the region in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Get a handle for the register of type `T` at `offset` bytes from
+    /// the start of the region.
+    ///
+    /// Panics if the register doesn't fit in the region or if it is not
+    /// aligned for `T`.
+    pub fn at<T: Copy>(&self, offset: u64) -> Mmio<T> {
+        let end = offset.checked_add(mem::size_of::<T>() as u64);
+        assert!(
+            end.map_or(false, |end| end <= self.size),
+            "MMIO offset {:#x} out of bounds",
+            offset
+        );
+        let addr = self.start + offset;
+        assert!(
+            addr.is_aligned(mem::align_of::<T>() as u64),
+            "MMIO address {:#x} misaligned",
+            addr.as_u64()
+        );
+
+        unsafe { Mmio::new(addr) }
+    }
+}
@@ -0,0 +1,111 @@
+//! Output rate limiting
+//!
+//! A buggy loop that prints on every iteration can flood the screen or
+//! the serial log. Both [crate::vga_buffer] and [crate::serial] pass
+//! their output through an [OutputLimiter], which can be configured to
+//! drop everything beyond a number of bytes per timer tick. Limiting is
+//! off by default.
+//!
+//! When output is dropped, a "\[N bytes suppressed\]" line is printed
+//! before the first output of a later tick. That way the total is known
+//! and we print one notice per burst instead of one per message.
+
+use core::fmt;
+
+pub struct OutputLimiter {
+    max_bytes_per_tick: Option<usize>,
+    tick: u64,
+    written: usize,
+    suppressed: usize,
+}
+
+impl OutputLimiter {
+    /// Create an [OutputLimiter] that doesn't limit anything.
+    pub const fn new() -> Self {
+        OutputLimiter {
+            max_bytes_per_tick: None,
+            tick: 0,
+            written: 0,
+            suppressed: 0,
+        }
+    }
+
+    /// Allow at most `max_bytes_per_tick` bytes per timer tick, or any
+    /// amount if `None`.
+    pub fn set_limit(&mut self, max_bytes_per_tick: Option<usize>) {
+        self.max_bytes_per_tick = max_bytes_per_tick;
+    }
+
+    /// Write `args` to `out`, dropping whatever doesn't fit in the
+    /// limit for the current `tick`.
+    pub fn write<W: fmt::Write>(
+        &mut self,
+        out: &mut W,
+        tick: u64,
+        args: fmt::Arguments,
+    ) -> fmt::Result {
+        let max_bytes = match self.max_bytes_per_tick {
+            Some(max_bytes) => max_bytes,
+            None => return out.write_fmt(args),
+        };
+
+        if tick != self.tick {
+            self.tick = tick;
+            self.written = 0;
+            if self.suppressed > 0 {
+                writeln!(out, "[{} bytes suppressed]", self.suppressed)?;
+                self.suppressed = 0;
+            }
+        }
+
+        fmt::write(
+            &mut Limited {
+                out,
+                limiter: self,
+                max_bytes,
+            },
+            args,
+        )
+    }
+}
+
+/// Writes to `out` until the budget of `limiter` is used up, and counts
+/// the rest as suppressed.
+struct Limited<'a, W> {
+    out: &'a mut W,
+    limiter: &'a mut OutputLimiter,
+    max_bytes: usize,
+}
+
+impl<W: fmt::Write> fmt::Write for Limited<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let room = self.max_bytes.saturating_sub(self.limiter.written);
+        let mut len = s.len().min(room);
+        // Don't split characters, we have to write valid str.
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        self.out.write_str(&s[..len])?;
+        self.limiter.written += len;
+        self.limiter.suppressed += s.len() - len;
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_output_limit() {
+    let mut limiter = OutputLimiter::new();
+    let mut out = crate::Capture::<64>::new();
+
+    limiter.set_limit(Some(16));
+    for _ in 0..100 {
+        limiter
+            .write(&mut out, 0, format_args!("{}", "0123456789"))
+            .unwrap();
+    }
+    assert_eq!(out.as_bytes(), b"0123456789012345");
+
+    limiter.write(&mut out, 1, format_args!("x")).unwrap();
+    assert_eq!(&out.as_bytes()[16..], b"[984 bytes suppressed]\nx");
+}
@@ -0,0 +1,144 @@
+//! PCI configuration space
+//!
+//! Every PCI function has 256 bytes of configuration space, which hold
+//! its IDs, its class and the base address registers (BARs) that say
+//! where its own registers are. [scan] finds the functions that are
+//! present by reading it through the legacy configuration mechanism: we
+//! write the address of a dword to [CONFIG_ADDRESS], and then read the
+//! dword from [CONFIG_DATA].
+
+use x86_64::instructions::port::Port;
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+/// Bit of [CONFIG_ADDRESS] that enables the access.
+const CONFIG_ENABLE: u32 = 0x8000_0000;
+
+/// Vendor ID read for functions that don't exist.
+const NO_VENDOR: u16 = 0xffff;
+
+const DEVICES_PER_BUS: u8 = 32;
+const FUNCTIONS_PER_DEVICE: u8 = 8;
+
+/// Bit of the header type that is set if the device has functions
+/// other than 0.
+const MULTI_FUNCTION: u8 = 0x80;
+
+/// Offset of the first BAR in configuration space.
+const BAR_OFFSET: u8 = 0x10;
+
+/// Where a function is on the PCI bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciAddress {
+    pub bus: u8,
+    /// Below 32.
+    pub device: u8,
+    /// Below 8.
+    pub function: u8,
+}
+
+impl PciAddress {
+    /// Read the dword at `offset` of the configuration space of this
+    /// function. The low 2 bits of `offset` are ignored, since the
+    /// access is always aligned.
+    pub fn read_config(&self, offset: u8) -> u32 {
+        let address = CONFIG_ENABLE
+            | u32::from(self.bus) << 16
+            | u32::from(self.device) << 11
+            | u32::from(self.function) << 8
+            | u32::from(offset & 0xfc);
+
+        let mut address_port = Port::<u32>::new(CONFIG_ADDRESS);
+        let mut data_port = Port::<u32>::new(CONFIG_DATA);
+        // The address and data must not be split by another access
+        // from an interrupt handler. Safe because reading configuration
+        // space has no side effects.
+        x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+            address_port.write(address);
+            data_port.read()
+        })
+    }
+}
+
+/// A function found by [scan].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    /// What kind of device it is, eg 0x06 for a bridge.
+    pub class: u8,
+    /// The kind within the class, eg 0x00 for a host bridge.
+    pub subclass: u8,
+    /// The layout of the rest of the configuration space, without the
+    /// [MULTI_FUNCTION] bit.
+    pub header_type: u8,
+    /// Whether the device has functions other than 0. Only meaningful
+    /// for function 0.
+    pub multi_function: bool,
+    /// The raw base address registers. Normal devices have six and
+    /// PCI-to-PCI bridges two, the rest are 0.
+    pub bars: [u32; 6],
+}
+
+impl PciDevice {
+    /// Read the configuration of the function at `address`, or `None`
+    /// if there is no function there.
+    pub fn probe(address: PciAddress) -> Option<Self> {
+        let ids = address.read_config(0);
+        let vendor_id = ids as u16;
+        if vendor_id == NO_VENDOR {
+            return None;
+        }
+
+        let class = address.read_config(0x08);
+        let header_type = (address.read_config(0x0c) >> 16) as u8;
+        let bar_count = match header_type & !MULTI_FUNCTION {
+            0 => 6,
+            1 => 2,
+            _ => 0,
+        };
+        let mut bars = [0; 6];
+        for (i, bar) in bars.iter_mut().take(bar_count).enumerate() {
+            *bar = address.read_config(BAR_OFFSET + 4 * i as u8);
+        }
+
+        Some(PciDevice {
+            address,
+            vendor_id,
+            device_id: (ids >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            header_type: header_type & !MULTI_FUNCTION,
+            multi_function: header_type & MULTI_FUNCTION != 0,
+            bars,
+        })
+    }
+}
+
+/// Find every function on buses 0 to 255. Function 0 of each device is
+/// probed first, and the others only if the device has several.
+///
+/// Nothing is probed until the iterator is advanced, and all 8192
+/// devices are probed by the time it ends, so this takes a while.
+pub fn scan() -> impl Iterator<Item = PciDevice> {
+    (0..=255).flat_map(|bus| {
+        (0..DEVICES_PER_BUS).flat_map(move |device| {
+            let address = |function| PciAddress {
+                bus,
+                device,
+                function,
+            };
+            // Every device has a function 0, which tells us whether
+            // there are others.
+            let functions = match PciDevice::probe(address(0)) {
+                None => 0,
+                Some(first) if first.multi_function => FUNCTIONS_PER_DEVICE,
+                Some(_) => 1,
+            };
+            (0..functions)
+                .filter_map(move |function| PciDevice::probe(address(function)))
+        })
+    })
+}
@@ -0,0 +1,51 @@
+//! Powering off
+//!
+//! ACPI powers off the machine when the S5 sleep type and the sleep
+//! enable bit are written to the PM1a control register. Finding that
+//! register properly means parsing the FADT, which we don't do yet.
+//! Instead the port is configured with [set_acpi_poweroff], or with the
+//! `acpi_pm1a` key of the kernel command line, see [crate::boot_config].
+//! On qemu's default machine it is `0x604`.
+//!
+//! [crate::shutdown] tries this first and falls back to other ways of
+//! stopping.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use x86_64::instructions::port::Port;
+
+/// Bit of the PM1a control register that enters the sleep state.
+const SLP_EN: u16 = 1 << 13;
+
+/// The sleep type is at bits 10 to 12 of PM1a control.
+const SLP_TYP_SHIFT: u16 = 10;
+
+/// The PM1a control port in the lower 16 bits and the S5 sleep type
+/// above them. Zero means that ACPI poweroff is not configured, which is
+/// fine because port zero is the DMA controller, never PM1a.
+static POWEROFF: AtomicU32 = AtomicU32::new(0);
+
+/// Power off through the PM1a control register at `port`, writing the
+/// S5 sleep type `slp_typ`. The sleep type comes from the `\_S5` object
+/// of the DSDT, it is 0 on qemu and Bochs.
+pub fn set_acpi_poweroff(port: u16, slp_typ: u8) {
+    let value = u32::from(slp_typ & 0b111) << 16 | u32::from(port);
+    POWEROFF.store(value, Ordering::SeqCst);
+}
+
+/// Try to power off with ACPI. Returns if it's not configured, or if
+/// the machine is still running after the write, eg because the port
+/// was wrong.
+pub fn acpi_poweroff() {
+    let value = POWEROFF.load(Ordering::SeqCst);
+    if value == 0 {
+        return;
+    }
+    let port = value as u16;
+    let slp_typ = (value >> 16) as u16;
+
+    // Safe because the port was configured as the PM1a control register,
+    // and all we can do by writing it is power off.
+    unsafe {
+        Port::new(port).write(slp_typ << SLP_TYP_SHIFT | SLP_EN);
+    }
+}
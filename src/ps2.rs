@@ -0,0 +1,312 @@
+//! PS/2 controller
+//!
+//! The keyboard is attached to the 8042 PS/2 controller, and the
+//! keyboard interrupt handler reads every scancode from its data port.
+//! If the controller already holds a byte when we boot, eg a key that
+//! was pressed during the bootloader, that byte would be decoded as the
+//! start of the first key press. [flush] discards it. [crate::init]
+//! calls it before the keyboard interrupt is unmasked.
+//!
+//! Commands for the keyboard itself, like [set_leds], are written to
+//! the data port too. The keyboard answers each byte with an ACK, which
+//! arrives in the output buffer like a scancode.
+
+use x86_64::instructions::port::Port;
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+
+/// Bit of the status register that is set while the output buffer
+/// holds a byte for us.
+const OUTPUT_FULL: u8 = 0x01;
+
+/// Bit of the status register that is set while the controller hasn't
+/// consumed the last byte we wrote.
+const INPUT_FULL: u8 = 0x02;
+
+/// Keyboard command to set the LEDs. It's followed by a byte with one
+/// bit per LED.
+const SET_LEDS: u8 = 0xed;
+const LED_SCROLL_LOCK: u8 = 0x01;
+const LED_NUM_LOCK: u8 = 0x02;
+const LED_CAPS_LOCK: u8 = 0x04;
+
+/// Response of the keyboard to a byte it accepted.
+const ACK: u8 = 0xfa;
+/// Response of the keyboard to a byte it wants us to send again.
+const RESEND: u8 = 0xfe;
+
+/// How many times a byte is sent before we give up on it.
+const MAX_ATTEMPTS: usize = 3;
+
+/// How many times we poll the status register while waiting for the
+/// controller, before giving up.
+const MAX_POLLS: usize = 100_000;
+
+/// How many bytes [flush] discards at most. The output buffer holds a
+/// single byte, but a device may keep sending, so don't wait forever.
+const MAX_FLUSH_BYTES: usize = 16;
+
+/// Read and discard bytes from the output buffer of the controller
+/// until it is empty. Returns the number of bytes that were discarded.
+pub fn flush() -> usize {
+    let mut status = Port::<u8>::new(STATUS_PORT);
+    let mut data = Port::<u8>::new(DATA_PORT);
+
+    // Safe because reading these ports only consumes pending output,
+    // which is exactly what we want.
+    drain(|| unsafe { status.read() }, || unsafe { data.read() })
+}
+
+/// Call `read` while `status` reports a full output buffer.
+fn drain(
+    mut status: impl FnMut() -> u8,
+    mut read: impl FnMut() -> u8,
+) -> usize {
+    let mut discarded = 0;
+    while discarded < MAX_FLUSH_BYTES && status() & OUTPUT_FULL != 0 {
+        read();
+        discarded += 1;
+    }
+    discarded
+}
+
+/// Read the byte in the output buffer of the controller, or `None` if
+/// there is none. The keyboard interrupt handler uses this instead of
+/// reading the data port directly, see [set_leds].
+pub fn read_output() -> Option<u8> {
+    let mut ports = Ports::new();
+    if ports.status() & OUTPUT_FULL != 0 {
+        Some(ports.read_data())
+    }
+    else {
+        None
+    }
+}
+
+/// Errors returned when sending a command to the keyboard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    /// The controller didn't accept or answer a byte in time.
+    Timeout,
+    /// The keyboard answered with something other than an ACK, or asked
+    /// us to resend too many times. Holds the last answer.
+    NoAck(u8),
+}
+
+/// The registers of the controller, so that command sequences can be
+/// tested without a real keyboard.
+trait Controller {
+    fn status(&mut self) -> u8;
+    fn read_data(&mut self) -> u8;
+    fn write_data(&mut self, byte: u8);
+}
+
+struct Ports {
+    status: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Ports {
+    fn new() -> Self {
+        Ports {
+            status: Port::new(STATUS_PORT),
+            data: Port::new(DATA_PORT),
+        }
+    }
+}
+
+impl Controller for Ports {
+    fn status(&mut self) -> u8 {
+        unsafe { self.status.read() }
+    }
+
+    fn read_data(&mut self) -> u8 {
+        unsafe { self.data.read() }
+    }
+
+    fn write_data(&mut self, byte: u8) {
+        unsafe { self.data.write(byte) }
+    }
+}
+
+/// Turn the Caps Lock, Num Lock and Scroll Lock LEDs of the keyboard
+/// on or off.
+///
+/// This waits for the keyboard to acknowledge the command. Interrupts
+/// are disabled meanwhile, otherwise the keyboard interrupt handler
+/// would take the ACK as a scancode. The interrupts for the ACKs still
+/// arrive afterwards, but by then [read_output] finds nothing to read.
+pub fn set_leds(caps: bool, num: bool, scroll: bool) -> Result<(), Ps2Error> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        send_leds(&mut Ports::new(), caps, num, scroll)
+    })
+}
+
+fn send_leds(
+    controller: &mut impl Controller,
+    caps: bool,
+    num: bool,
+    scroll: bool,
+) -> Result<(), Ps2Error> {
+    let mut leds = 0;
+    if caps {
+        leds |= LED_CAPS_LOCK;
+    }
+    if num {
+        leds |= LED_NUM_LOCK;
+    }
+    if scroll {
+        leds |= LED_SCROLL_LOCK;
+    }
+
+    send_with_ack(controller, SET_LEDS)?;
+    send_with_ack(controller, leds)
+}
+
+/// Write `byte` to the keyboard and wait for it to be acknowledged,
+/// resending it if the keyboard asks us to.
+fn send_with_ack(
+    controller: &mut impl Controller,
+    byte: u8,
+) -> Result<(), Ps2Error> {
+    let mut response = RESEND;
+    for _ in 0..MAX_ATTEMPTS {
+        wait_for(controller, |status| status & INPUT_FULL == 0)?;
+        controller.write_data(byte);
+        wait_for(controller, |status| status & OUTPUT_FULL != 0)?;
+        response = controller.read_data();
+        if response != RESEND {
+            break;
+        }
+    }
+
+    if response == ACK {
+        Ok(())
+    }
+    else {
+        Err(Ps2Error::NoAck(response))
+    }
+}
+
+/// Poll the status register until `ready` returns true for it.
+fn wait_for(
+    controller: &mut impl Controller,
+    ready: impl Fn(u8) -> bool,
+) -> Result<(), Ps2Error> {
+    for _ in 0..MAX_POLLS {
+        if ready(controller.status()) {
+            return Ok(());
+        }
+    }
+    Err(Ps2Error::Timeout)
+}
+
+/// A stale extended key prefix would turn the first real key press
+/// into garbage. After draining, the key decodes correctly.
+#[test_case]
+fn test_drain_discards_stale_byte() {
+    use crate::keyboard::{KeyDecoder, KeyEvent};
+    use core::cell::Cell;
+
+    let pending = Cell::new(Some(0xe0u8));
+    let discarded = drain(
+        || match pending.get() {
+            Some(_) => OUTPUT_FULL,
+            None => 0,
+        },
+        || pending.take().unwrap(),
+    );
+    assert_eq!(discarded, 1);
+    assert_eq!(pending.get(), None);
+
+    let mut decoder = KeyDecoder::new();
+    assert_eq!(decoder.add_byte(0x1e), Some(KeyEvent::Unicode('a')));
+}
+
+#[test_case]
+fn test_drain_is_bounded() {
+    assert_eq!(drain(|| OUTPUT_FULL, || 0), MAX_FLUSH_BYTES);
+}
+
+/// A keyboard that answers every byte with the next response of a
+/// script and records what it was sent.
+#[cfg(test)]
+struct MockKeyboard {
+    responses: &'static [u8],
+    pending: Option<u8>,
+    sent: [u8; 8],
+    sent_len: usize,
+}
+
+#[cfg(test)]
+impl MockKeyboard {
+    fn new(responses: &'static [u8]) -> Self {
+        MockKeyboard {
+            responses,
+            pending: None,
+            sent: [0; 8],
+            sent_len: 0,
+        }
+    }
+
+    fn sent(&self) -> &[u8] {
+        &self.sent[..self.sent_len]
+    }
+}
+
+#[cfg(test)]
+impl Controller for MockKeyboard {
+    fn status(&mut self) -> u8 {
+        match self.pending {
+            Some(_) => OUTPUT_FULL,
+            None => 0,
+        }
+    }
+
+    fn read_data(&mut self) -> u8 {
+        self.pending.take().unwrap()
+    }
+
+    fn write_data(&mut self, byte: u8) {
+        let (&response, rest) = self.responses.split_first().unwrap();
+        self.responses = rest;
+        self.pending = Some(response);
+        self.sent[self.sent_len] = byte;
+        self.sent_len += 1;
+    }
+}
+
+/// Pressing Caps Lock should send the LED command with the Caps Lock
+/// bit set, in addition to Num Lock which is on initially.
+#[test_case]
+fn test_caps_lock_sets_leds() {
+    use crate::keyboard::KeyDecoder;
+
+    let mut decoder = KeyDecoder::new();
+    decoder.add_byte(0x3a);
+    decoder.add_byte(0xba);
+    let locks = decoder.locks();
+    assert!(locks.caps);
+
+    let mut keyboard = MockKeyboard::new(&[ACK, ACK]);
+    let result = send_leds(&mut keyboard, locks.caps, locks.num, locks.scroll);
+    assert_eq!(result, Ok(()));
+    assert_eq!(keyboard.sent(), &[SET_LEDS, LED_CAPS_LOCK | LED_NUM_LOCK]);
+}
+
+#[test_case]
+fn test_send_with_ack_resends() {
+    let mut keyboard = MockKeyboard::new(&[RESEND, ACK, ACK]);
+    assert_eq!(send_leds(&mut keyboard, false, true, true), Ok(()));
+    assert_eq!(
+        keyboard.sent(),
+        &[SET_LEDS, SET_LEDS, LED_NUM_LOCK | LED_SCROLL_LOCK]
+    );
+
+    let mut keyboard = MockKeyboard::new(&[0x00]);
+    assert_eq!(
+        send_leds(&mut keyboard, false, false, false),
+        Err(Ps2Error::NoAck(0x00))
+    );
+}
@@ -0,0 +1,236 @@
+//! Lock-free ring buffer for interrupt handlers
+//!
+//! Interrupt handlers can't allocate and shouldn't wait for locks, since
+//! whoever holds the lock can't run until the handler returns. A [Ring]
+//! is a fixed size queue with a single producer, usually an interrupt
+//! handler, and a single consumer, usually normal kernel code. Neither
+//! side ever waits for the other: [Ring::push] fails if the ring is
+//! full and [Ring::pop] returns `None` if it is empty.
+//!
+//! The positions of both ends are atomics. The producer is the only one
+//! that moves the tail and the consumer is the only one that moves the
+//! head. If two producers or two consumers overlap anyway, eg because
+//! the handler interrupted normal code that was also pushing, the
+//! second one fails as if the ring was full or empty.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// A single producer, single consumer queue of up to `N` values.
+pub struct Ring<T, const N: usize> {
+    slots: UnsafeCell<MaybeUninit<[T; N]>>,
+    /// Number of values that have been popped. Only [Ring::pop] changes
+    /// it.
+    head: AtomicUsize,
+    /// Number of values that have been pushed. Only [Ring::push]
+    /// changes it.
+    tail: AtomicUsize,
+    pushing: AtomicBool,
+    popping: AtomicBool,
+}
+
+// Safe because values are only moved in by push and out by pop, and
+// the flags ensure that only one of each runs at a time.
+unsafe impl<T: Send, const N: usize> Sync for Ring<T, N> {}
+unsafe impl<T: Send, const N: usize> Send for Ring<T, N> {}
+
+impl<T, const N: usize> Ring<T, N> {
+    /// Create an empty [Ring]. `N` must not be 0.
+    pub const fn new() -> Self {
+        assert!(N > 0, "a ring needs room for at least one value");
+        Ring {
+            slots: UnsafeCell::new(MaybeUninit::uninit()),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            pushing: AtomicBool::new(false),
+            popping: AtomicBool::new(false),
+        }
+    }
+
+    fn slot(&self, position: usize) -> *mut T {
+        let first = self.slots.get() as *mut T;
+        // Safe because the index is in bounds of the array.
+        unsafe { first.add(position % N) }
+    }
+
+    /// Add `value` to the back of the ring. If the ring is full, or
+    /// another push is in progress, `value` is returned.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        if self.pushing.swap(true, Ordering::Acquire) {
+            return Err(value);
+        }
+
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        let result = if tail.wrapping_sub(head) == N {
+            Err(value)
+        }
+        else {
+            // Safe because the slot is not in use: the consumer doesn't
+            // read past the tail and we are the only producer.
+            unsafe { self.slot(tail).write(value) };
+            self.tail.store(tail.wrapping_add(1), Ordering::Release);
+            Ok(())
+        };
+
+        self.pushing.store(false, Ordering::Release);
+        result
+    }
+
+    /// Remove the value at the front of the ring. Returns `None` if the
+    /// ring is empty, or if another pop is in progress.
+    pub fn pop(&self) -> Option<T> {
+        if self.popping.swap(true, Ordering::Acquire) {
+            return None;
+        }
+
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        let value = if head == tail {
+            None
+        }
+        else {
+            // Safe because the producer wrote this slot before moving
+            // the tail past it, and it won't touch it again until we
+            // move the head.
+            let value = unsafe { self.slot(head).read() };
+            self.head.store(head.wrapping_add(1), Ordering::Release);
+            Some(value)
+        };
+
+        self.popping.store(false, Ordering::Release);
+        value
+    }
+
+    /// Get the number of values in the ring. If the other side is
+    /// running concurrently, this may be outdated as soon as it returns.
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+        tail.wrapping_sub(head)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() == N
+    }
+}
+
+impl<T, const N: usize> Drop for Ring<T, N> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+#[test_case]
+fn test_full_and_empty() {
+    let ring = Ring::<u32, 4>::new();
+    assert!(ring.is_empty());
+    assert_eq!(ring.pop(), None);
+
+    for i in 0..4 {
+        assert_eq!(ring.push(i), Ok(()));
+    }
+    assert!(ring.is_full());
+    assert_eq!(ring.push(4), Err(4));
+
+    for i in 0..4 {
+        assert_eq!(ring.pop(), Some(i));
+    }
+    assert_eq!(ring.pop(), None);
+}
+
+/// Pass many more values than fit through a small ring, so that the
+/// positions wrap around the slots many times.
+#[test_case]
+fn test_order_across_wrap_around() {
+    let ring = Ring::<u32, 3>::new();
+    let mut next_in = 0;
+    let mut next_out = 0;
+
+    while next_out < 1000 {
+        // Push a varying number of values, so that the ends meet at
+        // every slot.
+        for _ in 0..next_in % 4 {
+            if ring.push(next_in).is_ok() {
+                next_in += 1;
+            }
+        }
+        if ring.push(next_in).is_ok() {
+            next_in += 1;
+        }
+        while let Some(value) = ring.pop() {
+            assert_eq!(value, next_out);
+            next_out += 1;
+        }
+    }
+}
+
+#[test_case]
+fn test_overlapping_push_fails() {
+    let ring = Ring::<u32, 4>::new();
+
+    // Pretend that we interrupted another push.
+    ring.pushing.store(true, Ordering::SeqCst);
+    assert_eq!(ring.push(1), Err(1));
+    ring.pushing.store(false, Ordering::SeqCst);
+
+    assert_eq!(ring.push(1), Ok(()));
+    ring.popping.store(true, Ordering::SeqCst);
+    assert_eq!(ring.pop(), None);
+    ring.popping.store(false, Ordering::SeqCst);
+    assert_eq!(ring.pop(), Some(1));
+}
+
+#[test_case]
+fn test_drop_remaining_values() {
+    use crate::DropFlag;
+
+    let flag = DropFlag::new();
+    {
+        let ring = Ring::<_, 2>::new();
+        assert!(ring.push(flag.guard()).is_ok());
+    }
+    assert!(flag.dropped());
+}
+
+/// Push from an interrupt handler and pop in normal code, like the
+/// keyboard does.
+#[test_case]
+fn test_push_from_interrupt_handler() {
+    use x86_64::instructions::interrupts::int3;
+    use x86_64::structures::idt::InterruptStackFrame;
+
+    static RING: Ring<u64, 8> = Ring::new();
+    static PUSHED: AtomicUsize = AtomicUsize::new(0);
+
+    extern "x86-interrupt" fn producer(_stack_frame: InterruptStackFrame) {
+        let value = PUSHED.load(Ordering::SeqCst) as u64;
+        if RING.push(value).is_ok() {
+            PUSHED.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    crate::with_custom_idt(
+        |idt| {
+            idt.breakpoint.set_handler_fn(producer);
+        },
+        || {
+            let mut expected = 0;
+            for round in 0..20 {
+                for _ in 0..round % 10 {
+                    int3();
+                }
+                while let Some(value) = RING.pop() {
+                    assert_eq!(value, expected);
+                    expected += 1;
+                }
+            }
+            assert_eq!(expected as usize, PUSHED.load(Ordering::SeqCst));
+        },
+    );
+}
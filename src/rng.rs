@@ -0,0 +1,85 @@
+//! Pseudo-random numbers
+//!
+//! [Rng] is a small xorshift64* generator. It is fast and good enough
+//! for randomized tests, eg allocation patterns for the allocators, but
+//! it is predictable, so don't use it for anything security related.
+//!
+//! Tests should use [Rng::new] with a fixed seed, so that a failure can
+//! be reproduced. [Rng::from_tsc] gives a different sequence on every
+//! boot.
+
+/// Replaces a seed of 0, which would make xorshift return 0 forever.
+const ZERO_SEED_REPLACEMENT: u64 = 0x_9e37_79b9_7f4a_7c15;
+
+/// A seedable pseudo-random number generator. The same seed always
+/// gives the same sequence.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// Create a generator with the given `seed`. Any value works,
+    /// including 0.
+    pub const fn new(seed: u64) -> Self {
+        let state = match seed {
+            0 => ZERO_SEED_REPLACEMENT,
+            seed => seed,
+        };
+        Rng { state }
+    }
+
+    /// Create a generator seeded from the time stamp counter, for when
+    /// the numbers don't have to be reproducible.
+    pub fn from_tsc() -> Self {
+        Self::new(crate::cpu::rdtsc())
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x_2545_f491_4f6c_dd1d)
+    }
+
+    /// Get a number in `lo..hi`.
+    ///
+    /// Panics if the range is empty, ie `lo >= hi`.
+    pub fn gen_range(&mut self, lo: u64, hi: u64) -> u64 {
+        assert!(lo < hi, "Empty range {}..{}", lo, hi);
+        // Scale to the size of the range with a widening multiply. This
+        // is less biased than the remainder and doesn't divide.
+        let range = u128::from(hi - lo);
+        let scaled = (u128::from(self.next_u64()) * range) >> 64;
+        lo + scaled as u64
+    }
+}
+
+#[test_case]
+fn test_same_seed_same_sequence() {
+    let mut first = Rng::new(42);
+    let mut second = Rng::new(42);
+    for _ in 0..100 {
+        assert_eq!(first.next_u64(), second.next_u64());
+    }
+
+    let mut other = Rng::new(43);
+    assert_ne!(Rng::new(42).next_u64(), other.next_u64());
+    assert_ne!(Rng::new(0).next_u64(), 0);
+}
+
+#[test_case]
+fn test_gen_range() {
+    let mut rng = Rng::new(7);
+    let mut seen = [false; 10];
+    for _ in 0..1000 {
+        let value = rng.gen_range(10, 20);
+        assert!((10..20).contains(&value), "{} out of range", value);
+        seen[(value - 10) as usize] = true;
+    }
+    assert!(seen.iter().all(|&seen| seen));
+
+    assert_eq!(rng.gen_range(5, 6), 5);
+    let value = rng.gen_range(0, u64::MAX);
+    assert!(value < u64::MAX);
+}
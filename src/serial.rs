@@ -5,14 +5,30 @@
 //! [crate::serial_print] and [crate::serial_println] to print messages
 //! on the host. But you could use it on another serial device if you
 //! want.
+//!
+//! To check what was printed from inside the kernel, eg in a test, call
+//! [redirect_to_buffer]. Output of the print macros then goes to memory
+//! instead of the port until [stop_redirect], and [captured] returns it.
+//!
+//! The host can ask for a snapshot of the kernel's state by sending
+//! [DUMP_REQUEST] (Ctrl-D). The serial interrupt handler answers with
+//! [crate::dump_diagnostics] and the kernel carries on afterwards.
 
+use crate::log_buffer::LogBuffer;
+use crate::output_limit::OutputLimiter;
+use crate::vga_buffer::{CapturedOutput, CAPTURE_SIZE, TRUNCATED_MARKER};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use uart_16550::SerialPort;
 
+/// IO port base of the first serial port.
+const SERIAL1_PORT: u16 = 0x3F8;
+
 lazy_static! {
     pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+        let mut serial_port = unsafe { SerialPort::new(SERIAL1_PORT) };
         serial_port.init();
         Mutex::new(serial_port)
     };
@@ -39,14 +55,573 @@ macro_rules! serial_println {
 // has to be since the macros use it. Let's at least hide it in the
 // docs.
 #[doc(hidden)]
-pub fn _print(args: ::core::fmt::Arguments) {
+pub fn _print(args: fmt::Arguments) {
+    try_write_fmt(args).expect("Printing to serial failed");
+}
+
+/// Print `args` like [crate::serial_print] does, but return the result
+/// instead of panicking. An error means that either the serial port or
+/// one of the formatted values failed.
+pub fn try_write_fmt(args: fmt::Arguments) -> fmt::Result {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut redirect = REDIRECT.lock();
+        if redirect.active {
+            return fmt::write(&mut redirect.buffer, args);
+        }
+        drop(redirect);
+
+        let mut serial = SERIAL1.lock();
+        OUTPUT_LIMITER.lock().write(
+            &mut FlowControlled(&mut *serial),
+            crate::interrupts::ticks(),
+            args,
+        )
+    })
+}
+
+/// Write `value` to serial in decimal, padded to `width` bytes with
+/// `pad`. See [crate::sink::write_u64_padded]. Unlike the print macros,
+/// this bypasses the output limit.
+pub fn write_u64_padded(value: u64, width: usize, pad: u8) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut serial = SERIAL1.lock();
+        crate::sink::write_u64_padded(&mut *serial, value, width, pad);
+    });
+}
+
+/// Like [write_u64_padded], but in hexadecimal. See
+/// [crate::sink::write_u64_hex_padded].
+pub fn write_u64_hex_padded(value: u64, width: usize, pad: u8) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut serial = SERIAL1.lock();
+        crate::sink::write_u64_hex_padded(&mut *serial, value, width, pad);
+    });
+}
+
+/// Where [try_write_fmt] writes instead of the port while redirected.
+struct Redirect {
+    active: bool,
+    buffer: LogBuffer<CAPTURE_SIZE>,
+}
+
+static REDIRECT: Mutex<Redirect> = Mutex::new(Redirect {
+    active: false,
+    buffer: LogBuffer::new(),
+});
+
+/// Send the output of [crate::serial_print] and [crate::serial_println]
+/// to a buffer in memory instead of the port, until [stop_redirect].
+/// Anything that was captured before is discarded. Only the last
+/// [CAPTURE_SIZE] bytes are kept.
+///
+/// Output that doesn't go through the print macros, like [send_byte]
+/// and the diagnostics of interrupt handlers, still goes to the port.
+/// So does the output of the test runner, as long as the test stops
+/// redirecting before it ends.
+pub fn redirect_to_buffer() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut redirect = REDIRECT.lock();
+        redirect.active = true;
+        redirect.buffer = LogBuffer::new();
+    });
+}
+
+/// Go back to printing to the port. What was captured is kept until the
+/// next [redirect_to_buffer].
+pub fn stop_redirect() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| REDIRECT.lock().active = false);
+}
+
+/// Get a copy of what was printed since [redirect_to_buffer].
+pub fn captured() -> CapturedOutput {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        CapturedOutput::from_log(&REDIRECT.lock().buffer)
+    })
+}
+
+static OUTPUT_LIMITER: Mutex<OutputLimiter> = Mutex::new(OutputLimiter::new());
+
+/// Drop output of [crate::serial_print] and [crate::serial_println]
+/// beyond `max_bytes_per_tick` bytes per timer tick, or remove the
+/// limit with `None`. See [crate::output_limit] for details.
+pub fn set_output_limit(max_bytes_per_tick: Option<usize>) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        OUTPUT_LIMITER.lock().set_limit(max_bytes_per_tick);
+    });
+}
+
+/// Release the locks that [crate::serial_print] takes, even if someone
+/// else holds them.
+///
+/// This is unsafe because whoever held them will keep using the port
+/// and the buffers behind them. Only use it when that code is never
+/// going to resume, eg when panicking.
+pub unsafe fn force_unlock() {
+    SERIAL1.force_unlock();
+    REDIRECT.force_unlock();
+    OUTPUT_LIMITER.force_unlock();
+}
+
+/// Send `s` and a newline to the host through a port that is
+/// initialized on the spot, for [crate::early_print]. This doesn't use
+/// [struct@SERIAL1], so it works before anything is set up, but nothing
+/// stops it from interleaving with other output.
+pub(crate) fn early_write(s: &str) {
+    // Safe because this is the port SERIAL1 uses, and initializing it
+    // again only resets the settings to the same values.
+    let mut serial = unsafe { SerialPort::new(SERIAL1_PORT) };
+    serial.init();
+    for byte in s.bytes().chain(Some(b'\n')) {
+        serial.send(byte);
+    }
+}
+
+/// Send a single byte to the host as is, without any formatting. Unlike
+/// [crate::serial_print], this doesn't require valid UTF-8.
+pub fn send_byte(byte: u8) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        FlowControlled(&mut *SERIAL1.lock()).send(byte);
+    });
+}
+
+/// Bit of the modem control register that makes the UART receive what
+/// it sends instead of putting it on the line.
+const MCR_LOOPBACK: u8 = 0x10;
+
+/// Whether [crate::init] runs [loopback_test], see [set_test_on_init].
+static TEST_ON_INIT: AtomicBool = AtomicBool::new(false);
+
+/// Check that [struct@SERIAL1] works, by putting the UART in loopback
+/// mode, sending a byte and checking that the same byte is received.
+/// The modem control register is restored afterwards. Returns `false`
+/// if the byte doesn't come back, eg because there is no UART at all.
+///
+/// Any bytes that were received from the host and not read yet are
+/// discarded.
+pub fn loopback_test() -> bool {
+    /// Arbitrary, but not all zeros or ones like a missing device.
+    const TEST_BYTE: u8 = 0xae;
+
+    with_loopback(|serial| {
+        while serial.try_receive().is_some() {}
+        serial.send(TEST_BYTE);
+        let received =
+            (0..MAX_LOOPBACK_POLLS).find_map(|_| serial.try_receive());
+        received == Some(TEST_BYTE)
+    })
+}
+
+/// Make [struct@SERIAL1] receive `byte` as if the host had sent it, by
+/// sending it in loopback mode. The byte is left for the serial
+/// interrupt handler, which runs as soon as interrupts are enabled.
+/// This is meant for testing how we react to input, eg to
+/// [DUMP_REQUEST]. Returns `false` if the byte never arrives.
+pub fn receive_from_self(byte: u8) -> bool {
+    with_loopback(|serial| {
+        serial.send(byte);
+        (0..MAX_LOOPBACK_POLLS).any(|_| data_ready())
+    })
+}
+
+/// How many times to poll for a byte sent in loopback mode before
+/// giving up.
+const MAX_LOOPBACK_POLLS: usize = 100_000;
+
+/// Call `f` with [struct@SERIAL1] locked and in loopback mode, and
+/// interrupts disabled. The modem control register is restored after.
+fn with_loopback<R>(f: impl FnOnce(&mut SerialPort) -> R) -> R {
+    use x86_64::instructions::interrupts;
+    use x86_64::instructions::port::Port;
+
+    interrupts::without_interrupts(|| {
+        let mut serial = SERIAL1.lock();
+        let mut modem_control = Port::<u8>::new(SERIAL1_PORT + 4);
+
+        // Safe because we hold the lock, so nobody else uses the port
+        // until the modem control register is restored.
+        let saved = unsafe { modem_control.read() };
+        unsafe { modem_control.write(saved | MCR_LOOPBACK) };
+        let result = f(&mut serial);
+        unsafe { modem_control.write(saved) };
+        result
+    })
+}
+
+/// Make [crate::init] run [loopback_test] and print a warning on screen
+/// if it fails. This is off by default, so it must be enabled before
+/// calling [crate::init].
+pub fn set_test_on_init(enabled: bool) {
+    TEST_ON_INIT.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether [set_test_on_init] enabled the test.
+pub(crate) fn test_on_init() -> bool {
+    TEST_ON_INIT.load(Ordering::Relaxed)
+}
+
+/// Print `value` with its [fmt::Debug] implementation, followed by a
+/// newline, but at most `max_bytes` of it. If the output is longer, it
+/// is cut off and [TRUNCATED_MARKER] is appended. This is meant for large
+/// structures like page tables or the memory map, which would take a
+/// long time to print in full and flood the log.
+///
+/// Formatting stops as soon as `max_bytes` have been written, so the
+/// rest of `value` costs nothing.
+pub fn debug_truncated<T: fmt::Debug>(value: &T, max_bytes: usize) {
+    crate::serial_println!("{}", Truncated { value, max_bytes });
+}
+
+/// Displays the [fmt::Debug] output of `value`, truncated to
+/// `max_bytes`. See [debug_truncated].
+struct Truncated<'a, T> {
+    value: &'a T,
+    max_bytes: usize,
+}
+
+impl<T: fmt::Debug> fmt::Display for Truncated<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use core::fmt::Write;
+
+        let mut bounded = Bounded {
+            out: f,
+            room: self.max_bytes,
+            overflowed: false,
+        };
+        match write!(bounded, "{:?}", self.value) {
+            // We return an error from Bounded to stop formatting once
+            // it's full, that's not a real failure.
+            Err(_) if bounded.overflowed => f.write_str(TRUNCATED_MARKER),
+            result => result,
+        }
+    }
+}
+
+/// Passes at most `room` bytes on to `out`. Fails once it's full so
+/// that formatting stops early.
+struct Bounded<'a, W> {
+    out: &'a mut W,
+    room: usize,
+    overflowed: bool,
+}
+
+impl<W: fmt::Write> fmt::Write for Bounded<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if s.len() <= self.room {
+            self.room -= s.len();
+            return self.out.write_str(s);
+        }
+
+        let mut len = self.room;
+        // Don't split characters, we have to write valid str.
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.out.write_str(&s[..len])?;
+        self.room = 0;
+        self.overflowed = true;
+        Err(fmt::Error)
+    }
+}
+
+/// Byte the host sends to ask us to pause.
+const XOFF: u8 = 0x13;
+/// Byte the host sends to let us resume after [XOFF].
+const XON: u8 = 0x11;
+
+/// Whether [set_flow_control] enabled flow control.
+static FLOW_CONTROL: AtomicBool = AtomicBool::new(false);
+
+/// Whether we have received [XOFF] and not [XON] since.
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Byte the host sends to ask for [crate::dump_diagnostics]. This is
+/// what Ctrl-D sends in most terminals.
+pub const DUMP_REQUEST: u8 = 0x04;
+
+/// Whether we have received [DUMP_REQUEST] and not answered it yet.
+static DUMP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Number of times we have answered [DUMP_REQUEST], see [dumps].
+static DUMPS: AtomicU64 = AtomicU64::new(0);
+
+/// Enable or disable software flow control, which is off by default.
+/// While enabled, [crate::serial_print], [crate::serial_println] and
+/// [send_byte] check for XOFF (`0x13`) from the host before every byte,
+/// and wait for XON (`0x11`) after receiving one. That way a host that
+/// can't keep up with bulk output doesn't lose any of it.
+///
+/// Other bytes received while checking are discarded, except for
+/// [DUMP_REQUEST]. Note that a host that sends XOFF and never XON stops
+/// the kernel the next time it prints.
+pub fn set_flow_control(enabled: bool) {
+    FLOW_CONTROL.store(enabled, Ordering::Relaxed);
+    if !enabled {
+        PAUSED.store(false, Ordering::Relaxed);
+    }
+}
+
+/// The parts of a UART that flow control needs.
+trait Uart {
+    /// Get a received byte, if there is one.
+    fn try_receive(&mut self) -> Option<u8>;
+    fn send(&mut self, byte: u8);
+}
+
+impl Uart for SerialPort {
+    fn try_receive(&mut self) -> Option<u8> {
+        use x86_64::instructions::port::Port;
+
+        if !data_ready() {
+            return None;
+        }
+        // Safe because the caller has a `&mut SerialPort`, so nobody
+        // else is using the port, and reading the data register has no
+        // effect other than consuming the received byte.
+        Some(unsafe { Port::<u8>::new(SERIAL1_PORT).read() })
+    }
+
+    fn send(&mut self, byte: u8) {
+        SerialPort::send(self, byte);
+    }
+}
+
+/// Process the bytes received by `uart`, and update `paused` for every
+/// [XOFF] or [XON]. A [DUMP_REQUEST] is remembered for
+/// [handle_interrupt].
+fn poll_flow_control(uart: &mut impl Uart, paused: &mut bool) {
+    while let Some(byte) = uart.try_receive() {
+        match byte {
+            XOFF => *paused = true,
+            XON => *paused = false,
+            DUMP_REQUEST => DUMP_REQUESTED.store(true, Ordering::Relaxed),
+            _ => {}
+        }
+    }
+}
+
+/// Check whether the UART has received a byte that hasn't been read.
+fn data_ready() -> bool {
+    use x86_64::instructions::port::Port;
+
+    /// Bit of the line status register that is set when a byte has been
+    /// received.
+    const DATA_READY: u8 = 0x01;
+
+    // Safe because reading the line status register has no side
+    // effects.
+    let line_status = unsafe { Port::<u8>::new(SERIAL1_PORT + 5).read() };
+    line_status & DATA_READY != 0
+}
+
+/// Handle the bytes received from the host. The serial interrupt
+/// handler calls this. [XOFF] and [XON] update the flow control state
+/// if it is enabled, and [DUMP_REQUEST] prints
+/// [crate::dump_diagnostics]. Anything else is discarded.
+///
+/// If [struct@SERIAL1] is locked, the bytes are left to whoever holds
+/// it. A dump request they receive is answered the next time this runs.
+pub(crate) fn handle_interrupt() {
+    if let Some(mut serial) = SERIAL1.try_lock() {
+        let mut paused = PAUSED.load(Ordering::Relaxed);
+        poll_flow_control(&mut *serial, &mut paused);
+        if FLOW_CONTROL.load(Ordering::Relaxed) {
+            PAUSED.store(paused, Ordering::Relaxed);
+        }
+    }
+
+    if DUMP_REQUESTED.swap(false, Ordering::Relaxed) {
+        crate::dump_diagnostics();
+        DUMPS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Get the number of times the kernel has answered [DUMP_REQUEST].
+pub fn dumps() -> u64 {
+    DUMPS.load(Ordering::Relaxed)
+}
+
+/// Send `byte` over `uart`, first waiting for as long as the host has
+/// asked us to pause.
+fn send_flow_controlled(uart: &mut impl Uart, paused: &mut bool, byte: u8) {
+    poll_flow_control(uart, paused);
+    while *paused {
+        core::hint::spin_loop();
+        poll_flow_control(uart, paused);
+    }
+    uart.send(byte);
+}
+
+/// Writes to the wrapped port, honoring flow control if it is enabled.
+struct FlowControlled<'a>(&'a mut SerialPort);
+
+impl FlowControlled<'_> {
+    fn send(&mut self, byte: u8) {
+        if !FLOW_CONTROL.load(Ordering::Relaxed) {
+            return self.0.send(byte);
+        }
+        // Only accessed with SERIAL1 locked, so nobody else changes it
+        // in the meantime.
+        let mut paused = PAUSED.load(Ordering::Relaxed);
+        send_flow_controlled(&mut *self.0, &mut paused, byte);
+        PAUSED.store(paused, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Write for FlowControlled<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+/// Print `args` without taking the lock of [struct@SERIAL1]. This is
+/// for diagnostics from the serial interrupt handler, which may have
+/// interrupted code that holds the lock, so waiting for it would
+/// deadlock. Use [crate::serial_print] everywhere else.
+///
+/// The bytes go straight to the port, so they may end up in the middle
+/// of the output of whoever holds the lock. No output limit applies.
+#[doc(hidden)]
+pub fn _irq_print(args: fmt::Arguments) -> fmt::Result {
     use core::fmt::Write;
+
+    // Safe because SERIAL1 has already initialized the port, and
+    // sending a byte doesn't depend on any state that the lock holder
+    // could be in the middle of changing.
+    let mut serial = unsafe { SerialPort::new(SERIAL1_PORT) };
+    serial.write_fmt(args)
+}
+
+#[test_case]
+fn test_redirect_to_buffer() {
+    redirect_to_buffer();
+    crate::serial_println!("redirected {}", 42);
+    stop_redirect();
+
+    assert_eq!(captured().as_str(), Some("redirected 42\n"));
+}
+
+#[test_case]
+fn test_loopback_test() {
+    use x86_64::instructions::port::Port;
+
+    let mut modem_control = Port::<u8>::new(SERIAL1_PORT + 4);
+    let before = unsafe { modem_control.read() };
+    assert!(loopback_test());
+    let after = unsafe { modem_control.read() };
+    assert_eq!(after, before);
+    assert_eq!(after & MCR_LOOPBACK, 0);
+}
+
+#[test_case]
+fn test_irq_print_while_locked() {
     use x86_64::instructions::interrupts;
 
     interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("Printing to serial failed");
+        let _serial = SERIAL1.lock();
+        assert_eq!(_irq_print(format_args!("!")), Ok(()));
     });
 }
+
+/// A UART that receives the bytes of a script, one per poll, and
+/// records what is sent, for testing flow control.
+#[cfg(test)]
+struct MockUart {
+    incoming: &'static [Option<u8>],
+    polls: usize,
+    sent: [u8; 8],
+    sent_len: usize,
+    /// Number of bytes that had been sent when XON was received.
+    sent_before_xon: Option<usize>,
+}
+
+#[cfg(test)]
+impl Uart for MockUart {
+    fn try_receive(&mut self) -> Option<u8> {
+        let byte = self.incoming.get(self.polls).copied().flatten();
+        self.polls += 1;
+        if byte == Some(XON) {
+            self.sent_before_xon = Some(self.sent_len);
+        }
+        byte
+    }
+
+    fn send(&mut self, byte: u8) {
+        self.sent[self.sent_len] = byte;
+        self.sent_len += 1;
+    }
+}
+
+#[test_case]
+fn test_flow_control_pauses_and_resumes() {
+    let mut uart = MockUart {
+        incoming: &[None, Some(XOFF), None, None, None, Some(XON), None],
+        polls: 0,
+        sent: [0; 8],
+        sent_len: 0,
+        sent_before_xon: None,
+    };
+    let mut paused = false;
+
+    for &byte in b"ab" {
+        send_flow_controlled(&mut uart, &mut paused, byte);
+    }
+
+    // Nothing was sent between XOFF and XON, but we kept polling.
+    assert_eq!(uart.sent_before_xon, Some(1));
+    assert_eq!(uart.polls, 7);
+    assert_eq!(&uart.sent[..uart.sent_len], b"ab");
+    assert!(!paused);
+}
+
+#[test_case]
+fn test_try_write_fmt_propagates_errors() {
+    assert_eq!(try_write_fmt(format_args!("")), Ok(()));
+    assert_eq!(
+        try_write_fmt(format_args!("{}", crate::FailingDisplay)),
+        Err(fmt::Error)
+    );
+}
+
+#[test_case]
+fn test_debug_truncated() {
+    use core::fmt::Write;
+
+    let mut out = crate::Capture::<64>::new();
+    let large = [0u8; 1000];
+    let truncated = Truncated {
+        value: &large,
+        max_bytes: 10,
+    };
+    write!(out, "{}", truncated).unwrap();
+    assert_eq!(out.as_bytes(), b"[0, 0, 0, ...(truncated)");
+
+    let mut out = crate::Capture::<64>::new();
+    let small = Truncated {
+        value: &[1, 2],
+        max_bytes: 10,
+    };
+    write!(out, "{}", small).unwrap();
+    assert_eq!(out.as_bytes(), b"[1, 2]");
+
+    // Just check that it doesn't fail.
+    debug_truncated(&large, 16);
+}
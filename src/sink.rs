@@ -0,0 +1,184 @@
+//! Output-agnostic byte sinks
+//!
+//! Code that doesn't care where its output ends up can write to a
+//! [Sink] instead of a specific device. Both the VGA [Writer] and the
+//! serial port implement it, and [MultiSink] forwards the same bytes to
+//! several sinks at once, eg to mirror the screen to the host. Use
+//! [write_fmt] with `format_args!` for formatted output, or
+//! [write_u64_padded] and [write_u64_hex_padded] for fixed width
+//! numbers, eg for tables.
+
+use crate::vga_buffer::Writer;
+use core::fmt;
+use uart_16550::SerialPort;
+
+/// Something that accepts bytes of output.
+pub trait Sink {
+    /// Write all `bytes` to the sink. Sinks handle bytes they can't
+    /// display themselves, eg [Writer] replaces them with placeholders.
+    fn write_bytes(&mut self, bytes: &[u8]);
+}
+
+impl Sink for Writer {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_text(bytes);
+    }
+}
+
+impl Sink for SerialPort {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.send(byte);
+        }
+    }
+}
+
+/// A [Sink] that writes everything to each of the sinks it wraps, in
+/// order.
+pub struct MultiSink<'a, 'b> {
+    sinks: &'a mut [&'b mut dyn Sink],
+}
+
+impl<'a, 'b> MultiSink<'a, 'b> {
+    pub fn new(sinks: &'a mut [&'b mut dyn Sink]) -> Self {
+        MultiSink { sinks }
+    }
+}
+
+impl Sink for MultiSink<'_, '_> {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        for sink in self.sinks.iter_mut() {
+            sink.write_bytes(bytes);
+        }
+    }
+}
+
+/// Adapter that lets us use `core::fmt` machinery with any [Sink].
+struct FmtAdapter<'a>(&'a mut dyn Sink);
+
+impl fmt::Write for FmtAdapter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Write formatted output to `sink`, eg
+/// `sink::write_fmt(&mut sink, format_args!("{}", 42))`.
+pub fn write_fmt(sink: &mut dyn Sink, args: fmt::Arguments) -> fmt::Result {
+    fmt::write(&mut FmtAdapter(sink), args)
+}
+
+/// Write `value` in decimal, right-justified to `width` bytes by
+/// prepending `pad`. Numbers that are wider are written in full.
+///
+/// This formats into a buffer on the stack, so it is cheaper than
+/// padding with `format_args!` and doesn't need the heap.
+pub fn write_u64_padded(
+    sink: &mut dyn Sink,
+    value: u64,
+    width: usize,
+    pad: u8,
+) {
+    write_u64_radix(sink, value, 10, width, pad);
+}
+
+/// Like [write_u64_padded], but in lowercase hexadecimal without a
+/// `0x` prefix.
+pub fn write_u64_hex_padded(
+    sink: &mut dyn Sink,
+    value: u64,
+    width: usize,
+    pad: u8,
+) {
+    write_u64_radix(sink, value, 16, width, pad);
+}
+
+fn write_u64_radix(
+    sink: &mut dyn Sink,
+    mut value: u64,
+    radix: u64,
+    width: usize,
+    pad: u8,
+) {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+    // Enough for u64::MAX in decimal.
+    let mut digits = [0; 20];
+    let mut start = digits.len();
+    loop {
+        start -= 1;
+        digits[start] = DIGITS[(value % radix) as usize];
+        value /= radix;
+        if value == 0 {
+            break;
+        }
+    }
+
+    for _ in digits.len() - start..width {
+        sink.write_bytes(&[pad]);
+    }
+    sink.write_bytes(&digits[start..]);
+}
+
+/// A sink that stores whatever is written to it, for testing.
+#[cfg(test)]
+struct CaptureSink {
+    bytes: [u8; 32],
+    len: usize,
+}
+
+#[cfg(test)]
+impl CaptureSink {
+    fn new() -> Self {
+        CaptureSink {
+            bytes: [0; 32],
+            len: 0,
+        }
+    }
+
+    fn captured(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+#[cfg(test)]
+impl Sink for CaptureSink {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        let end = self.len + bytes.len();
+        self.bytes[self.len..end].copy_from_slice(bytes);
+        self.len = end;
+    }
+}
+
+#[test_case]
+fn test_multi_sink() {
+    let mut first = CaptureSink::new();
+    let mut second = CaptureSink::new();
+
+    {
+        let mut sinks: [&mut dyn Sink; 2] = [&mut first, &mut second];
+        let mut multi = MultiSink::new(&mut sinks);
+        multi.write_bytes(b"hello ");
+        write_fmt(&mut multi, format_args!("{}", 42)).unwrap();
+    }
+
+    assert_eq!(first.captured(), b"hello 42");
+    assert_eq!(second.captured(), b"hello 42");
+}
+
+#[test_case]
+fn test_write_u64_padded() {
+    let mut sink = CaptureSink::new();
+    write_u64_padded(&mut sink, 42, 6, b' ');
+    assert_eq!(sink.captured(), b"    42");
+
+    let mut sink = CaptureSink::new();
+    write_u64_hex_padded(&mut sink, 0xbeef, 8, b'0');
+    assert_eq!(sink.captured(), b"0000beef");
+
+    let mut sink = CaptureSink::new();
+    write_u64_padded(&mut sink, u64::MAX, 4, b' ');
+    write_u64_hex_padded(&mut sink, 0, 0, b'0');
+    assert_eq!(sink.captured(), b"184467440737095516150");
+}
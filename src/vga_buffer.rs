@@ -19,14 +19,28 @@
 //! [struct@WRITER] already holds a mutable reference to the VGA buffer
 //! (`0xb8000`), so don't create another [Writer] instance for the same
 //! buffer!
+//!
+//! To check what was printed, eg in a test, wrap the code that prints
+//! with [start_capture] and [stop_capture]. To look at what is on
+//! screen, use [Writer::with_snapshot].
+//!
+//! Rows that scroll off the top of the screen are kept, with their
+//! colors, in a scrollback of [SCROLLBACK_LINES] lines. Use
+//! [Writer::scroll_up] and [Writer::scroll_down] to page through it.
 
+use crate::log_buffer::LogBuffer;
+use crate::output_limit::OutputLimiter;
+use alloc::vec::Vec;
 use core::fmt;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use volatile::Volatile;
 
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+/// Size of the screen in text mode, in rows and columns.
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
 
 /// Print to the vga buffer, similar to how `std::fmt::print` would
 /// behave in a terminal if it were available to us.
@@ -44,19 +58,222 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+/// Like [print], but never waits for the lock of [struct@WRITER] and
+/// never panics. Evaluates to `true` if the text was printed, or
+/// `false` if the writer was locked or formatting failed.
+///
+/// This is meant for places where blocking or panicking would make
+/// things worse, eg exception handlers. To keep it that way, the output
+/// is not copied to [crate::log_buffer] or rate limited, as those would
+/// need more locks.
+#[macro_export]
+macro_rules! try_print {
+    ($($arg:tt)*) => ($crate::vga_buffer::_try_print(format_args!($($arg)*)));
+}
+
+/// Like [println], but with the guarantees of [try_print].
+#[macro_export]
+macro_rules! try_println {
+    () => ($crate::try_print!("\n"));
+    ($($arg:tt)*) => ($crate::try_print!("{}\n", format_args!($($arg)*)));
+}
+
 // This is not really intended to be a part of the public API, but it
 // has to be since the macros use it. Let's at least hide it in the
 // docs.
 #[doc(hidden)]
-pub fn _print(args: fmt::Arguments) {
+pub fn _try_print(args: fmt::Arguments) -> bool {
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
 
+    interrupts::without_interrupts(|| match WRITER.try_lock() {
+        Some(mut writer) => writer.write_fmt(args).is_ok(),
+        None => false,
+    })
+}
+
+// This is not really intended to be a part of the public API, but it
+// has to be since the macros use it. Let's at least hide it in the
+// docs.
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    try_write_fmt(args).unwrap();
+}
+
+/// Print `args` like [print] does, but return the result instead of
+/// panicking. Our [Writer] never fails, so an error means that one of
+/// the formatted values failed, eg a `Display` impl returned `Err`.
+pub fn try_write_fmt(args: fmt::Arguments) -> fmt::Result {
+    use x86_64::instructions::interrupts;
+
+    let result = interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        OUTPUT_LIMITER.lock().write(
+            &mut *writer,
+            crate::interrupts::ticks(),
+            args,
+        )
+    });
+    crate::log_buffer::_log(args);
+    result
+}
+
+/// Write `value` to the screen in decimal, padded to `width` bytes with
+/// `pad`. See [crate::sink::write_u64_padded]. Unlike the print macros,
+/// this bypasses the output limit and the log.
+pub fn write_u64_padded(value: u64, width: usize, pad: u8) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        crate::sink::write_u64_padded(&mut *writer, value, width, pad);
+    });
+}
+
+/// Like [write_u64_padded], but in hexadecimal. See
+/// [crate::sink::write_u64_hex_padded].
+pub fn write_u64_hex_padded(value: u64, width: usize, pad: u8) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        crate::sink::write_u64_hex_padded(&mut *writer, value, width, pad);
+    });
+}
+
+static OUTPUT_LIMITER: Mutex<OutputLimiter> = Mutex::new(OutputLimiter::new());
+
+/// Drop output of [crate::print] and [crate::println] beyond
+/// `max_bytes_per_tick` bytes per timer tick, or remove the limit with
+/// `None`. See [crate::output_limit] for details.
+pub fn set_output_limit(max_bytes_per_tick: Option<usize>) {
+    use x86_64::instructions::interrupts;
+
     interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
+        OUTPUT_LIMITER.lock().set_limit(max_bytes_per_tick);
     });
 }
 
+/// Next row that [early_write] writes to.
+static EARLY_ROW: AtomicUsize = AtomicUsize::new(0);
+
+/// Write `s` to its own row of the screen, without going through
+/// [struct@WRITER], for [crate::early_print]. Each call uses the next
+/// row, starting from the top and wrapping around. Bytes that are not
+/// printable ASCII are replaced and the line is cut off at the edge of
+/// the screen.
+///
+/// Nothing is locked, so concurrent calls or [struct@WRITER] can write
+/// over the same cells.
+pub(crate) fn early_write(s: &str) {
+    let row = EARLY_ROW.fetch_add(1, Ordering::Relaxed) % BUFFER_HEIGHT;
+
+    for col in 0..BUFFER_WIDTH {
+        let ascii = match s.as_bytes().get(col) {
+            Some(&byte @ 0x20..=0x7e) => byte,
+            Some(_) => 0xfe,
+            None => b' ',
+        };
+        let character = ScreenChar::new(ascii, DEFAULT_COLOR);
+        VgaBuffer::write_early(row, col, character);
+    }
+}
+
+/// Text printed above the message by [print_panic].
+const PANIC_BANNER: &str = "*** KERNEL PANIC ***";
+
+/// Release the lock of [struct@WRITER], even if someone else holds it.
+///
+/// This is unsafe because whoever held the lock will keep writing to
+/// the buffer. Only use it when that code is never going to resume, eg
+/// when panicking.
+pub unsafe fn force_unlock() {
+    WRITER.force_unlock();
+}
+
+/// Print the panic `info` in white on red, preceded by a banner, so
+/// that it is impossible to miss on screen.
+///
+/// This is meant to be called from the panic handler. It disables
+/// interrupts so that nothing else can print over the message, and
+/// forcibly unlocks [struct@WRITER] because the panic might have
+/// happened while it was locked. The color is not restored, because we
+/// don't expect to print anything else after a panic.
+pub fn print_panic(info: &PanicInfo) {
+    use core::fmt::Write;
+
+    x86_64::instructions::interrupts::disable();
+    unsafe { force_unlock() };
+
+    // A panic while printing a panic would find this locked.
+    if PANIC_MESSAGE.is_locked() {
+        unsafe { PANIC_MESSAGE.force_unlock() };
+    }
+    let mut message = PANIC_MESSAGE.lock();
+    message.len = 0;
+    message.truncated = false;
+    // Errors only mean that the message was truncated.
+    let _ = write!(message, "{}", info);
+
+    let mut writer = WRITER.lock();
+    writer.set_buffered(false);
+    writer.color_code = ColorCode::new(Color::White, Color::Red);
+
+    // There's nothing useful to do with errors while panicking anyway.
+    let _ = writeln!(writer, "\n{}", PANIC_BANNER);
+    writer.write_text(&message.bytes[..message.len]);
+    if message.truncated {
+        writer.write_string(TRUNCATED_MARKER);
+    }
+    writer.write_byte(b'\n');
+}
+
+/// How many bytes of the panic message [print_panic] shows at most.
+pub const PANIC_MESSAGE_SIZE: usize = 1024;
+
+/// Appended to text that was cut short, eg by [print_panic] to a panic
+/// message that didn't fit in [PANIC_MESSAGE_SIZE]. It's plain ASCII
+/// because CP437 has no ellipsis, and [crate::serial::debug_truncated]
+/// uses the same marker so that both outputs can be searched for it.
+pub const TRUNCATED_MARKER: &str = "...(truncated)";
+
+/// A fixed buffer that [print_panic] formats the message into, so that
+/// printing it never needs the heap, which may be what failed.
+struct PanicMessage {
+    bytes: [u8; PANIC_MESSAGE_SIZE],
+    len: usize,
+    truncated: bool,
+}
+
+impl fmt::Write for PanicMessage {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let room = PANIC_MESSAGE_SIZE - self.len;
+        let mut len = s.len().min(room);
+        // Don't split characters, so that the message stays valid.
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.bytes[self.len..self.len + len]
+            .copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+
+        if len < s.len() {
+            // Stop formatting, there's no room for the rest anyway.
+            self.truncated = true;
+            return Err(fmt::Error);
+        }
+        Ok(())
+    }
+}
+
+// This is a static rather than on the stack, because we may be
+// panicking because the stack is nearly full.
+static PANIC_MESSAGE: Mutex<PanicMessage> = Mutex::new(PanicMessage {
+    bytes: [0; PANIC_MESSAGE_SIZE],
+    len: 0,
+    truncated: false,
+});
+
 // The lazy static is required here because we don't want compile time
 // evaluation of the pointer.
 //
@@ -68,21 +285,155 @@ lazy_static! {
     /// allow use in multithreaded kernels.
     pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
         column_position: 0,
-        color_code: ColorCode::new(Color::Yellow, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+        color_code: DEFAULT_COLOR,
+        buffer: unsafe { VgaBuffer::new(0xb8000 as *mut Buffer) },
+        cursor_style: CursorStyle::Off,
+        cursor_cell: None,
+        word_wrap: false,
+        word_start: None,
+        scroll_fill_color: None,
+        buffered: false,
+        shadow: [[ScreenChar::BLANK; BUFFER_WIDTH]; BUFFER_HEIGHT],
+        dirty: [0; BUFFER_HEIGHT],
+        capture: None,
+        newline_mode: NewlineMode::Literal,
+        pending_cr: false,
+        scrollback: Scrollback::new(),
+        view_offset: 0,
+        live_screen: [[ScreenChar::BLANK; BUFFER_WIDTH]; BUFFER_HEIGHT],
+        indent: 0,
+        indent_pending: false,
+        max_line: None,
+        line_truncated: false,
+    });
+}
+
+/// How many bytes [stop_capture] returns at most. If more were written,
+/// the oldest ones are lost.
+pub const CAPTURE_SIZE: usize = 1024;
+
+/// Bytes written to [struct@WRITER] between [start_capture] and
+/// [stop_capture].
+pub struct CapturedOutput {
+    bytes: [u8; CAPTURE_SIZE],
+    len: usize,
+}
+
+impl CapturedOutput {
+    /// Copy the contents of `log`, from oldest to newest.
+    pub(crate) fn from_log(log: &LogBuffer<CAPTURE_SIZE>) -> Self {
+        let mut output = CapturedOutput {
+            bytes: [0; CAPTURE_SIZE],
+            len: 0,
+        };
+        let (first, second) = log.contents();
+        output.len = first.len() + second.len();
+        output.bytes[..first.len()].copy_from_slice(first);
+        output.bytes[first.len()..output.len].copy_from_slice(second);
+        output
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    /// The captured output as a string, or `None` if it contains
+    /// placeholders for characters that the screen can't display.
+    pub fn as_str(&self) -> Option<&str> {
+        core::str::from_utf8(self.as_bytes()).ok()
+    }
+}
+
+/// Start recording every byte written to [struct@WRITER], as it appears
+/// on screen, ie with unprintable characters already replaced. Anything
+/// that was captured before is discarded.
+pub fn start_capture() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        WRITER.lock().capture = Some(LogBuffer::new());
+    });
+}
+
+/// Stop recording and return what was written since [start_capture].
+/// Only the last [CAPTURE_SIZE] bytes are kept. If there was no capture
+/// running, the result is empty.
+pub fn stop_capture() -> CapturedOutput {
+    use x86_64::instructions::interrupts;
+
+    let capture =
+        interrupts::without_interrupts(|| WRITER.lock().capture.take());
+    match capture {
+        Some(capture) => CapturedOutput::from_log(&capture),
+        None => CapturedOutput::from_log(&LogBuffer::new()),
+    }
+}
+
+/// The color that [struct@WRITER] starts with, yellow on black.
+pub const DEFAULT_COLOR: ColorCode =
+    ColorCode((Color::Black as u8) << 4 | Color::Yellow as u8);
+
+/// Make `color` the color of [struct@WRITER], for all text printed from
+/// now on and for the rows that are blanked when scrolling or clearing.
+/// This is meant to be called early, before most output. Text that is
+/// already on screen keeps its color.
+pub fn set_default_color(color: ColorCode) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        WRITER.lock().color_code = color;
     });
 }
 
+/// How many timer ticks the software cursor stays on or off.
+const CURSOR_BLINK_TICKS: u64 = 5;
+
+/// Blink the software cursor of [struct@WRITER]. Called from the timer
+/// interrupt with the current tick count.
+#[doc(hidden)]
+pub fn cursor_tick(ticks: u64) {
+    if ticks % CURSOR_BLINK_TICKS != 0 {
+        return;
+    }
+    // Never wait for the lock in an interrupt handler. We'll simply
+    // blink on a later tick.
+    if let Some(mut writer) = WRITER.try_lock() {
+        writer.toggle_cursor();
+    }
+}
+
+/// How [Writer] draws the software cursor, see
+/// [Writer::set_software_cursor].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorStyle {
+    /// Don't draw a cursor.
+    Off,
+    /// Swap the foreground and background colors of the cursor cell.
+    Block,
+    /// Replace the character of the cursor cell with an underscore.
+    Underline,
+}
+
+/// How [Writer] treats `\r` in text, see [Writer::set_newline_mode].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NewlineMode {
+    /// Only `\n` changes lines. `\r` is unprintable and is shown as a
+    /// placeholder.
+    Literal,
+    /// `\r\n` changes lines once, like a lone `\n`. A lone `\r` is
+    /// still shown as a placeholder.
+    CrLfAsOne,
+    /// Like [NewlineMode::CrLfAsOne], but a lone `\r` changes lines
+    /// too, as if it were `\r\n`.
+    CrAsNewline,
+}
+
 /// Color byte for the VGA buffer. The VGA buffer requires both a
 /// foreground and a background color, so we can't use this enum
 /// directly. Use [ColorCode] as the VGA color byte instead.
-// Rust will consider dead code every single Color we do not use. Since
-// we don't intend to use every one, we supress warnings for this enum
-// in particular.
-#[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
-enum Color {
+pub enum Color {
     Black = 0,
     Blue = 1,
     Green = 2,
@@ -101,29 +452,220 @@ enum Color {
     White = 15,
 }
 
+/// Whether the top bit of the attribute byte makes the character blink
+/// (the default) instead of selecting a bright background color. See
+/// [set_blink_enabled].
+static BLINK_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Bit of the attribute byte that is either the blink bit or the top
+/// bit of the background color.
+const BLINK_BIT: u8 = 0x80;
+
+/// Choose what the top bit of the attribute byte does. With blinking
+/// enabled, which is how the BIOS leaves it, characters with that bit
+/// set blink and only the first 8 colors can be used as background.
+/// With blinking disabled, all 16 colors can be used as background.
+///
+/// This is controlled by bit 3 of the Attribute Mode Control register
+/// of the attribute controller. The controller has a single port,
+/// `0x3C0`, for both the register index and the data, with a flip-flop
+/// that alternates between the two on each write. So the sequence is:
+///  1. read `0x3DA` to reset the flip-flop to "index"
+///  2. write the index `0x10` to `0x3C0`, with bit 5 set, because
+///     clearing it would blank the screen
+///  3. read the current value of the register from `0x3C1`
+///  4. write the modified value to `0x3C0`
+///
+/// Only [ColorCode]s created afterwards are affected, existing text
+/// keeps its attribute bytes.
+pub fn set_blink_enabled(enabled: bool) {
+    use x86_64::instructions::interrupts;
+    use x86_64::instructions::port::Port;
+
+    const MODE_CONTROL_INDEX: u8 = 0x10;
+    const PALETTE_ADDRESS_SOURCE: u8 = 0x20;
+    const BLINK_ENABLE: u8 = 0x08;
+
+    let mut input_status = Port::<u8>::new(0x3da);
+    let mut address_data = Port::<u8>::new(0x3c0);
+    let mut data_read = Port::<u8>::new(0x3c1);
+
+    // Nothing else may touch the flip-flop in the middle of the
+    // sequence.
+    interrupts::without_interrupts(|| unsafe {
+        input_status.read();
+        address_data.write(MODE_CONTROL_INDEX | PALETTE_ADDRESS_SOURCE);
+        let mode = data_read.read();
+        let mode = if enabled {
+            mode | BLINK_ENABLE
+        }
+        else {
+            mode & !BLINK_ENABLE
+        };
+        address_data.write(mode);
+        BLINK_ENABLED.store(enabled, Ordering::SeqCst);
+    });
+}
+
 /// A color code containing both a foreground and a background color.
 /// The information is encoded in a single byte as per the VGA buffer.
 /// You can definitely write this directly into the VGA buffer, but
 /// most of the time you want to create a [ScreenChar] instead.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
-struct ColorCode(u8);
+pub struct ColorCode(u8);
 
 impl ColorCode {
-    fn new(foreground: Color, background: Color) -> ColorCode {
-        ColorCode((background as u8) << 4 | (foreground as u8))
+    pub fn new(foreground: Color, background: Color) -> ColorCode {
+        ColorCode::from_byte((background as u8) << 4 | (foreground as u8))
+    }
+
+    /// The same colors with foreground and background swapped.
+    fn inverted(self) -> ColorCode {
+        ColorCode::from_byte(self.0 << 4 | self.0 >> 4)
+    }
+
+    /// Wrap an attribute byte for the current blink mode. While
+    /// blinking is enabled a bright background would blink instead, so
+    /// it is replaced with its dark counterpart.
+    fn from_byte(byte: u8) -> ColorCode {
+        if BLINK_ENABLED.load(Ordering::Relaxed) {
+            ColorCode(byte & !BLINK_BIT)
+        }
+        else {
+            ColorCode(byte)
+        }
     }
 }
 
 /// A tuple of (ASCII code, color code) that represents a single
-/// character on the screen as per the VGA buffer standard.
+/// character on the screen as per the VGA buffer standard. The hardware
+/// reads the cells of the buffer as exactly these two bytes, in this
+/// order, so the layout must not change.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)]
-struct ScreenChar {
+pub struct ScreenChar {
     ascii_character: u8,
     color_code: ColorCode,
 }
 
+impl ScreenChar {
+    /// An empty cell, black on black.
+    const BLANK: ScreenChar = ScreenChar::blank(ColorCode(0));
+
+    /// Create a cell showing `ascii` in `color`. The byte is shown as is
+    /// with code page 437, it is not checked or replaced.
+    pub const fn new(ascii: u8, color: ColorCode) -> ScreenChar {
+        ScreenChar {
+            ascii_character: ascii,
+            color_code: color,
+        }
+    }
+
+    /// Create an empty cell, ie a space, with background `color`.
+    pub const fn blank(color: ColorCode) -> ScreenChar {
+        ScreenChar::new(b' ', color)
+    }
+
+    /// The byte that is shown in the cell.
+    pub fn ascii(&self) -> u8 {
+        self.ascii_character
+    }
+
+    pub fn color(&self) -> ColorCode {
+        self.color_code
+    }
+}
+
+/// A copy of the whole screen at one point in time, see
+/// [Writer::with_snapshot].
+#[derive(Clone)]
+pub struct ScreenSnapshot {
+    cells: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    column_position: usize,
+}
+
+impl ScreenSnapshot {
+    /// Get the cell at `row` and `col`, counting from the top left.
+    ///
+    /// Panics if the position is outside of the screen.
+    pub fn cell(&self, row: usize, col: usize) -> ScreenChar {
+        self.cells[row][col]
+    }
+
+    /// Get all cells of `row`, from left to right.
+    pub fn row(&self, row: usize) -> &[ScreenChar; BUFFER_WIDTH] {
+        &self.cells[row]
+    }
+
+    /// The column where the next character would have been written.
+    /// Text is always written to the bottom row.
+    pub fn column_position(&self) -> usize {
+        self.column_position
+    }
+}
+
+/// A copy of a rectangle of the screen, which is written back to
+/// [struct@WRITER] when this is dropped, see [Writer::save_region].
+///
+/// The copy is owned by the guard, so it can be kept for as long as
+/// needed. Whatever was drawn over the rectangle in the meantime is
+/// simply overwritten.
+#[must_use = "the region is restored when the guard is dropped"]
+pub struct RegionGuard {
+    top: usize,
+    left: usize,
+    width: usize,
+    cells: Vec<ScreenChar>,
+}
+
+impl Drop for RegionGuard {
+    fn drop(&mut self) {
+        use x86_64::instructions::interrupts;
+
+        interrupts::without_interrupts(|| {
+            WRITER.lock().restore_region(self);
+        });
+    }
+}
+
+/// How many lines that scrolled off the screen are kept.
+pub const SCROLLBACK_LINES: usize = 100;
+
+/// Lines that scrolled off the top of the screen, oldest first. When it
+/// is full, the oldest line is overwritten.
+struct Scrollback {
+    lines: [[ScreenChar; BUFFER_WIDTH]; SCROLLBACK_LINES],
+    start: usize,
+    len: usize,
+}
+
+impl Scrollback {
+    const fn new() -> Self {
+        Scrollback {
+            lines: [[ScreenChar::BLANK; BUFFER_WIDTH]; SCROLLBACK_LINES],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, line: [ScreenChar; BUFFER_WIDTH]) {
+        let end = (self.start + self.len) % SCROLLBACK_LINES;
+        self.lines[end] = line;
+        if self.len < SCROLLBACK_LINES {
+            self.len += 1;
+        }
+        else {
+            self.start = (self.start + 1) % SCROLLBACK_LINES;
+        }
+    }
+
+    /// Get the `i`th line, counting from the oldest.
+    fn line(&self, i: usize) -> &[ScreenChar; BUFFER_WIDTH] {
+        &self.lines[(self.start + i) % SCROLLBACK_LINES]
+    }
+}
+
 /// The entire VGA buffer. It is a 2d array of [ScreenChar]s. For
 /// safety reasons, this should be manipulated through a [Writer].
 #[repr(transparent)]
@@ -131,6 +673,72 @@ struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+/// Marks the end of a cell that [Writer::draw_table] cut short. CP437
+/// has no ellipsis, so this is a `»`.
+pub const TRUNCATED: u8 = 0xaf;
+
+// Box drawing characters of CP437 for Writer::draw_table, each given as
+// [left or top, middle, right or bottom] where it applies.
+const BOX_HORIZONTAL: u8 = 0xc4;
+const BOX_VERTICAL: u8 = 0xb3;
+const BOX_TOP: [u8; 3] = [0xda, 0xc2, 0xbf];
+const BOX_MIDDLE: [u8; 3] = [0xc3, 0xc5, 0xb4];
+const BOX_BOTTOM: [u8; 3] = [0xc0, 0xc1, 0xd9];
+
+/// Pointer to the VGA text buffer, with volatile accessors for its
+/// cells. This is where all access to the memory mapped buffer goes
+/// through, so the unsafe code that it needs is in one place.
+struct VgaBuffer(*mut Buffer);
+
+impl VgaBuffer {
+    /// Wrap `buffer`.
+    ///
+    /// This is unsafe because `buffer` must point to a VGA text buffer
+    /// that stays mapped forever, and nothing else may access it while
+    /// the [VgaBuffer] exists. So there must only ever be one for the
+    /// buffer at `0xb8000`, the one in [struct@WRITER]. The only
+    /// exception is [VgaBuffer::write_early].
+    unsafe fn new(buffer: *mut Buffer) -> Self {
+        VgaBuffer(buffer)
+    }
+
+    /// Write a cell of the buffer at `0xb8000` without going through a
+    /// [VgaBuffer], for [early_write], which must work before and
+    /// regardless of [struct@WRITER].
+    ///
+    /// This is the exception to the guarantees of [VgaBuffer::new]. It
+    /// can interleave with the writes of [struct@WRITER], but we only
+    /// run on one CPU, so each cell write still happens as a whole, and
+    /// the worst case is that the two overwrite each others' text.
+    fn write_early(row: usize, col: usize, character: ScreenChar) {
+        let buffer = 0xb8000 as *mut Buffer;
+        // Safe because the VGA buffer is always mapped at 0xb8000, and
+        // see above for the aliasing.
+        unsafe { (*buffer).chars[row][col].write(character) }
+    }
+
+    fn read(&self, row: usize, col: usize) -> ScreenChar {
+        // Safe because of the guarantees of Self::new.
+        unsafe { (*self.0).chars[row][col].read() }
+    }
+
+    fn write(&mut self, row: usize, col: usize, character: ScreenChar) {
+        // Safe because of the guarantees of Self::new, and because
+        // writes need `&mut self`, so they can't race with anything.
+        unsafe { (*self.0).chars[row][col].write(character) }
+    }
+}
+
+// Raw pointers are neither Send nor Sync, but both are fine here.
+// VgaBuffer::new guarantees that it is the only way to the buffer, apart
+// from VgaBuffer::write_early, and the buffer is there for the whole
+// runtime of the kernel, no matter which context uses it. Reads only
+// need `&self`, but writes need `&mut self`, so the usual borrow rules
+// make sure that they never race. In practice, it is only ever accessed
+// through the Mutex of WRITER.
+unsafe impl Send for VgaBuffer {}
+unsafe impl Sync for VgaBuffer {}
+
 /// Write to the VGA buffer. This works like a character stream, where
 /// the user is not required or allowed to manipulate the buffer
 /// directly. Instead [Writer] keeps track of where the cursor is and
@@ -139,98 +747,1152 @@ struct Buffer {
 pub struct Writer {
     column_position: usize,
     color_code: ColorCode,
-    buffer: &'static mut Buffer,
+    buffer: VgaBuffer,
+    cursor_style: CursorStyle,
+    /// Position and original contents of the cell the software cursor
+    /// is drawn on, if it is currently drawn.
+    cursor_cell: Option<(usize, usize, ScreenChar)>,
+    word_wrap: bool,
+    /// Column where the word at the end of the current line starts, if
+    /// the line ends with one.
+    word_start: Option<usize>,
+    /// Background color of the rows that scroll in at the bottom, or
+    /// `None` to use the background of `color_code`.
+    scroll_fill_color: Option<Color>,
+    /// Whether changes go to `shadow` until [Writer::flush], instead of
+    /// straight to `buffer`.
+    buffered: bool,
+    /// Copy of the screen in regular memory, used while buffered.
+    shadow: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    /// For each row, a bit for each cell of `shadow` that has changed
+    /// since the last flush.
+    dirty: [u128; BUFFER_HEIGHT],
+    /// Copy of every byte written since [start_capture], if capturing.
+    capture: Option<LogBuffer<CAPTURE_SIZE>>,
+    newline_mode: NewlineMode,
+    /// Whether the last byte of text was a `\r` that hasn't been
+    /// handled yet, because it depends on the next byte.
+    pending_cr: bool,
+    scrollback: Scrollback,
+    /// How many lines back into `scrollback` the screen shows, 0 if it
+    /// shows the current output.
+    view_offset: usize,
+    /// The current output, saved while the screen shows the scrollback.
+    live_screen: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    /// Number of spaces written at the start of each line, see
+    /// [Writer::set_indent].
+    indent: usize,
+    /// Whether the last byte was a `\n`, so the indentation goes before
+    /// the next one.
+    indent_pending: bool,
+    /// Column after which the rest of a line is dropped, see
+    /// [Writer::set_max_line].
+    max_line: Option<usize>,
+    /// Whether the current line went past `max_line`.
+    line_truncated: bool,
 }
 
 impl Writer {
-    /// Write a single byte to the screen. To change lines, pass a '\n'
-    /// character.
-    pub fn write_byte(&mut self, byte: u8) {
-        match byte {
-            b'\n' => self.new_line(),
-            byte => {
-                // If the line is full, move to the next one
-                if self.column_position >= BUFFER_WIDTH {
-                    self.new_line();
+    /// Collect changes in a copy of the screen in regular memory, and
+    /// only write the cells that changed to the VGA buffer on
+    /// [Writer::flush]. This avoids redundant writes to video memory,
+    /// which is slow, eg when the whole screen is repainted.
+    ///
+    /// Nothing written while buffered is visible until the next flush,
+    /// including the software cursor. Disabling buffering flushes.
+    pub fn set_buffered(&mut self, buffered: bool) {
+        if buffered == self.buffered {
+            return;
+        }
+        if buffered {
+            for row in 0..BUFFER_HEIGHT {
+                for col in 0..BUFFER_WIDTH {
+                    self.shadow[row][col] = self.buffer.read(row, col);
                 }
+            }
+            self.dirty = [0; BUFFER_HEIGHT];
+        }
+        else {
+            self.flush();
+        }
+        self.buffered = buffered;
+    }
 
-                let row = BUFFER_HEIGHT - 1;
-                let col = self.column_position;
-
-                let color_code = self.color_code;
-                self.buffer.chars[row][col].write(ScreenChar {
-                    ascii_character: byte,
-                    color_code,
-                });
+    /// Write the cells that changed since the last flush to the VGA
+    /// buffer. Does nothing unless buffered, see [Writer::set_buffered].
+    pub fn flush(&mut self) {
+        let Writer {
+            buffer,
+            shadow,
+            dirty,
+            ..
+        } = self;
+        flush_dirty(shadow, dirty, |row, col, character| {
+            buffer.write(row, col, character);
+        });
+    }
 
-                self.column_position += 1;
+    /// Copy the screen, as it will look after the next flush if
+    /// buffered. The software cursor is not included, cells show what is
+    /// under it.
+    pub fn snapshot(&self) -> ScreenSnapshot {
+        let mut snapshot = ScreenSnapshot {
+            cells: [[ScreenChar::BLANK; BUFFER_WIDTH]; BUFFER_HEIGHT],
+            column_position: self.column_position,
+        };
+        for (row, cells) in snapshot.cells.iter_mut().enumerate() {
+            for (col, cell) in cells.iter_mut().enumerate() {
+                *cell = self.read_cell(row, col);
             }
         }
+        if let Some((row, col, original)) = self.cursor_cell {
+            snapshot.cells[row][col] = original;
+        }
+        snapshot
     }
 
-    /// Convenience function to call [Writer::write_byte] on every byte
-    /// of a string.
-    pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            // str is UTF-8 but the VGA buffer supports CCSID 437 only.
-            // We can deal with this by transforming unprintable
-            // characters to a printable placeholder.
-            match byte {
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                _ => self.write_byte(0xfe),
+    /// Save the rectangle of `height` rows and `width` columns with its
+    /// top left corner at `top` and `left`, eg before drawing a dialog
+    /// over it. It is restored when the returned guard is dropped.
+    ///
+    /// The guard locks [struct@WRITER] when it's dropped, so don't drop
+    /// it while holding the lock. The copy is on the heap.
+    ///
+    /// Panics if the rectangle doesn't fit on the screen.
+    pub fn save_region(
+        &self,
+        top: usize,
+        left: usize,
+        height: usize,
+        width: usize,
+    ) -> RegionGuard {
+        assert!(
+            top + height <= BUFFER_HEIGHT && left + width <= BUFFER_WIDTH,
+            "Region doesn't fit on the screen"
+        );
+
+        let mut cells = Vec::with_capacity(height * width);
+        for row in top..top + height {
+            for col in left..left + width {
+                let cell = match self.cursor_cell {
+                    Some((r, c, original)) if (r, c) == (row, col) => original,
+                    _ => self.read_cell(row, col),
+                };
+                cells.push(cell);
             }
         }
+        RegionGuard {
+            top,
+            left,
+            width,
+            cells,
+        }
     }
 
-    /// Move to new line, essentially do what you expect for '\n'.
-    fn new_line(&mut self) {
-        for row in 1..BUFFER_HEIGHT {
-            for col in 0..BUFFER_WIDTH {
-                let character = self.buffer.chars[row][col].read();
-                self.buffer.chars[row - 1][col].write(character);
+    /// Write the cells saved in `region` back to where they came from.
+    fn restore_region(&mut self, region: &RegionGuard) {
+        // The cursor may have been drawn over the region since, and it
+        // must not restore its old cell over ours.
+        self.hide_cursor();
+        if region.width == 0 {
+            return;
+        }
+        for (i, row) in region.cells.chunks(region.width).enumerate() {
+            for (j, &cell) in row.iter().enumerate() {
+                self.write_cell(region.top + i, region.left + j, cell);
             }
         }
-        self.clear_row(BUFFER_HEIGHT - 1);
-        self.column_position = 0;
     }
 
-    fn clear_row(&mut self, row: usize) {
-        let blank = ScreenChar {
-            ascii_character: b' ',
-            color_code: self.color_code,
-        };
-        for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
-        }
+    /// Take a [Writer::snapshot] of [struct@WRITER] and pass it to `f`.
+    ///
+    /// The lock is only held while copying, with interrupts disabled, so
+    /// nothing can change the screen halfway and `f` can take as long as
+    /// it likes without holding up printing.
+    pub fn with_snapshot<R>(f: impl FnOnce(&ScreenSnapshot) -> R) -> R {
+        use x86_64::instructions::interrupts;
+
+        let snapshot =
+            interrupts::without_interrupts(|| WRITER.lock().snapshot());
+        f(&snapshot)
     }
-}
 
-impl fmt::Write for Writer {
-    fn write_str(&mut self, s: &str) -> fmt::Result {
-        self.write_string(s);
-        Ok(())
+    /// Write `character` to the cell at `row` and `col` exactly, with its
+    /// own color rather than the current one. The position where text
+    /// is written next doesn't change. If the software cursor is on the
+    /// cell, it stays there and the new character shows once it blinks
+    /// off.
+    ///
+    /// Panics if the cell is not on the screen.
+    pub fn set_cell(&mut self, row: usize, col: usize, character: ScreenChar) {
+        assert!(
+            row < BUFFER_HEIGHT && col < BUFFER_WIDTH,
+            "Cell ({}, {}) is not on the screen",
+            row,
+            col
+        );
+        self.follow_output();
+        match &mut self.cursor_cell {
+            Some((r, c, original)) if (*r, *c) == (row, col) => {
+                *original = character
+            }
+            _ => self.write_cell(row, col, character),
+        }
     }
-}
 
-#[test_case]
-fn test_println_simple() {
-    println!("test_println_simple output");
-}
+    /// Draw a table with its top left corner at `top` and `left`, with
+    /// a border around it and lines between the rows and columns. Each
+    /// of `rows` is a row of cells, and `col_widths` gives the width of
+    /// the text in each column, without the lines. Cells that are too
+    /// long end in a [TRUNCATED] marker. Rows with fewer cells than
+    /// there are columns get empty ones, extra cells are ignored.
+    ///
+    /// The table uses the current color. Like with [Writer::set_cell],
+    /// the position where text is written next doesn't change, and the
+    /// screen never scrolls. Whatever doesn't fit on the screen is cut
+    /// off.
+    pub fn draw_table(
+        &mut self,
+        top: usize,
+        left: usize,
+        col_widths: &[usize],
+        rows: &[&[&str]],
+    ) {
+        if col_widths.is_empty() {
+            return;
+        }
 
-#[test_case]
-fn test_println_many() {
-    for _ in 0..200 {
-        println!("test_println_many output");
+        self.draw_table_line(top, left, col_widths, BOX_TOP);
+        for (i, cells) in rows.iter().enumerate() {
+            let row = top + 2 * i + 1;
+            let mut col = left;
+            self.put_clamped(row, col, BOX_VERTICAL);
+            for (j, &width) in col_widths.iter().enumerate() {
+                let text =
+                    cells.get(j).map_or(&b""[..], |text| text.as_bytes());
+                for k in 0..width {
+                    let byte = match text.get(k) {
+                        Some(_) if k == width - 1 && text.len() > width => {
+                            TRUNCATED
+                        }
+                        Some(&byte @ 0x20..=0x7e) => byte,
+                        Some(_) => 0xfe,
+                        None => b' ',
+                    };
+                    self.put_clamped(row, col + 1 + k, byte);
+                }
+                col += width + 1;
+                self.put_clamped(row, col, BOX_VERTICAL);
+            }
+
+            let boundary = if i == rows.len() - 1 {
+                BOX_BOTTOM
+            }
+            else {
+                BOX_MIDDLE
+            };
+            self.draw_table_line(row + 1, left, col_widths, boundary);
+        }
     }
-}
 
-#[test_case]
-fn test_println_output() {
-    use core::fmt::Write;
-    use x86_64::instructions::interrupts;
+    /// Write `title` in a box, on lines of its own, using the box
+    /// drawing characters of [Writer::draw_table]. Titles that don't
+    /// fit on a line are cut off.
+    pub fn write_banner(&mut self, title: &str) {
+        if self.column_position != 0 {
+            self.write_byte(b'\n');
+        }
+        self.write_string("\n\n\n");
+        let width = title.len().clamp(1, BUFFER_WIDTH - 2);
+        self.draw_table(BUFFER_HEIGHT - 4, 0, &[width], &[&[title]]);
+    }
 
-    let s = "Some test string that fits on a single line";
-    // Disable interrupts and keep the writer locked for the entire
+    /// Write a status line: `name` on the left, and a marker on the
+    /// right edge of the screen that says whether it is `ok`, "[ OK ]"
+    /// in green or "[FAIL]" in red on the current background. Names
+    /// that are too long end in a [TRUNCATED] marker. The line starts
+    /// on a new line if the current one isn't empty, and it is ended
+    /// with a newline.
+    pub fn write_status(&mut self, name: &str, ok: bool) {
+        let (marker, color) = if ok {
+            (b"[ OK ]", Color::LightGreen)
+        }
+        else {
+            (b"[FAIL]", Color::LightRed)
+        };
+        let name_width = BUFFER_WIDTH - marker.len() - 1;
+
+        if self.column_position != 0 {
+            self.write_byte(b'\n');
+        }
+        if name.len() > name_width {
+            self.write_text(&name.as_bytes()[..name_width - 1]);
+            self.write_byte(TRUNCATED);
+        }
+        else {
+            self.write_string(name);
+        }
+        while self.column_position < BUFFER_WIDTH - marker.len() {
+            self.write_byte(b' ');
+        }
+
+        let original = self.color_code;
+        self.color_code = ColorCode::from_byte(original.0 & 0xf0 | color as u8);
+        self.write_raw(marker);
+        self.color_code = original;
+        self.write_byte(b'\n');
+    }
+
+    /// Draw a horizontal line of a table for [Writer::draw_table], with
+    /// `first` at the left, `middle` between the columns and `last` at
+    /// the right.
+    fn draw_table_line(
+        &mut self,
+        row: usize,
+        left: usize,
+        col_widths: &[usize],
+        [first, middle, last]: [u8; 3],
+    ) {
+        let mut col = left;
+        self.put_clamped(row, col, first);
+        for (i, &width) in col_widths.iter().enumerate() {
+            for _ in 0..width {
+                col += 1;
+                self.put_clamped(row, col, BOX_HORIZONTAL);
+            }
+            col += 1;
+            let end = if i == col_widths.len() - 1 {
+                last
+            }
+            else {
+                middle
+            };
+            self.put_clamped(row, col, end);
+        }
+    }
+
+    /// [Writer::set_cell] with the current color, unless the cell is
+    /// not on the screen.
+    fn put_clamped(&mut self, row: usize, col: usize, byte: u8) {
+        if row < BUFFER_HEIGHT && col < BUFFER_WIDTH {
+            self.set_cell(row, col, ScreenChar::new(byte, self.color_code));
+        }
+    }
+
+    /// Read the cell at `row` and `col`, as it will look after the next
+    /// flush if buffered. Like with [Writer::snapshot], the software
+    /// cursor is not included.
+    ///
+    /// Panics if the cell is not on the screen.
+    pub fn char_at(&self, row: usize, col: usize) -> ScreenChar {
+        assert!(
+            row < BUFFER_HEIGHT && col < BUFFER_WIDTH,
+            "Cell ({}, {}) is not on the screen",
+            row,
+            col
+        );
+        match self.cursor_cell {
+            Some((r, c, original)) if (r, c) == (row, col) => original,
+            _ => self.read_cell(row, col),
+        }
+    }
+
+    /// Copy the text of the line the cursor is on into `buf`, without
+    /// trailing blanks, and return how many bytes were copied. If `buf`
+    /// is shorter than the line, only the start of it is copied.
+    ///
+    /// Only printable ASCII is copied as is. Everything else, like the
+    /// `0xfe` placeholders of [Writer::write_string], becomes `?`. The
+    /// line is read as it is, even when the view is scrolled back.
+    pub fn current_line(&self, buf: &mut [u8]) -> usize {
+        let row = BUFFER_HEIGHT - 1;
+        let cell = |col| {
+            if self.view_offset == 0 {
+                self.char_at(row, col).ascii_character
+            }
+            else {
+                self.live_screen[row][col].ascii_character
+            }
+        };
+        let len = (0..BUFFER_WIDTH)
+            .rposition(|col| cell(col) != b' ')
+            .map_or(0, |last| last + 1)
+            .min(buf.len());
+
+        for (col, byte) in buf[..len].iter_mut().enumerate() {
+            *byte = match cell(col) {
+                character @ 0x20..=0x7e => character,
+                _ => b'?',
+            };
+        }
+        len
+    }
+
+    /// Read a cell, from the shadow copy if buffered.
+    fn read_cell(&self, row: usize, col: usize) -> ScreenChar {
+        if self.buffered {
+            self.shadow[row][col]
+        }
+        else {
+            self.buffer.read(row, col)
+        }
+    }
+
+    /// Write a cell, to the shadow copy if buffered. Writes that don't
+    /// change the cell are not flushed.
+    fn write_cell(&mut self, row: usize, col: usize, character: ScreenChar) {
+        if !self.buffered {
+            self.buffer.write(row, col, character);
+        }
+        else if self.shadow[row][col] != character {
+            self.shadow[row][col] = character;
+            self.dirty[row] |= 1 << col;
+        }
+    }
+
+    /// Draw a blinking cursor where the next character will go. This is
+    /// for when the hardware cursor is not available. The blinking is
+    /// driven by the timer interrupt, so it requires [crate::init].
+    pub fn set_software_cursor(&mut self, style: CursorStyle) {
+        self.hide_cursor();
+        self.cursor_style = style;
+    }
+
+    /// Start every line after a `\n` with `n` spaces, eg to show nested
+    /// structures. The spaces are written when the first character of
+    /// the line is, so they take up columns like any other text and the
+    /// line wraps that much earlier. Lines that only continue because
+    /// they wrapped are not indented. An indentation that would leave
+    /// no room on the line is cut down to the width of the screen minus
+    /// one. The default is 0.
+    pub fn set_indent(&mut self, n: usize) {
+        self.indent = n;
+    }
+
+    /// Indent `n` spaces further than now, eg for the children of a
+    /// node. See [Writer::set_indent].
+    pub fn push_indent(&mut self, n: usize) {
+        self.indent += n;
+    }
+
+    /// Undo a [Writer::push_indent] with the same `n`.
+    pub fn pop_indent(&mut self, n: usize) {
+        self.indent = self.indent.saturating_sub(n);
+    }
+
+    /// Drop the characters of each line that go beyond `max_line`
+    /// columns until the next `\n`, instead of wrapping them, eg for
+    /// machine generated output with very long lines. A line that is cut
+    /// short ends with a [TRUNCATED] marker in the column right after
+    /// the limit. With `None`, the default, long lines wrap as usual.
+    ///
+    /// The limit applies to the row on the screen, so a limit of the
+    /// width of the screen or more still wraps.
+    pub fn set_max_line(&mut self, max_line: Option<usize>) {
+        self.max_line = max_line;
+    }
+
+    /// Break lines between words instead of in the middle of them. When
+    /// a word doesn't fit on the current line, the part that was
+    /// already written is moved to the next one. A word that takes up
+    /// an entire line is still broken wherever the line ends. Off by
+    /// default.
+    pub fn set_word_wrap(&mut self, enabled: bool) {
+        self.word_wrap = enabled;
+    }
+
+    /// Choose how line endings other than `\n` are handled, eg to print
+    /// text with `\r\n` line endings without blank lines in between.
+    /// This only affects text, not [Writer::write_raw]. The default is
+    /// [NewlineMode::Literal].
+    ///
+    /// In [NewlineMode::CrLfAsOne] a `\r` at the end of the text is only
+    /// shown once the next byte is written, because only then do we
+    /// know whether a `\n` follows.
+    pub fn set_newline_mode(&mut self, mode: NewlineMode) {
+        self.newline_mode = mode;
+        self.pending_cr = false;
+    }
+
+    /// Blank the rows that scroll in at the bottom with `color` as the
+    /// background, eg to make it visible where scrolling started. Text
+    /// written to such a row still uses the current color.
+    pub fn set_scroll_fill_color(&mut self, color: Color) {
+        self.scroll_fill_color = Some(color);
+    }
+
+    /// Go back to blanking scrolled in rows with the current color.
+    pub fn clear_scroll_fill_color(&mut self) {
+        self.scroll_fill_color = None;
+    }
+
+    /// Draw the software cursor if it is hidden, or hide it otherwise.
+    fn toggle_cursor(&mut self) {
+        if self.cursor_cell.is_some() {
+            self.hide_cursor();
+            return;
+        }
+        // The cursor belongs to the current output, not the scrollback.
+        if self.cursor_style == CursorStyle::Off || self.view_offset != 0 {
+            return;
+        }
+
+        let row = BUFFER_HEIGHT - 1;
+        let col = self.column_position.min(BUFFER_WIDTH - 1);
+        let original = self.read_cell(row, col);
+        let cursor = match self.cursor_style {
+            CursorStyle::Block => ScreenChar {
+                ascii_character: original.ascii_character,
+                color_code: original.color_code.inverted(),
+            },
+            _ => ScreenChar {
+                ascii_character: b'_',
+                color_code: original.color_code,
+            },
+        };
+        self.write_cell(row, col, cursor);
+        self.cursor_cell = Some((row, col, original));
+    }
+
+    /// Restore the cell under the software cursor, if it is drawn. This
+    /// must be done before modifying the buffer, so that we neither
+    /// leave a stale cursor behind nor restore over new contents.
+    fn hide_cursor(&mut self) {
+        if let Some((row, col, original)) = self.cursor_cell.take() {
+            self.write_cell(row, col, original);
+        }
+    }
+
+    /// Write a single byte to the screen. To change lines, pass a '\n'
+    /// character.
+    pub fn write_byte(&mut self, byte: u8) {
+        if byte != b'\n' && core::mem::replace(&mut self.indent_pending, false)
+        {
+            for _ in 0..self.indent.min(BUFFER_WIDTH - 1) {
+                self.write_byte(b' ');
+            }
+        }
+        if let Some(capture) = &mut self.capture {
+            capture.write_bytes(&[byte]);
+        }
+        self.follow_output();
+        self.hide_cursor();
+        match byte {
+            b'\n' => {
+                self.new_line();
+                self.indent_pending = true;
+                self.line_truncated = false;
+            }
+            byte => {
+                // A limit that doesn't fit on the screen never applies,
+                // the line wraps before it is reached.
+                let byte = match self.max_line {
+                    Some(max_line)
+                        if max_line < BUFFER_WIDTH
+                            && self.column_position >= max_line =>
+                    {
+                        if self.line_truncated {
+                            return;
+                        }
+                        self.line_truncated = true;
+                        TRUNCATED
+                    }
+                    _ => byte,
+                };
+
+                // If the line is full, move to the next one
+                if self.column_position >= BUFFER_WIDTH {
+                    self.wrap_line();
+                }
+
+                let row = BUFFER_HEIGHT - 1;
+                let col = self.column_position;
+
+                let color_code = self.color_code;
+                self.write_cell(
+                    row,
+                    col,
+                    ScreenChar {
+                        ascii_character: byte,
+                        color_code,
+                    },
+                );
+
+                if byte == b' ' {
+                    self.word_start = None;
+                }
+                else if self.word_start.is_none() {
+                    self.word_start = Some(col);
+                }
+                self.column_position += 1;
+            }
+        }
+    }
+
+    /// Convenience function to call [Writer::write_byte] on every byte
+    /// of a string.
+    pub fn write_string(&mut self, s: &str) {
+        self.write_text(s.as_bytes());
+    }
+
+    /// Write text bytes, replacing the ones that are not printable
+    /// ASCII with a placeholder. This is what [Writer::write_string]
+    /// does, but it doesn't require valid UTF-8.
+    pub(crate) fn write_text(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if self.handle_line_ending(byte) {
+                continue;
+            }
+
+            // str is UTF-8 but the VGA buffer supports CCSID 437 only.
+            // We can deal with this by transforming unprintable
+            // characters to a printable placeholder.
+            match byte {
+                0x20..=0x7e | b'\n' => self.write_byte(byte),
+                _ => self.write_byte(0xfe),
+            }
+        }
+    }
+
+    /// Deal with `\r` according to the newline mode. Returns whether
+    /// `byte` was consumed, otherwise it should be written as usual.
+    fn handle_line_ending(&mut self, byte: u8) -> bool {
+        let after_cr = core::mem::replace(&mut self.pending_cr, false);
+        match (self.newline_mode, byte) {
+            (NewlineMode::Literal, _) => false,
+            (NewlineMode::CrLfAsOne, _) => {
+                // The previous `\r` wasn't part of a `\r\n` after all.
+                if after_cr && byte != b'\n' {
+                    self.write_byte(0xfe);
+                }
+                self.pending_cr = byte == b'\r';
+                self.pending_cr
+            }
+            (NewlineMode::CrAsNewline, b'\r') => {
+                self.write_byte(b'\n');
+                self.pending_cr = true;
+                true
+            }
+            // The `\r` before it already changed lines.
+            (NewlineMode::CrAsNewline, b'\n') => after_cr,
+            (NewlineMode::CrAsNewline, _) => false,
+        }
+    }
+
+    /// Write bytes that are already valid CP437 to the screen. Unlike
+    /// [Writer::write_string], the bytes are not translated or replaced
+    /// by placeholders, with the exception of `b'\n'` which still
+    /// changes lines. Wrapping works as usual.
+    pub fn write_raw(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_byte(byte);
+        }
+    }
+
+    /// Move to the next line because the current one is full. With word
+    /// wrap enabled, the word at the end of the line comes along, unless
+    /// it takes up the entire line.
+    fn wrap_line(&mut self) {
+        let word_start = match self.word_start {
+            Some(start) if self.word_wrap && start > 0 => start,
+            _ => return self.new_line(),
+        };
+
+        self.new_line();
+        let old_row = BUFFER_HEIGHT - 2;
+        let new_row = BUFFER_HEIGHT - 1;
+        let blank = ScreenChar::blank(self.color_code);
+        for (new_col, old_col) in (word_start..BUFFER_WIDTH).enumerate() {
+            let character = self.read_cell(old_row, old_col);
+            self.write_cell(new_row, new_col, character);
+            self.write_cell(old_row, old_col, blank);
+        }
+        self.column_position = BUFFER_WIDTH - word_start;
+        self.word_start = Some(0);
+    }
+
+    /// Move to new line, essentially do what you expect for '\n'.
+    fn new_line(&mut self) {
+        self.scroll(1);
+        self.column_position = 0;
+    }
+
+    /// Move every row up by `n` rows, in a single pass over the buffer,
+    /// and blank the `n` rows at the bottom like [Self::new_line] does.
+    /// Values of `n` larger than the screen clear all of it. The column
+    /// the next character goes to stays the same.
+    ///
+    /// The rows that move off the top are added to the scrollback.
+    pub fn scroll(&mut self, n: usize) {
+        self.follow_output();
+        self.hide_cursor();
+        let n = n.min(BUFFER_HEIGHT);
+        for row in 0..n {
+            let mut line = [ScreenChar::BLANK; BUFFER_WIDTH];
+            for (col, cell) in line.iter_mut().enumerate() {
+                *cell = self.read_cell(row, col);
+            }
+            self.scrollback.push(line);
+        }
+        for row in n..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                let character = self.read_cell(row, col);
+                self.write_cell(row - n, col, character);
+            }
+        }
+        for row in BUFFER_HEIGHT - n..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        // The word that was being written moved up, we can't wrap it
+        // anymore.
+        self.word_start = None;
+    }
+
+    /// Show `n` lines further back in the scrollback, or as far back as
+    /// it goes. Cells keep the colors they had when they scrolled off.
+    ///
+    /// Writing anything, or [Writer::scroll_down] all the way, goes back
+    /// to showing the current output.
+    pub fn scroll_up(&mut self, n: usize) {
+        let offset = (self.view_offset + n).min(self.scrollback.len);
+        if offset == self.view_offset {
+            return;
+        }
+        if self.view_offset == 0 {
+            self.hide_cursor();
+            for row in 0..BUFFER_HEIGHT {
+                for col in 0..BUFFER_WIDTH {
+                    self.live_screen[row][col] = self.read_cell(row, col);
+                }
+            }
+        }
+        self.view_offset = offset;
+        self.repaint_view();
+    }
+
+    /// Show `n` lines less far back in the scrollback, see
+    /// [Writer::scroll_up].
+    pub fn scroll_down(&mut self, n: usize) {
+        let offset = self.view_offset.saturating_sub(n);
+        if offset == self.view_offset {
+            return;
+        }
+        self.view_offset = offset;
+        self.repaint_view();
+    }
+
+    /// Go back to showing the current output if the screen shows the
+    /// scrollback. Must be called before writing to the screen.
+    fn follow_output(&mut self) {
+        self.scroll_down(self.view_offset);
+    }
+
+    /// Draw the screen as seen `view_offset` lines back.
+    fn repaint_view(&mut self) {
+        let history = self.scrollback.len;
+        for row in 0..BUFFER_HEIGHT {
+            let line = history - self.view_offset + row;
+            for col in 0..BUFFER_WIDTH {
+                let cell = if line < history {
+                    self.scrollback.line(line)[col]
+                }
+                else {
+                    self.live_screen[line - history][col]
+                };
+                self.write_cell(row, col, cell);
+            }
+        }
+    }
+
+    /// Blank the entire screen with the current color and start over at
+    /// the beginning of the bottom row.
+    pub fn clear_screen(&mut self) {
+        self.follow_output();
+        self.hide_cursor();
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.column_position = 0;
+        self.word_start = None;
+    }
+
+    /// Blank `row` with the current color, or with the scroll fill
+    /// color as background if one is set.
+    fn clear_row(&mut self, row: usize) {
+        let color_code = match self.scroll_fill_color {
+            Some(fill) => ColorCode::from_byte(
+                (fill as u8) << 4 | (self.color_code.0 & 0x0f),
+            ),
+            None => self.color_code,
+        };
+        let blank = ScreenChar::blank(color_code);
+        for col in 0..BUFFER_WIDTH {
+            self.write_cell(row, col, blank);
+        }
+    }
+}
+
+/// Pass each cell of `shadow` that is marked in `dirty` to `write`, and
+/// mark them as clean.
+fn flush_dirty(
+    shadow: &[[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    dirty: &mut [u128; BUFFER_HEIGHT],
+    mut write: impl FnMut(usize, usize, ScreenChar),
+) {
+    for (row, dirty) in dirty.iter_mut().enumerate() {
+        while *dirty != 0 {
+            let col = dirty.trailing_zeros() as usize;
+            write(row, col, shadow[row][col]);
+            *dirty &= *dirty - 1;
+        }
+    }
+}
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_string(s);
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_capture() {
+    start_capture();
+    print!("hello\nworld");
+    let output = stop_capture();
+    assert_eq!(output.as_str(), Some("hello\nworld"));
+
+    // Nothing is recorded after stopping.
+    print!("more");
+    assert_eq!(stop_capture().as_bytes(), b"");
+}
+
+#[test_case]
+fn test_println_simple() {
+    println!("test_println_simple output");
+}
+
+#[test_case]
+fn test_println_many() {
+    for _ in 0..200 {
+        println!("test_println_many output");
+    }
+}
+
+#[test_case]
+fn test_try_println() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let _writer = WRITER.lock();
+        assert!(!try_println!("test_try_println locked output"));
+    });
+    assert!(try_println!("test_try_println output"));
+}
+
+#[test_case]
+fn test_software_cursor() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_string("\nab");
+
+        let row = BUFFER_HEIGHT - 1;
+        let col = writer.column_position;
+        let original = ScreenChar {
+            ascii_character: b'x',
+            color_code: ColorCode::new(Color::Yellow, Color::Blue),
+        };
+        writer.buffer.write(row, col, original);
+
+        writer.set_software_cursor(CursorStyle::Block);
+        writer.toggle_cursor();
+        let cursor = writer.buffer.read(row, col);
+        assert_eq!(cursor.ascii_character, b'x');
+        assert_eq!(
+            cursor.color_code,
+            ColorCode::new(Color::Blue, Color::Yellow)
+        );
+
+        writer.toggle_cursor();
+        assert_eq!(writer.buffer.read(row, col), original);
+
+        // Writing while the cursor is drawn must not leave it behind
+        // on the old cell.
+        writer.toggle_cursor();
+        writer.write_byte(b'c');
+        let written = writer.buffer.read(row, col);
+        assert_eq!(written.ascii_character, b'c');
+        assert_eq!(written.color_code, writer.color_code);
+
+        writer.set_software_cursor(CursorStyle::Off);
+    });
+}
+
+#[test_case]
+fn test_newline_mode() {
+    use x86_64::instructions::interrupts;
+
+    let row = |writer: &Writer, row: usize| {
+        let mut chars = [0; 3];
+        for (col, c) in chars.iter_mut().enumerate() {
+            *c = writer.buffer.read(row, col).ascii_character;
+        }
+        chars
+    };
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.set_newline_mode(NewlineMode::CrLfAsOne);
+        writer.write_string("\na\r\nb");
+        assert_eq!(row(&writer, BUFFER_HEIGHT - 2), *b"a  ");
+        assert_eq!(row(&writer, BUFFER_HEIGHT - 1), *b"b  ");
+
+        // A lone \r is kept.
+        writer.write_string("\na\rb");
+        assert_eq!(row(&writer, BUFFER_HEIGHT - 1), [b'a', 0xfe, b'b']);
+
+        writer.set_newline_mode(NewlineMode::CrAsNewline);
+        writer.write_string("\na\rb\r\nc");
+        assert_eq!(row(&writer, BUFFER_HEIGHT - 3), *b"a  ");
+        assert_eq!(row(&writer, BUFFER_HEIGHT - 2), *b"b  ");
+        assert_eq!(row(&writer, BUFFER_HEIGHT - 1), *b"c  ");
+
+        writer.set_newline_mode(NewlineMode::Literal);
+        writer.write_string("\na\r\nb");
+        assert_eq!(row(&writer, BUFFER_HEIGHT - 2), [b'a', 0xfe, b' ']);
+    });
+}
+
+#[test_case]
+fn test_word_wrap() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.set_word_wrap(true);
+
+        // The word starts 3 columns before the end of the line.
+        writer.write_string("\n");
+        for _ in 0..BUFFER_WIDTH - 4 {
+            writer.write_byte(b'-');
+        }
+        writer.write_string(" word\n");
+
+        let row = BUFFER_HEIGHT - 3;
+        for col in BUFFER_WIDTH - 3..BUFFER_WIDTH {
+            let screen_char = writer.buffer.read(row, col);
+            assert_eq!(screen_char.ascii_character, b' ');
+        }
+        for (col, &byte) in b"word".iter().enumerate() {
+            let screen_char = writer.buffer.read(row + 1, col);
+            assert_eq!(screen_char.ascii_character, byte);
+        }
+
+        // A word that doesn't fit in a line at all is broken anyway.
+        for _ in 0..BUFFER_WIDTH + 1 {
+            writer.write_byte(b'x');
+        }
+        assert_eq!(writer.column_position, 1);
+
+        writer.set_word_wrap(false);
+    });
+}
+
+#[test_case]
+fn test_scroll_preserves_colors() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let colors = [
+            ColorCode::new(Color::Red, Color::Black),
+            ColorCode::new(Color::Green, Color::Blue),
+            ColorCode::new(Color::White, Color::Magenta),
+        ];
+        let original_color = writer.color_code;
+
+        writer.write_string("\n");
+        for &color_code in colors.iter() {
+            writer.color_code = color_code;
+            writer.write_byte(b'c');
+        }
+        writer.color_code = original_color;
+        writer.set_scroll_fill_color(Color::Cyan);
+        writer.write_string("\n");
+        writer.clear_scroll_fill_color();
+
+        for (col, &color_code) in colors.iter().enumerate() {
+            let screen_char = writer.buffer.read(BUFFER_HEIGHT - 2, col);
+            assert_eq!(screen_char.ascii_character, b'c');
+            assert_eq!(screen_char.color_code, color_code);
+        }
+        let fill = ColorCode::from_byte(
+            (Color::Cyan as u8) << 4 | (original_color.0 & 0x0f),
+        );
+        for col in 0..BUFFER_WIDTH {
+            let screen_char = writer.buffer.read(BUFFER_HEIGHT - 1, col);
+            assert_eq!(screen_char.color_code, fill);
+        }
+    });
+}
+
+#[test_case]
+fn test_scroll_multiple_lines() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.clear_screen();
+        for row in 0..BUFFER_HEIGHT {
+            let character = ScreenChar {
+                ascii_character: b'a' + row as u8,
+                color_code: writer.color_code,
+            };
+            writer.write_cell(row, 0, character);
+        }
+
+        writer.scroll(3);
+
+        for row in 0..BUFFER_HEIGHT - 3 {
+            let screen_char = writer.buffer.read(row, 0);
+            assert_eq!(screen_char.ascii_character, b'a' + row as u8 + 3);
+        }
+        for row in BUFFER_HEIGHT - 3..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                let screen_char = writer.buffer.read(row, col);
+                assert_eq!(screen_char.ascii_character, b' ');
+                assert_eq!(screen_char.color_code, writer.color_code);
+            }
+        }
+
+        writer.scroll(BUFFER_HEIGHT + 1);
+        let screen_char = writer.buffer.read(0, 0);
+        assert_eq!(screen_char.ascii_character, b' ');
+    });
+}
+
+/// Scrolling while the software cursor is drawn must move the cell
+/// that was under it, not the cursor, and must not restore that cell
+/// over the blank row that scrolls in.
+#[test_case]
+fn test_scroll_with_cursor() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_string("\nab");
+
+        let row = BUFFER_HEIGHT - 1;
+        let col = writer.column_position;
+        let original = ScreenChar {
+            ascii_character: b'x',
+            color_code: ColorCode::new(Color::Yellow, Color::Blue),
+        };
+        writer.buffer.write(row, col, original);
+
+        writer.set_software_cursor(CursorStyle::Block);
+        writer.toggle_cursor();
+        writer.scroll(1);
+        assert_eq!(writer.buffer.read(row - 1, col), original);
+        assert!(writer.cursor_cell.is_none());
+
+        // Turning the cursor off hides it, which must not write the old
+        // cell back either.
+        writer.set_software_cursor(CursorStyle::Off);
+        let blank = writer.buffer.read(row, col);
+        assert_eq!(blank.ascii_character, b' ');
+        assert_eq!(blank.color_code, writer.color_code);
+    });
+}
+
+#[test_case]
+fn test_buffered_flush_writes_dirty_cells() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.set_buffered(true);
+
+        let row = BUFFER_HEIGHT - 1;
+        let original = writer.read_cell(row, 0);
+        let changed = ScreenChar {
+            ascii_character: original.ascii_character ^ 1,
+            ..original
+        };
+        writer.write_cell(row, 0, changed);
+        writer.write_cell(row, 5, changed);
+        // Rewriting a cell with its contents is not a change.
+        let unchanged = writer.read_cell(row, 7);
+        writer.write_cell(row, 7, unchanged);
+
+        let writer = &mut *writer;
+        let mut writes = 0;
+        let mut cols = [0; 2];
+        flush_dirty(&writer.shadow, &mut writer.dirty, |_, col, _| {
+            if writes < cols.len() {
+                cols[writes] = col;
+            }
+            writes += 1;
+        });
+        assert_eq!(writes, 2);
+        assert_eq!(cols, [0, 5]);
+
+        // Everything is clean after a flush.
+        flush_dirty(&writer.shadow, &mut writer.dirty, |_, _, _| {
+            panic!("cell flushed twice")
+        });
+        writer.set_buffered(false);
+    });
+}
+
+#[test_case]
+fn test_set_default_color() {
+    use x86_64::instructions::interrupts;
+
+    let green = ColorCode::new(Color::Green, Color::Black);
+    set_default_color(green);
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.clear_screen();
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                let screen_char = writer.read_cell(row, col);
+                assert_eq!(screen_char.ascii_character, b' ');
+                assert_eq!(screen_char.color_code, green);
+            }
+        }
+    });
+    set_default_color(DEFAULT_COLOR);
+}
+
+#[test_case]
+fn test_bright_background() {
+    assert_eq!(ColorCode::new(Color::White, Color::LightBlue).0, 0x1f);
+
+    set_blink_enabled(false);
+    let bright = ColorCode::new(Color::White, Color::LightBlue);
+    set_blink_enabled(true);
+    assert_eq!(bright.0, 0x9f);
+}
+
+#[test_case]
+fn test_try_write_fmt_propagates_errors() {
+    assert_eq!(try_write_fmt(format_args!("{}", 42)), Ok(()));
+    assert_eq!(
+        try_write_fmt(format_args!("{}", crate::FailingDisplay)),
+        Err(fmt::Error)
+    );
+}
+
+#[test_case]
+fn test_println_output() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    let s = "Some test string that fits on a single line";
+    // Disable interrupts and keep the writer locked for the entire
     // duration of the test. Otherwise we might have output from
     // another thread interfere with the test.
     interrupts::without_interrupts(|| {
@@ -242,8 +1904,405 @@ fn test_println_output() {
         // there must be no leading characters from a previous print.
         writeln!(writer, "\n{}", s).expect("writeln failed");
         for (i, c) in s.chars().enumerate() {
-            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
+            let screen_char = writer.buffer.read(BUFFER_HEIGHT - 2, i);
             assert_eq!(char::from(screen_char.ascii_character), c);
         }
     });
 }
+
+#[test_case]
+fn test_write_raw() {
+    use x86_64::instructions::interrupts;
+
+    let bytes = [0xb0, 0xb1, 0xb2];
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+
+        // Start from an empty line so that the bytes end up at the
+        // start of the last row.
+        writer.write_byte(b'\n');
+        writer.write_raw(&bytes);
+        for (i, &byte) in bytes.iter().enumerate() {
+            let screen_char = writer.buffer.read(BUFFER_HEIGHT - 1, i);
+            assert_eq!(screen_char.ascii_character, byte);
+        }
+    });
+}
+
+/// The VGA hardware reads each cell as the character byte followed by
+/// the attribute byte.
+#[test_case]
+fn test_screen_char_layout() {
+    use core::mem;
+
+    let color = ColorCode::new(Color::Yellow, Color::Blue);
+    let character = ScreenChar::new(b'x', color);
+
+    assert_eq!(mem::size_of::<ScreenChar>(), 2);
+    assert_eq!(mem::align_of::<ScreenChar>(), 1);
+
+    let base = &character as *const ScreenChar as usize;
+    let ascii = &character.ascii_character as *const u8 as usize;
+    let attribute = &character.color_code as *const ColorCode as usize;
+    assert_eq!(ascii - base, 0);
+    assert_eq!(attribute - base, 1);
+
+    let bytes: [u8; 2] = unsafe { mem::transmute(character) };
+    assert_eq!(bytes, [b'x', color.0]);
+    let round_trip: ScreenChar = unsafe { mem::transmute(bytes) };
+    assert_eq!(round_trip, character);
+
+    assert_eq!(ScreenChar::blank(color), ScreenChar::new(b' ', color));
+}
+
+/// Snapshot while the timer interrupt repaints the whole screen with a
+/// different character every time. A torn snapshot would mix two of
+/// them.
+#[test_case]
+fn test_snapshot_is_consistent() {
+    use core::sync::atomic::AtomicU8;
+    use x86_64::structures::idt::InterruptStackFrame;
+
+    static FRAMES: AtomicU8 = AtomicU8::new(0);
+
+    extern "x86-interrupt" fn repaint(_stack_frame: InterruptStackFrame) {
+        use crate::interrupts::{InterruptIndex, PICS};
+
+        if let Some(mut writer) = WRITER.try_lock() {
+            let frame = FRAMES.fetch_add(1, Ordering::SeqCst);
+            let character =
+                ScreenChar::new(b'a' + frame % 26, writer.color_code);
+            for row in 0..BUFFER_HEIGHT {
+                for col in 0..BUFFER_WIDTH {
+                    writer.write_cell(row, col, character);
+                }
+            }
+        }
+        unsafe {
+            PICS.lock()
+                .notify_end_of_interrupt(InterruptIndex::Timer as u8);
+        }
+    }
+
+    crate::with_custom_idt(
+        |idt| {
+            idt[usize::from(crate::interrupts::InterruptIndex::Timer as u8)]
+                .set_handler_fn(repaint);
+        },
+        || {
+            // The screen only has a single character once painted.
+            while FRAMES.load(Ordering::SeqCst) == 0 {
+                x86_64::instructions::hlt();
+            }
+            while FRAMES.load(Ordering::SeqCst) < 3 {
+                Writer::with_snapshot(|snapshot| {
+                    let first = snapshot.cell(0, 0);
+                    for row in 0..BUFFER_HEIGHT {
+                        assert!(snapshot.row(row).iter().all(|&c| c == first));
+                    }
+                });
+            }
+        },
+    );
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        WRITER.lock().clear_screen();
+    });
+}
+
+#[test_case]
+fn test_save_region() {
+    use x86_64::instructions::interrupts;
+
+    let color = ColorCode::new(Color::White, Color::Blue);
+    let original = |row: usize, col: usize| {
+        ScreenChar::new(b'a' + (row * 7 + col) as u8 % 26, color)
+    };
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        for row in 0..6 {
+            for col in 0..12 {
+                writer.write_cell(row, col, original(row, col));
+            }
+        }
+    });
+
+    let guard = interrupts::without_interrupts(|| {
+        WRITER.lock().save_region(1, 2, 4, 8)
+    });
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        for row in 0..6 {
+            for col in 0..12 {
+                writer.write_cell(row, col, ScreenChar::new(b'#', color));
+            }
+        }
+    });
+    drop(guard);
+
+    interrupts::without_interrupts(|| {
+        let writer = WRITER.lock();
+        for row in 0..6 {
+            for col in 0..12 {
+                let inside = (1..5).contains(&row) && (2..10).contains(&col);
+                let expected = if inside {
+                    original(row, col)
+                }
+                else {
+                    ScreenChar::new(b'#', color)
+                };
+                assert_eq!(writer.read_cell(row, col), expected);
+            }
+        }
+    });
+}
+
+#[test_case]
+fn test_scrollback_keeps_colors() {
+    use x86_64::instructions::interrupts;
+
+    let lines = [
+        ("red", ColorCode::new(Color::Red, Color::Black)),
+        ("green", ColorCode::new(Color::Green, Color::Blue)),
+        ("blue", ColorCode::new(Color::Blue, Color::Magenta)),
+    ];
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let original_color = writer.color_code;
+        writer.clear_screen();
+        for &(text, color_code) in lines.iter() {
+            writer.color_code = color_code;
+            writer.write_string(text);
+            // The row that comes in after the last line is blank.
+            if text == "blue" {
+                writer.set_scroll_fill_color(Color::Cyan);
+            }
+            writer.write_byte(b'\n');
+        }
+        writer.clear_scroll_fill_color();
+        writer.color_code = original_color;
+        for _ in 0..BUFFER_HEIGHT {
+            writer.write_byte(b'\n');
+        }
+
+        // The lines and the blank row are the last to have scrolled off.
+        writer.scroll_up(lines.len() + 1);
+        for (row, &(text, color_code)) in lines.iter().enumerate() {
+            for (col, byte) in text.bytes().enumerate() {
+                let screen_char = writer.buffer.read(row, col);
+                assert_eq!(screen_char, ScreenChar::new(byte, color_code));
+            }
+        }
+        let fill = ColorCode::from_byte(
+            (Color::Cyan as u8) << 4 | (Color::Blue as u8),
+        );
+        for col in 0..BUFFER_WIDTH {
+            let screen_char = writer.buffer.read(lines.len(), col);
+            assert_eq!(screen_char, ScreenChar::blank(fill));
+        }
+
+        // Back to the current output, which is blank.
+        writer.scroll_down(lines.len() + 1);
+        for row in 0..BUFFER_HEIGHT {
+            let screen_char = writer.buffer.read(row, 0);
+            assert_eq!(screen_char, ScreenChar::blank(original_color));
+        }
+
+        // Writing while paged back shows the current output again.
+        writer.scroll_up(1);
+        writer.write_byte(b'x');
+        assert_eq!(writer.view_offset, 0);
+        let screen_char = writer.buffer.read(BUFFER_HEIGHT - 1, 0);
+        assert_eq!(screen_char, ScreenChar::new(b'x', original_color));
+        writer.clear_screen();
+    });
+}
+
+#[test_case]
+fn test_set_cell() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let column_position = writer.column_position;
+        let red = ColorCode::new(Color::Red, Color::Black);
+        assert_ne!(writer.color_code, red);
+
+        writer.set_cell(10, 10, ScreenChar::new(b'X', red));
+        assert_eq!(writer.buffer.read(10, 10), ScreenChar::new(b'X', red));
+        assert_eq!(writer.char_at(10, 10), ScreenChar::new(b'X', red));
+        assert_eq!(writer.column_position, column_position);
+        writer.clear_screen();
+    });
+}
+
+#[test_case]
+fn test_indent() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_string("\n");
+        writer.set_indent(2);
+        writer.write_string("a\nb");
+
+        let row = BUFFER_HEIGHT - 2;
+        assert_eq!(writer.buffer.read(row, 0).ascii_character, b'a');
+        for (col, &byte) in b"  b".iter().enumerate() {
+            let screen_char = writer.buffer.read(row + 1, col);
+            assert_eq!(screen_char.ascii_character, byte);
+        }
+
+        // Nested levels add up, and an empty line stays empty.
+        writer.push_indent(3);
+        writer.write_string("\n\nc");
+        assert_eq!(writer.buffer.read(row, 0).ascii_character, b' ');
+        let col = writer.column_position - 1;
+        assert_eq!(col, 5);
+        let screen_char = writer.buffer.read(row + 1, col);
+        assert_eq!(screen_char.ascii_character, b'c');
+
+        writer.pop_indent(3);
+        writer.write_string("\nd");
+        assert_eq!(writer.column_position, 3);
+
+        writer.set_indent(0);
+        writer.write_string("\n");
+    });
+}
+
+#[test_case]
+fn test_draw_table() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.clear_screen();
+        writer.draw_table(2, 5, &[3, 4], &[&["ab", "cdefgh"], &["x", "y"]]);
+
+        let row = |writer: &Writer, row: usize| {
+            let mut bytes = [0; 10];
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                *byte = writer.char_at(row, 5 + i).ascii_character;
+            }
+            bytes
+        };
+        let [h, v] = [BOX_HORIZONTAL, BOX_VERTICAL];
+        assert_eq!(row(&writer, 2), [0xda, h, h, h, 0xc2, h, h, h, h, 0xbf]);
+        assert_eq!(
+            row(&writer, 3),
+            [v, b'a', b'b', b' ', v, b'c', b'd', b'e', TRUNCATED, v]
+        );
+        assert_eq!(row(&writer, 4), [0xc3, h, h, h, 0xc5, h, h, h, h, 0xb4]);
+        assert_eq!(
+            row(&writer, 5),
+            [v, b'x', b' ', b' ', v, b'y', b' ', b' ', b' ', v]
+        );
+        assert_eq!(row(&writer, 6), [0xc0, h, h, h, 0xc1, h, h, h, h, 0xd9]);
+
+        // Drawing off the edge of the screen doesn't scroll.
+        writer.draw_table(BUFFER_HEIGHT - 1, BUFFER_WIDTH - 2, &[5], &[&["z"]]);
+        assert_eq!(
+            writer
+                .char_at(BUFFER_HEIGHT - 1, BUFFER_WIDTH - 1)
+                .ascii_character,
+            h
+        );
+        assert_eq!(writer.char_at(2, 5).ascii_character, 0xda);
+        writer.clear_screen();
+    });
+}
+
+#[test_case]
+fn test_writer_uses_vga_buffer() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        assert_eq!(writer.buffer.0 as usize, 0xb8000);
+
+        writer.write_string("\nvga");
+        let cells = 0xb8000 as *const ScreenChar;
+        let row = BUFFER_HEIGHT - 1;
+        for (col, &byte) in b"vga".iter().enumerate() {
+            let cell =
+                unsafe { cells.add(row * BUFFER_WIDTH + col).read_volatile() };
+            assert_eq!(cell, ScreenChar::new(byte, writer.color_code));
+            assert_eq!(writer.buffer.read(row, col), cell);
+        }
+    });
+}
+
+#[test_case]
+fn test_max_line() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_string("\n");
+        writer.set_max_line(Some(10));
+        writer.write_string("abcdefghijklmnopqrst");
+
+        let row = BUFFER_HEIGHT - 1;
+        let cell =
+            |writer: &Writer, col| writer.char_at(row, col).ascii_character;
+        for (col, &byte) in b"abcdefghij".iter().enumerate() {
+            assert_eq!(cell(&writer, col), byte);
+        }
+        assert_eq!(cell(&writer, 10), TRUNCATED);
+        for col in 11..20 {
+            assert_eq!(cell(&writer, col), b' ');
+        }
+        assert_eq!(writer.column_position, 11);
+
+        // The next line starts over.
+        writer.write_string("\nxy");
+        assert_eq!(cell(&writer, 0), b'x');
+        assert_eq!(writer.column_position, 2);
+
+        // A limit of the screen width still wraps.
+        writer.set_max_line(Some(BUFFER_WIDTH));
+        writer.write_string("\n");
+        for _ in 0..BUFFER_WIDTH {
+            writer.write_byte(b'a');
+        }
+        writer.write_string("bc");
+        assert_eq!(cell(&writer, 0), b'b');
+        assert_eq!(cell(&writer, 1), b'c');
+        assert_eq!(
+            writer.char_at(row - 1, BUFFER_WIDTH - 1).ascii_character,
+            b'a'
+        );
+        assert_eq!(writer.column_position, 2);
+
+        writer.set_max_line(None);
+        writer.write_string("\n");
+    });
+}
+
+#[test_case]
+fn test_current_line() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_string("\nhello");
+
+        let mut buf = [0; BUFFER_WIDTH];
+        let len = writer.current_line(&mut buf);
+        assert_eq!(&buf[..len], b"hello");
+
+        let mut short = [0; 3];
+        assert_eq!(writer.current_line(&mut short), 3);
+        assert_eq!(&short, b"hel");
+
+        writer.write_string(" w\u{f6}rld");
+        let len = writer.current_line(&mut buf);
+        assert_eq!(&buf[..len], b"hello w??rld");
+
+        writer.write_string("\n");
+        assert_eq!(writer.current_line(&mut buf), 0);
+    });
+}
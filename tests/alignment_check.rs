@@ -0,0 +1,55 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use blog_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use core::arch::asm;
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+lazy_static! {
+    /// Custom IDT for this test, so that the alignment check handler
+    /// can return a success exit code.
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.alignment_check.set_handler_fn(test_alignment_check_handler);
+        idt
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("alignment_check::alignment_check...\t");
+
+    TEST_IDT.load();
+    raise_alignment_check();
+
+    panic!("Execution continued after alignment check");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// Deliver an alignment check exception to the handler.
+///
+/// A misaligned access with CR0.AM and RFLAGS.AC set only faults in
+/// ring 3, and the kernel always runs in ring 0. So we raise the vector
+/// with `int` instead. Note that this doesn't push an error code, so
+/// the handler must not rely on its arguments.
+fn raise_alignment_check() {
+    unsafe {
+        asm!("int 17");
+    }
+}
+
+extern "x86-interrupt" fn test_alignment_check_handler(
+    _stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    blog_os::hlt_loop()
+}
@@ -0,0 +1,72 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use blog_os::allocator::{self, HEAP_SIZE};
+use blog_os::log_buffer::LogBuffer;
+use blog_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use spin::Mutex;
+
+/// What the alloc error handler reported.
+static REPORT: Mutex<LogBuffer<1024>> = Mutex::new(LogBuffer::new());
+
+fn capture(s: &str) {
+    REPORT.lock().write_bytes(s.as_bytes());
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    serial_print!("alloc_error::stats_reported_before_panic...\t");
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("Heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    allocator::set_alloc_error_output(capture);
+    let vec = Vec::<u8>::with_capacity(2 * HEAP_SIZE);
+
+    serial_println!("[failed]\n");
+    serial_println!("Error: allocation succeeded at {:p}", vec.as_ptr());
+    exit_qemu(QemuExitCode::Failed);
+    blog_os::hlt_loop()
+}
+
+/// By the time we panic, the handler must have reported the stats.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let report = REPORT.lock();
+    let (first, second) = report.contents();
+    let mut bytes = [0; 1024];
+    bytes[..first.len()].copy_from_slice(first);
+    bytes[first.len()..first.len() + second.len()].copy_from_slice(second);
+    let report = &bytes[..first.len() + second.len()];
+
+    let expected: [&[u8]; 4] =
+        [b"failed", b"--- heap stats", b"used: ", b"free blocks:"];
+    for text in expected {
+        if !report.windows(text.len()).any(|w| w == text) {
+            serial_println!("[failed]\n");
+            serial_println!("Error: report is missing {:?}", text);
+            serial_println!("{}", info);
+            exit_qemu(QemuExitCode::Failed);
+            blog_os::hlt_loop();
+        }
+    }
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    blog_os::hlt_loop()
+}
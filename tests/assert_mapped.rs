@@ -0,0 +1,71 @@
+#![no_std]
+#![no_main]
+
+use blog_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use bootloader::{entry_point, BootInfo};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+
+/// Nothing maps this, see `tests/memory.rs` for the addresses in use.
+const UNMAPPED: u64 = 0xdead_0000_0000;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("assert_mapped::assert_mapped_panics...\t");
+    blog_os::boot_init(boot_info);
+
+    if !cfg!(debug_assertions) {
+        // The assertion is compiled out, so there's nothing to test.
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    }
+
+    blog_os::assert_mapped!(VirtAddr::new(UNMAPPED));
+
+    serial_println!("[failed]\n");
+    serial_println!("Error: assert_mapped! accepted an unmapped address");
+    exit_qemu(QemuExitCode::Failed);
+    blog_os::hlt_loop()
+}
+
+/// Holds the start of the panic message.
+struct Message {
+    bytes: [u8; 256],
+    len: usize,
+}
+
+impl fmt::Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + len]
+            .copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/// We must get here through the assertion, not through some other
+/// panic.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message = Message {
+        bytes: [0; 256],
+        len: 0,
+    };
+    let _ = write!(message, "{}", info);
+    let expected = b"is not mapped";
+    let message = &message.bytes[..message.len];
+
+    if message.windows(expected.len()).any(|w| w == expected) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    }
+    else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: unexpected panic: {}", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    blog_os::hlt_loop()
+}
@@ -0,0 +1,95 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use blog_os::allocator::{self, Backend, HEAP_SIZE, HEAP_START};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    allocator::set_backend(Backend::Bump);
+    blog_os::boot_init(boot_info);
+    test_main();
+
+    blog_os::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn bump_backend_selected() {
+    assert_eq!(allocator::backend(), Backend::Bump);
+}
+
+/// Allocate until the bump allocator runs out of memory, free
+/// everything, and check that it starts over from the start of the
+/// heap.
+#[test_case]
+fn bump_exhaust_and_reset() {
+    const BLOCK_SIZE: usize = 1024;
+    const MAX_BLOCKS: usize = HEAP_SIZE / BLOCK_SIZE + 1;
+
+    let layout = Layout::from_size_align(BLOCK_SIZE, 8).unwrap();
+    let mut blocks = [core::ptr::null_mut(); MAX_BLOCKS];
+    let mut count = 0;
+
+    loop {
+        let ptr = unsafe { alloc(layout) };
+        if ptr.is_null() {
+            break;
+        }
+        assert!(count < MAX_BLOCKS, "Allocated more than the heap size");
+        blocks[count] = ptr;
+        count += 1;
+    }
+    assert_eq!(count, HEAP_SIZE / BLOCK_SIZE);
+
+    for &ptr in &blocks[..count] {
+        unsafe { dealloc(ptr, layout) };
+    }
+
+    let ptr = unsafe { alloc(layout) };
+    assert_eq!(ptr as usize, HEAP_START);
+    unsafe { dealloc(ptr, layout) };
+}
+
+/// Allocate without the lock, then switch back and check that the
+/// allocator carries on where it left off.
+#[test_case]
+fn bump_unlocked_allocations() {
+    use alloc::boxed::Box;
+    use x86_64::instructions::interrupts;
+
+    let (unlocked, value) = interrupts::without_interrupts(|| {
+        unsafe { allocator::begin_single_threaded() };
+        assert!(allocator::is_single_threaded());
+
+        let first = Box::new(1_u64);
+        let second = Box::new([2_u16; 3]);
+        assert!(*first == 1 && *second == [2; 3]);
+        let addr = &*second as *const _ as usize;
+        assert!(addr > &*first as *const _ as usize);
+        assert_eq!(addr % core::mem::align_of::<u16>(), 0);
+        drop(first);
+
+        allocator::end_single_threaded();
+        assert!(!allocator::is_single_threaded());
+        (addr, second)
+    });
+
+    // The allocation from before is still alive, so the next one must
+    // come after it.
+    let locked = Box::new(3_u8);
+    assert!(&*locked as *const u8 as usize > unlocked);
+    assert_eq!(*value, [2; 3]);
+}
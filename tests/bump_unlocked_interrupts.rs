@@ -0,0 +1,54 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use blog_os::allocator::{self, Backend};
+use blog_os::{exit_qemu, serial, serial_print, serial_println, QemuExitCode};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+/// Skip the lock of the allocator and then enable interrupts without
+/// ending single threaded mode. The next allocation must panic instead
+/// of racing with the interrupt handlers.
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("bump_unlocked_interrupts::alloc_panics...\t");
+
+    allocator::set_backend(Backend::Bump);
+    blog_os::boot_init(boot_info);
+
+    x86_64::instructions::interrupts::disable();
+    unsafe { allocator::begin_single_threaded() };
+    x86_64::instructions::interrupts::enable();
+    let value = Box::new(42);
+
+    serial_println!("[failed]\n");
+    serial_println!("Error: allocated {} with interrupts enabled", value);
+    exit_qemu(QemuExitCode::Failed);
+    blog_os::hlt_loop()
+}
+
+/// We must get here through the assertion in the allocator, not
+/// through some other panic.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial::redirect_to_buffer();
+    serial_println!("{}", info);
+    let output = serial::captured();
+    serial::stop_redirect();
+
+    let expected = "Interrupts enabled before allocator::end_single_threaded";
+    let found = output
+        .as_str()
+        .map_or(false, |output| output.contains(expected));
+    if !found {
+        blog_os::test_panic_handler(info);
+    }
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    blog_os::hlt_loop()
+}
@@ -0,0 +1,37 @@
+#![no_std]
+#![no_main]
+
+use blog_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use core::panic::PanicInfo;
+
+const BUFFER_WIDTH: usize = 80;
+
+/// The first call writes to the top row.
+const TEXT: &str = "early_print before init";
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    // This must come before anything else, in particular before the
+    // serial port is initialized by the first print.
+    blog_os::early_print(TEXT);
+
+    serial_print!("early_print::early_print_before_init...\t");
+    let buffer = 0xb8000 as *const u16;
+    for (col, byte) in TEXT.bytes().enumerate() {
+        let ascii = unsafe { buffer.add(col).read_volatile() } as u8;
+        assert_eq!(ascii, byte, "Wrong character in column {}", col);
+    }
+    let ascii = unsafe { buffer.add(TEXT.len()).read_volatile() } as u8;
+    assert_eq!(ascii, b' ');
+    assert!(TEXT.len() < BUFFER_WIDTH);
+
+    blog_os::init();
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    blog_os::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
@@ -0,0 +1,52 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use blog_os::interrupts::ExceptionReport;
+use blog_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use core::panic::PanicInfo;
+use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
+
+/// An address that nothing maps.
+const UNMAPPED_ADDR: u64 = 0x_7777_0000_1234;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("exception_report::page_fault...\t");
+
+    blog_os::init();
+    blog_os::with_custom_idt(
+        |idt| {
+            idt.page_fault.set_handler_fn(test_page_fault_handler);
+        },
+        || unsafe { (UNMAPPED_ADDR as *const u8).read_volatile() },
+    );
+
+    panic!("Execution continued after page fault");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let report = ExceptionReport::page_fault(&stack_frame, error_code);
+    if report.vector == 14
+        && report.cr2 == Some(UNMAPPED_ADDR)
+        && report.rip != 0
+        && report.error_code == Some(error_code.bits())
+    {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    }
+    else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: unexpected report {}", report);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    blog_os::hlt_loop()
+}
@@ -0,0 +1,74 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::{BootInfoFrameAllocator, CachedFrameAllocator};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
+
+const CAPACITY: usize = 16;
+
+/// The allocator under test. It is created in [main] because the tests
+/// can't take arguments.
+static CACHED: Mutex<Option<CachedFrameAllocator>> = Mutex::new(None);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os::{allocator, hlt_loop, memory};
+    use x86_64::VirtAddr;
+
+    blog_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("Heap initialization failed");
+
+    *CACHED.lock() = Some(frame_allocator.into_cached(CAPACITY));
+    test_main();
+
+    hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// Allocate every cached frame, check that it runs out, and check that
+/// a freed frame is handed out again.
+#[test_case]
+fn cached_allocate_free_exhaust() {
+    let mut cached = CACHED.lock();
+    let cached = cached.as_mut().unwrap();
+    assert_eq!(cached.free_frames(), CAPACITY);
+
+    let mut frames = [None; CAPACITY];
+    for frame in frames.iter_mut() {
+        *frame = Some(cached.allocate_frame().expect("Ran out of frames"));
+    }
+    assert_eq!(cached.allocate_frame(), None);
+
+    for (i, a) in frames.iter().enumerate() {
+        for b in &frames[i + 1..] {
+            assert_ne!(a, b, "Frame handed out twice");
+        }
+    }
+
+    let freed = frames[3].unwrap();
+    unsafe { cached.deallocate_frame(freed) };
+    assert_eq!(cached.allocate_frame(), Some(freed));
+    assert_eq!(cached.allocate_frame(), None);
+
+    for frame in frames.iter().flatten() {
+        unsafe { cached.deallocate_frame(*frame) };
+    }
+    assert_eq!(cached.free_frames(), CAPACITY);
+}
@@ -27,6 +27,7 @@ fn main(boot_info: &'static BootInfo) -> ! {
 
     allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("Heap initialization failed");
+    memory::install(mapper, frame_allocator);
 
     test_main();
 
@@ -78,3 +79,202 @@ fn many_boxes_long_lived() {
     }
     assert_eq!(*long_lived, 1);
 }
+
+/// Interleave large allocations of different sizes that are handled by
+/// the fallback allocator. After one of them is freed, an allocation of
+/// a different size but the same size class should reuse its region.
+#[test_case]
+fn large_allocation_reuses_same_class() {
+    let first = Vec::<u8>::with_capacity(3000);
+    let first_ptr = first.as_ptr();
+    let other = Vec::<u8>::with_capacity(5000);
+    drop(first);
+
+    let second = Vec::<u8>::with_capacity(3500);
+    assert_eq!(second.as_ptr(), first_ptr);
+    drop(other);
+}
+
+/// 2048 bytes is the largest block size and 4096 bytes goes to the
+/// fallback allocator. Both should give back the memory that was freed
+/// when the same size is requested again.
+#[test_case]
+fn block_and_fallback_boundary() {
+    use blog_os::allocator::fixed_size_block::BLOCK_SIZES;
+    use blog_os::allocator::size_histogram;
+
+    let last = BLOCK_SIZES.len() - 1;
+    let fallback = BLOCK_SIZES.len();
+
+    for &size in &[2048, 4096] {
+        let bucket = if size == 2048 { last } else { fallback };
+
+        let before = size_histogram()[bucket];
+        let first = Vec::<u8>::with_capacity(size);
+        let first_ptr = first.as_ptr();
+        drop(first);
+
+        let mut second = Vec::<u8>::with_capacity(size);
+        assert_eq!(second.as_ptr(), first_ptr, "{} bytes not reused", size);
+        second.resize(size, 0x55);
+        assert!(second.iter().all(|&byte| byte == 0x55));
+        drop(second);
+        assert_eq!(size_histogram()[bucket] - before, 2);
+    }
+}
+
+#[test_case]
+fn size_histogram_counts() {
+    use blog_os::allocator::fixed_size_block::BLOCK_SIZES;
+    use blog_os::allocator::size_histogram;
+
+    let index_32 = BLOCK_SIZES.iter().position(|&s| s == 32).unwrap();
+    let fallback = BLOCK_SIZES.len();
+
+    let before = size_histogram();
+    let small = [
+        Box::new([0u8; 32]),
+        Box::new([1u8; 32]),
+        Box::new([2u8; 32]),
+    ];
+    let large = Vec::<u8>::with_capacity(4096);
+    let after = size_histogram();
+    drop(small);
+    drop(large);
+
+    for (i, (before, after)) in before.iter().zip(after.iter()).enumerate() {
+        let expected = match i {
+            i if i == index_32 => 3,
+            i if i == fallback => 1,
+            _ => 0,
+        };
+        assert_eq!(after - before, expected, "Wrong count in bucket {}", i);
+    }
+}
+
+#[test_case]
+fn verify_heap_succeeds() {
+    use blog_os::{allocator, memory};
+
+    let result =
+        memory::with_mapper(|mapper, _| allocator::verify_heap(mapper));
+    assert_eq!(result, Ok(()));
+}
+
+/// Map only the first page of a two page range, so that the tail is
+/// missing.
+#[test_case]
+fn verify_heap_detects_unmapped_tail() {
+    use blog_os::allocator::{self, HeapError};
+    use blog_os::memory;
+    use x86_64::structures::paging::{Page, PageTableFlags};
+    use x86_64::VirtAddr;
+
+    let start = VirtAddr::new(0x_5555_5555_0000);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let result = memory::with_mapper(|mapper, frame_allocator| {
+        let page = Page::containing_address(start);
+        memory::create_mapping(page, flags, mapper, frame_allocator)
+            .expect("Mapping failed");
+        allocator::verify_heap_range(mapper, start, 2 * 4096)
+    });
+    assert_eq!(result, Err(HeapError::Unmapped(start + 4096u64)));
+}
+
+#[test_case]
+fn allocator_counters() {
+    let counters = blog_os::allocator::counters();
+    let allocs = counters.get("alloc").unwrap();
+    let deallocs = counters.get("dealloc").unwrap();
+
+    let value = Box::new(41);
+    // Make sure the allocation is not optimized out.
+    assert_eq!(unsafe { core::ptr::read_volatile(&*value) }, 41);
+    drop(value);
+
+    assert_eq!(counters.get("alloc"), Some(allocs + 1));
+    assert_eq!(counters.get("dealloc"), Some(deallocs + 1));
+}
+
+/// Allocate and free blocks of random sizes, and check that live blocks
+/// never overlap or get overwritten.
+#[test_case]
+fn random_alloc_and_free() {
+    use blog_os::rng::Rng;
+
+    const MAX_LIVE: usize = 32;
+
+    let baseline = blog_os::allocator::stats().used;
+    let mut rng = Rng::new(0x_b10c);
+    let mut live: Vec<(u8, Box<[u8]>)> = Vec::with_capacity(MAX_LIVE);
+
+    for i in 0..5_000 {
+        if live.len() == MAX_LIVE
+            || (!live.is_empty() && rng.gen_range(0, 2) == 0)
+        {
+            let index = rng.gen_range(0, live.len() as u64) as usize;
+            let (tag, block) = live.swap_remove(index);
+            assert!(block.iter().all(|&byte| byte == tag), "Block overwritten");
+            continue;
+        }
+
+        // Mostly small blocks, sometimes one for the fallback allocator.
+        let size = match rng.gen_range(0, 10) {
+            0 => rng.gen_range(2049, 4097),
+            _ => rng.gen_range(1, 1025),
+        } as usize;
+        let tag = i as u8;
+        let block = alloc::vec![tag; size].into_boxed_slice();
+
+        let start = block.as_ptr() as usize;
+        let end = start + block.len();
+        for (_, other) in &live {
+            let other_start = other.as_ptr() as usize;
+            let other_end = other_start + other.len();
+            assert!(
+                end <= other_start || other_end <= start,
+                "{:#x}..{:#x} overlaps {:#x}..{:#x}",
+                start,
+                end,
+                other_start,
+                other_end
+            );
+        }
+        live.push((tag, block));
+    }
+
+    for (tag, block) in live.drain(..) {
+        assert!(block.iter().all(|&byte| byte == tag), "Block overwritten");
+    }
+    drop(live);
+    assert_eq!(blog_os::allocator::stats().used, baseline);
+}
+
+/// A heap that would wrap around the end of the address space must be
+/// rejected before anything is mapped, instead of mapping the pages at
+/// the start of it.
+#[test_case]
+fn init_heap_rejects_overflowing_range() {
+    use blog_os::allocator::{self, HeapInitError};
+    use blog_os::memory;
+
+    let heap_start = 0xffff_ffff_ffff_f000;
+    let result = memory::with_mapper(|mapper, frame_allocator| {
+        allocator::init_heap_at(mapper, frame_allocator, heap_start, HEAP_SIZE)
+    });
+    assert!(matches!(
+        result,
+        Err(HeapInitError::InvalidRange { start, size: HEAP_SIZE })
+            if start == heap_start
+    ));
+
+    // Non-canonical addresses are rejected too.
+    let heap_start = 0x_8000_0000_0000;
+    let result = memory::with_mapper(|mapper, frame_allocator| {
+        allocator::init_heap_at(mapper, frame_allocator, heap_start, HEAP_SIZE)
+    });
+    assert!(matches!(result, Err(HeapInitError::InvalidRange { .. })));
+
+    // The real heap is still usable.
+    assert_eq!(*Box::new(42), 42);
+}
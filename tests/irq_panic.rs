@@ -0,0 +1,60 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use blog_os::vga_buffer::WRITER;
+use blog_os::{
+    exit_qemu, interrupts, serial, serial_print, serial_println, QemuExitCode,
+};
+use core::panic::PanicInfo;
+use x86_64::structures::idt::InterruptStackFrame;
+
+const TEST_VECTOR: u8 = 144;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("irq_panic::panic_in_handler_is_reported...\t");
+
+    blog_os::init();
+    interrupts::register(TEST_VECTOR, panicking_handler);
+
+    // The handler interrupts code that is in the middle of printing.
+    // `int` works with interrupts disabled, and the timer must not try
+    // to print while we hold the locks.
+    x86_64::instructions::interrupts::disable();
+    let locks = (WRITER.lock(), serial::SERIAL1.lock());
+    unsafe { core::arch::asm!("int 144") };
+
+    drop(locks);
+    serial_println!("[failed]\n");
+    serial_println!("Error: handler returned");
+    exit_qemu(QemuExitCode::Failed);
+    blog_os::hlt_loop()
+}
+
+extern "x86-interrupt" fn panicking_handler(_stack_frame: InterruptStackFrame) {
+    interrupts::isolate_panics(TEST_VECTOR, || {
+        let value: Option<u32> = None;
+        value.expect("nothing to handle");
+    });
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial::redirect_to_buffer();
+    let reported = interrupts::report_handler_panic(info);
+    let output = serial::captured();
+    serial::stop_redirect();
+
+    let expected = "panic in interrupt handler (vector 144): ";
+    let found = output.as_str().map_or(false, |output| {
+        output.starts_with(expected) && output.contains("nothing to handle")
+    });
+    if !reported || !found {
+        blog_os::test_panic_handler(info);
+    }
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    blog_os::hlt_loop()
+}
@@ -0,0 +1,49 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    blog_os::init();
+    test_main();
+    blog_os::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// qemu emulates a keyboard that acknowledges the LED command, so this
+/// only succeeds if the whole sequence was sent and answered.
+#[test_case]
+fn set_keyboard_leds_is_acknowledged() {
+    use blog_os::interrupts::set_keyboard_leds;
+
+    assert_eq!(set_keyboard_leds(true, false, false), Ok(()));
+    assert_eq!(set_keyboard_leds(false, true, false), Ok(()));
+}
+
+/// The ACKs of the LED command still raise the keyboard interrupt once
+/// interrupts are enabled again, but there is no scancode to decode.
+#[test_case]
+fn leds_ack_is_not_decoded() {
+    use blog_os::interrupts::{self, set_keyboard_leds};
+    use blog_os::keyboard;
+
+    while keyboard::pop_key().is_some() {}
+    let interrupts_before = interrupts::counters().get("keyboard").unwrap();
+    assert_eq!(set_keyboard_leds(false, false, false), Ok(()));
+    let deadline = interrupts::ticks() + 2;
+    while interrupts::ticks() < deadline {
+        x86_64::instructions::hlt();
+    }
+
+    let interrupts_after = interrupts::counters().get("keyboard").unwrap();
+    assert!(interrupts_after > interrupts_before);
+    assert_eq!(keyboard::pop_key(), None);
+}
@@ -0,0 +1,150 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::boot_init(boot_info);
+    test_main();
+
+    blog_os::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// Map an unused page through [memory::with_mapper] and make sure we
+/// can write to it and read back the value.
+#[test_case]
+fn with_mapper_creates_mapping() {
+    let page = Page::containing_address(VirtAddr::new(0xdead_beaf_000));
+    memory::with_mapper(|mapper, frame_allocator| {
+        let frame = frame_allocator
+            .allocate_frame()
+            .expect("Frame allocation failed");
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator) }
+            .expect("Mapping failed")
+            .flush();
+    });
+
+    let ptr: *mut u64 = page.start_address().as_mut_ptr();
+    unsafe {
+        ptr.write_volatile(0x_f021_f077_f065_f04e);
+        assert_eq!(ptr.read_volatile(), 0x_f021_f077_f065_f04e);
+    }
+}
+
+/// A writable mapping from [memory::create_mapping] can be written and
+/// read back.
+#[test_case]
+fn create_mapping_writable() {
+    let page = Page::containing_address(VirtAddr::new(0xdead_c0de_000));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    memory::with_mapper(|mapper, frame_allocator| {
+        memory::create_mapping(page, flags, mapper, frame_allocator)
+    })
+    .expect("Mapping failed");
+
+    let ptr: *mut u64 = page.start_address().as_mut_ptr();
+    unsafe {
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+    }
+}
+
+/// Mapping a physical address beyond what the CPU supports must fail
+/// instead of creating a mapping that faults on every access.
+#[test_case]
+fn identity_map_rejects_too_wide_address() {
+    let bits = blog_os::cpu::phys_addr_bits();
+    if bits >= 52 {
+        // Every representable address is valid.
+        return;
+    }
+
+    let start = PhysAddr::new(1 << bits);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let result = memory::with_mapper(|mapper, frame_allocator| unsafe {
+        memory::identity_map(start, 4096, flags, true, mapper, frame_allocator)
+    });
+    assert!(matches!(
+        result,
+        Err(memory::IdentityMapError::AddressTooWide(addr)) if addr == start
+    ));
+}
+
+/// A freshly mapped page passes [blog_os::assert_mapped]. See
+/// `tests/assert_mapped.rs` for the failing case.
+#[test_case]
+fn assert_mapped_accepts_mapped_page() {
+    let page = Page::containing_address(VirtAddr::new(0xdead_f00d_000));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    memory::with_mapper(|mapper, frame_allocator| {
+        memory::create_mapping(page, flags, mapper, frame_allocator)
+    })
+    .expect("Mapping failed");
+
+    blog_os::assert_mapped!(page.start_address());
+    blog_os::assert_mapped!(page.start_address() + 4095u64);
+    assert!(memory::with_mapper(|mapper, _| {
+        !memory::is_mapped(page.start_address() + 4096u64, mapper)
+    }));
+}
+
+/// A frame cloned with [memory::clone_page] is a different frame with
+/// the same contents.
+#[test_case]
+fn clone_page_copies_contents() {
+    memory::with_mapper(|mapper, frame_allocator| {
+        let offset = mapper.phys_offset();
+        let frame = |frame: PhysFrame| -> *mut u8 {
+            (offset + frame.start_address().as_u64()).as_mut_ptr()
+        };
+
+        let src = frame_allocator
+            .allocate_frame()
+            .expect("Frame allocation failed");
+        for i in 0..4096 {
+            unsafe { frame(src).add(i).write_volatile((i * 7 % 251) as u8) };
+        }
+
+        let clone = unsafe { memory::clone_page(src, frame_allocator, offset) }
+            .expect("Cloning failed");
+        assert_ne!(clone, src);
+        for i in 0..4096 {
+            let byte = unsafe { frame(clone).add(i).read_volatile() };
+            assert_eq!(byte, (i * 7 % 251) as u8, "Byte {}", i);
+        }
+    });
+}
+
+/// A zero offset is rejected before anything is created, so calling
+/// this again after boot_init is fine.
+#[test_case]
+fn try_init_rejects_zero_offset() {
+    let memory_map =
+        memory::with_mapper(|_, frame_allocator| frame_allocator.memory_map());
+
+    let result = unsafe { memory::try_init(VirtAddr::new(0), memory_map) };
+    assert_eq!(result.err(), Some(memory::OffsetError::Zero));
+
+    let result = unsafe { memory::try_init(VirtAddr::new(0x1000), memory_map) };
+    assert!(matches!(
+        result.err(),
+        Some(memory::OffsetError::BelowMemoryEnd { offset: 0x1000, .. })
+    ));
+}
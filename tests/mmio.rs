@@ -0,0 +1,43 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory;
+use blog_os::mmio::MmioRegion;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::FrameAllocator;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::boot_init(boot_info);
+    test_main();
+
+    blog_os::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// Map an unused frame as if it were a device and make sure that values
+/// written through a handle can be read back.
+#[test_case]
+fn mmio_write_read() {
+    let frame = memory::with_mapper(|_, frame_allocator| {
+        frame_allocator
+            .allocate_frame()
+            .expect("Frame allocation failed")
+    });
+    let region = unsafe { MmioRegion::map(frame.start_address(), 4096) }
+        .expect("Mapping failed");
+
+    let mut register = region.at::<u32>(8);
+    register.write(0xdead_beef);
+    assert_eq!(register.read(), 0xdead_beef);
+    assert_eq!(region.at::<u32>(8).read(), 0xdead_beef);
+}
@@ -0,0 +1,64 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use blog_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use core::arch::asm;
+use core::panic::PanicInfo;
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::InterruptStackFrame;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("overflow::overflow...\t");
+
+    blog_os::init();
+    blog_os::with_custom_idt(
+        |idt| {
+            idt.overflow.set_handler_fn(test_overflow_handler);
+        },
+        raise_overflow,
+    );
+
+    panic!("Execution continued after overflow");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// Overflow with the flag set, the way `into` would see it, then
+/// deliver the exception.
+///
+/// `into` only exists in 32-bit code, in long mode it raises an
+/// invalid opcode exception instead. So we raise the vector with `int`.
+fn raise_overflow() {
+    let mut value: i8 = i8::MAX;
+    let flags: u64;
+    // Read the flags in the same block, anything in between could
+    // change them.
+    unsafe {
+        asm!(
+            "add {value}, 1",
+            "pushfq",
+            "pop {flags}",
+            value = inout(reg_byte) value,
+            flags = out(reg) flags,
+        );
+    }
+    assert_eq!(value, i8::MIN);
+    assert!(RFlags::from_bits_truncate(flags).contains(RFlags::OVERFLOW_FLAG));
+
+    unsafe {
+        asm!("int 4");
+    }
+}
+
+extern "x86-interrupt" fn test_overflow_handler(
+    _stack_frame: InterruptStackFrame,
+) {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    blog_os::hlt_loop()
+}
@@ -0,0 +1,35 @@
+#![no_std]
+#![no_main]
+
+use blog_os::{
+    exit_qemu, serial_print, serial_println, DropFlag, QemuExitCode,
+};
+use core::panic::PanicInfo;
+
+/// Set if the guard that is alive during the panic is dropped.
+static DROPPED: DropFlag = DropFlag::new();
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("panic_abort::destructors_do_not_run...\t");
+
+    let _guard = DROPPED.guard();
+    panic!("Deliberate panic");
+}
+
+/// The kernel is built with `panic-strategy: abort`, so the stack must
+/// not be unwound on the way here. If it were, the guard in [_start]
+/// would have been dropped by now.
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    if DROPPED.dropped() {
+        serial_println!("[failed]\n");
+        serial_println!("Error: destructor ran during panic");
+        exit_qemu(QemuExitCode::Failed);
+    }
+    else {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    }
+    blog_os::hlt_loop()
+}
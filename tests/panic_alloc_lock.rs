@@ -0,0 +1,66 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use blog_os::{allocator, serial, vga_buffer};
+use blog_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+/// Panic inside the allocator, while it holds its lock. This relies on
+/// the fixed size block allocator checking the alignment of the blocks
+/// it gets back, which it only does in debug builds.
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("panic_alloc_lock::panic_with_allocator_locked...\t");
+
+    blog_os::boot_init(boot_info);
+    assert_eq!(allocator::backend(), allocator::Backend::FixedSizeBlock);
+
+    let layout = Layout::from_size_align(16, 8).unwrap();
+    unsafe {
+        let block = alloc(layout);
+        dealloc(block.add(8), layout);
+    }
+
+    fail("misaligned block was not detected")
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    allocator::set_panicking();
+
+    // The lock is still held, so this would hang if it tried to take
+    // it.
+    let block = unsafe { alloc(Layout::new::<u64>()) };
+    if !block.is_null() {
+        fail("allocated while panicking");
+    }
+
+    serial::redirect_to_buffer();
+    serial_println!("{}", info);
+    vga_buffer::print_panic(info);
+    let output = serial::captured();
+    serial::stop_redirect();
+
+    let reported = output
+        .as_str()
+        .map_or(false, |output| output.contains("assertion failed"));
+    if !reported {
+        fail("panic was not reported");
+    }
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    blog_os::hlt_loop()
+}
+
+fn fail(error: &str) -> ! {
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}", error);
+    exit_qemu(QemuExitCode::Failed);
+    blog_os::hlt_loop()
+}
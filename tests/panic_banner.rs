@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+
+use blog_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use core::panic::PanicInfo;
+
+const BUFFER_HEIGHT: usize = 25;
+const BUFFER_WIDTH: usize = 80;
+
+/// VGA attribute byte for white on red.
+const WHITE_ON_RED: u8 = 0x4f;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("panic_banner::panic_banner...\t");
+    panic!("Deliberate panic");
+}
+
+/// Print the panic through the normal kernel path, then check the
+/// screen instead of failing the test.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::vga_buffer::print_panic(info);
+
+    match banner_color() {
+        Some(WHITE_ON_RED) => {
+            serial_println!("[ok]");
+            exit_qemu(QemuExitCode::Success);
+        }
+        Some(color) => {
+            serial_println!("[failed]\n");
+            serial_println!("Error: banner has color {:#04x}", color);
+            exit_qemu(QemuExitCode::Failed);
+        }
+        None => {
+            serial_println!("[failed]\n");
+            serial_println!("Error: banner not found on screen");
+            exit_qemu(QemuExitCode::Failed);
+        }
+    }
+
+    blog_os::hlt_loop()
+}
+
+/// Look for the panic banner in a snapshot of the VGA buffer. Returns
+/// the color byte of its cells if they all share the same one.
+fn banner_color() -> Option<u8> {
+    let text = b"KERNEL PANIC";
+    let buffer = 0xb8000 as *const u16;
+
+    for row in 0..BUFFER_HEIGHT {
+        let mut cells = [0u16; BUFFER_WIDTH];
+        for (col, cell) in cells.iter_mut().enumerate() {
+            *cell =
+                unsafe { buffer.add(row * BUFFER_WIDTH + col).read_volatile() };
+        }
+
+        for start in 0..=(BUFFER_WIDTH - text.len()) {
+            let candidate = &cells[start..start + text.len()];
+            let matches = candidate
+                .iter()
+                .zip(text.iter())
+                .all(|(&cell, &c)| cell as u8 == c);
+            if !matches {
+                continue;
+            }
+
+            let color = (candidate[0] >> 8) as u8;
+            if candidate.iter().all(|&cell| (cell >> 8) as u8 == color) {
+                return Some(color);
+            }
+            return None;
+        }
+    }
+
+    None
+}
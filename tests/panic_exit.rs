@@ -0,0 +1,22 @@
+#![no_std]
+#![no_main]
+
+use blog_os::{serial_print, serial_println, QemuExitCode};
+use core::panic::PanicInfo;
+
+// With the `qemu-exit-on-panic` feature, `blog_os::abort` exits qemu.
+// We make it use the success code, so that a passing run prints "[ok]"
+// and exits, while a broken abort hangs until the test times out.
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("panic_exit::abort_exits_qemu...\t");
+    blog_os::set_abort_exit_code(QemuExitCode::Success);
+    panic!("Deliberate panic");
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    blog_os::abort()
+}
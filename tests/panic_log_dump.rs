@@ -0,0 +1,52 @@
+#![no_std]
+#![no_main]
+
+use blog_os::{
+    exit_qemu, log_buffer, println, serial_print, serial_println, QemuExitCode,
+};
+use core::panic::PanicInfo;
+
+const LINES: usize = 5;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("panic_log_dump::panic_log_dump...\t");
+
+    for i in 0..LINES {
+        println!("log line {}", i);
+    }
+    panic!("Deliberate panic");
+}
+
+/// Dump the log like the kernel's panic handler does, and check that
+/// the lines printed before the panic are in it.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::vga_buffer::print_panic(info);
+
+    let mut dump = [0u8; 1024];
+    let mut len = 0;
+    log_buffer::dump(|byte| {
+        if len < dump.len() {
+            dump[len] = byte;
+            len += 1;
+        }
+    });
+    let dump = &dump[..len];
+
+    for i in 0..LINES {
+        let mut line = *b"log line 0";
+        line[9] += i as u8;
+        if !dump.windows(line.len()).any(|w| w == line) {
+            serial_println!("[failed]\n");
+            serial_println!("Error: log line {} missing from dump", i);
+            exit_qemu(QemuExitCode::Failed);
+            blog_os::hlt_loop();
+        }
+    }
+
+    serial_println!("[ok]");
+    log_buffer::dump_to_serial();
+    exit_qemu(QemuExitCode::Success);
+    blog_os::hlt_loop()
+}
@@ -0,0 +1,55 @@
+#![no_std]
+#![no_main]
+
+use blog_os::vga_buffer::{self, PANIC_MESSAGE_SIZE};
+use blog_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use core::fmt;
+use core::panic::PanicInfo;
+
+/// Displays as far more text than fits in the panic message buffer.
+struct Long;
+
+impl fmt::Display for Long {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for _ in 0..4 * PANIC_MESSAGE_SIZE {
+            f.write_str("x")?;
+        }
+        Ok(())
+    }
+}
+
+/// The heap is never initialized, so if printing the panic allocated,
+/// the allocation would fail and we would panic again instead of
+/// getting to the checks.
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("panic_truncated::long_message_truncated...\t");
+    panic!("{}", Long);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    vga_buffer::start_capture();
+    vga_buffer::print_panic(info);
+    let output = vga_buffer::stop_capture();
+    let output = output.as_bytes();
+
+    let marker = b"...(truncated)\n";
+    if !output.ends_with(marker) {
+        fail("message was not marked as truncated");
+    }
+    if !output.contains(&b'x') {
+        fail("message is missing");
+    }
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    blog_os::hlt_loop()
+}
+
+fn fail(error: &str) -> ! {
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}", error);
+    exit_qemu(QemuExitCode::Failed);
+    blog_os::hlt_loop()
+}
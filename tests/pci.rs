@@ -0,0 +1,44 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::pci::{self, PciAddress};
+use core::panic::PanicInfo;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    blog_os::init();
+    test_main();
+
+    blog_os::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// Qemu puts its host bridge, an i440FX on the default machine, at the
+/// very first address.
+#[test_case]
+fn scan_finds_host_bridge() {
+    let first = PciAddress {
+        bus: 0,
+        device: 0,
+        function: 0,
+    };
+    let bridge = pci::scan()
+        .find(|device| device.address == first)
+        .expect("No device at 00:00.0");
+
+    assert_ne!(bridge.vendor_id, 0xffff);
+    assert_eq!((bridge.class, bridge.subclass), (0x06, 0x00));
+    assert_eq!(bridge.header_type, 0);
+}
+
+#[test_case]
+fn scan_finds_vga_controller() {
+    assert!(pci::scan().any(|device| device.class == 0x03));
+}
@@ -0,0 +1,82 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use blog_os::{exit_qemu, memory, serial_print, serial_println, QemuExitCode};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr2};
+use x86_64::structures::idt::{
+    InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode,
+};
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+/// Address of the page that is mapped read-only.
+const READ_ONLY_ADDR: u64 = 0x_5555_0000_0000;
+
+lazy_static! {
+    /// Custom IDT for this test, so that the page fault handler can
+    /// return a success exit code.
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("read_only_mapping::write_faults...\t");
+
+    blog_os::boot_init(boot_info);
+    // The test IDT has no handlers for hardware interrupts.
+    x86_64::instructions::interrupts::disable();
+    TEST_IDT.load();
+
+    // Without this, writes from ring 0 ignore the writable flag.
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT)) };
+
+    let page = Page::containing_address(VirtAddr::new(READ_ONLY_ADDR));
+    memory::with_mapper(|mapper, frame_allocator| {
+        memory::create_mapping(
+            page,
+            PageTableFlags::PRESENT,
+            mapper,
+            frame_allocator,
+        )
+    })
+    .expect("Mapping failed");
+
+    let ptr: *mut u64 = page.start_address().as_mut_ptr();
+    unsafe { ptr.write_volatile(42) };
+
+    panic!("Execution continued after writing to a read-only page");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let expected = PageFaultErrorCode::PROTECTION_VIOLATION
+        | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if Cr2::read() == VirtAddr::new(READ_ONLY_ADDR)
+        && error_code.contains(expected)
+    {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    }
+    else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: unexpected page fault {:?}", error_code);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    blog_os::hlt_loop()
+}
@@ -0,0 +1,39 @@
+#![no_std]
+#![no_main]
+
+use blog_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use core::panic::PanicInfo;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("register_dump::register_dump...\t");
+    panic!("Deliberate panic");
+}
+
+/// Dump the registers like the kernel's panic handler does, and check
+/// that the stack and instruction pointers point to this handler.
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    let registers = blog_os::dump_registers();
+
+    let local = 0u64;
+    let stack = &local as *const u64 as u64;
+    let handler = panic as usize as u64;
+    if registers.rsp.abs_diff(stack) > 4096 {
+        fail("RSP is not on the current stack");
+    }
+    if !(handler..handler + 4096).contains(&registers.rip) {
+        fail("RIP is not in the panic handler");
+    }
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    blog_os::hlt_loop()
+}
+
+fn fail(message: &str) -> ! {
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}", message);
+    exit_qemu(QemuExitCode::Failed);
+    blog_os::hlt_loop()
+}
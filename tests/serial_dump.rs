@@ -0,0 +1,73 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::{interrupts, serial};
+use core::panic::PanicInfo;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    blog_os::init();
+    test_main();
+
+    blog_os::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// Receiving the dump request prints every section of the dump, in
+/// order, and the kernel keeps running afterwards.
+///
+/// The recent output is sent with [serial::send_byte], so it goes to
+/// the port instead of the capture buffer, which is too small for it.
+#[test_case]
+fn dump_request_prints_dump() {
+    let dumps = serial::dumps();
+
+    serial::redirect_to_buffer();
+    let received = serial::receive_from_self(serial::DUMP_REQUEST);
+    // The interrupt handler runs once interrupts are enabled again, so
+    // this should only take a single tick, if any.
+    let deadline = interrupts::ticks() + 10;
+    while serial::dumps() == dumps && interrupts::ticks() < deadline {
+        x86_64::instructions::hlt();
+    }
+    serial::stop_redirect();
+
+    assert!(received, "The UART didn't receive the dump request");
+    assert_eq!(serial::dumps(), dumps + 1);
+
+    let captured = serial::captured();
+    let output = captured.as_str().expect("Dump is not valid UTF-8");
+    let sections = [
+        "=== diagnostic dump ===",
+        "--- registers ---",
+        "--- heap stats",
+        "--- memory map ---",
+        "--- recent output ---",
+        "=== end of diagnostic dump ===",
+    ];
+    let mut rest = output;
+    for section in sections {
+        let start = rest
+            .find(section)
+            .unwrap_or_else(|| panic!("Missing section {:?}", section));
+        rest = &rest[start + section.len()..];
+    }
+}
+
+/// The dump request is not left pending, so no other dump follows.
+#[test_case]
+fn kernel_resumes_after_dump() {
+    let dumps = serial::dumps();
+    let start = interrupts::ticks();
+    while interrupts::ticks() < start + 3 {
+        x86_64::instructions::hlt();
+    }
+    assert_eq!(serial::dumps(), dumps);
+}
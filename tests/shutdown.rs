@@ -0,0 +1,22 @@
+#![no_std]
+#![no_main]
+
+use blog_os::{serial_print, serial_println};
+use core::panic::PanicInfo;
+
+/// ACPI poweroff is not configured, so shutdown has to fall back to
+/// the exit device, which reports success. If it didn't terminate the
+/// VM, the test would hang until it times out.
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("shutdown::shutdown_terminates...\t");
+    blog_os::init();
+
+    serial_println!("[ok]");
+    blog_os::shutdown();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
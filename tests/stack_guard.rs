@@ -0,0 +1,95 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use blog_os::{exit_qemu, memory, serial_print, serial_println, QemuExitCode};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{
+    InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode,
+};
+
+/// Start address of the guard page of the stack under test.
+static GUARD_PAGE: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("stack_guard::guard_page_faults...\t");
+
+    blog_os::boot_init(boot_info);
+
+    let stack = memory::with_mapper(|mapper, frame_allocator| {
+        memory::allocate_stack(4, mapper, frame_allocator)
+    })
+    .expect("Allocating the stack failed");
+    assert_eq!(stack.top() - stack.bottom(), 4 * 4096);
+
+    // The first and last word of the stack are usable.
+    let top = (stack.top() - 8u64).as_mut_ptr::<u64>();
+    let bottom = stack.bottom().as_mut_ptr::<u64>();
+    unsafe {
+        top.write_volatile(1);
+        bottom.write_volatile(2);
+        assert_eq!(top.read_volatile(), 1);
+        assert_eq!(bottom.read_volatile(), 2);
+    }
+
+    let guard = stack.guard_page().start_address();
+    GUARD_PAGE.store(guard.as_u64(), Ordering::SeqCst);
+    // The test IDT has no handlers for hardware interrupts.
+    x86_64::instructions::interrupts::disable();
+    TEST_IDT.load();
+
+    // Like the first push that overflows the stack.
+    unsafe {
+        (stack.bottom() - 8u64)
+            .as_mut_ptr::<u64>()
+            .write_volatile(3)
+    };
+
+    serial_println!("[failed]\n");
+    serial_println!("Error: writing to the guard page didn't fault");
+    exit_qemu(QemuExitCode::Failed);
+    blog_os::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let guard = GUARD_PAGE.load(Ordering::SeqCst);
+    let addr = Cr2::read().as_u64();
+    if (guard..guard + 4096).contains(&addr)
+        && !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+    {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    }
+    else {
+        serial_println!("[failed]\n");
+        serial_println!(
+            "Error: unexpected page fault at {:#x} {:?}",
+            addr,
+            error_code
+        );
+        exit_qemu(QemuExitCode::Failed);
+    }
+    blog_os::hlt_loop();
+}
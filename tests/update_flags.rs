@@ -0,0 +1,106 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use blog_os::{exit_qemu, memory, serial_print, serial_println, QemuExitCode};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr2};
+use x86_64::structures::idt::{
+    InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode,
+};
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+/// Address of the page whose flags are changed.
+const PAGE_ADDR: u64 = 0x_5555_1000_0000;
+
+const WRITABLE: PageTableFlags =
+    PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE);
+
+/// Number of write faults on the page.
+static FAULTS: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// Custom IDT for this test, so that the page fault handler can
+    /// make the page writable again and return.
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("update_flags::read_only_then_writable...\t");
+
+    blog_os::boot_init(boot_info);
+    // The test IDT has no handlers for hardware interrupts.
+    x86_64::instructions::interrupts::disable();
+    TEST_IDT.load();
+
+    // Without this, writes from ring 0 ignore the writable flag.
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT)) };
+
+    let page = page();
+    memory::with_mapper(|mapper, frame_allocator| {
+        memory::create_mapping(page, WRITABLE, mapper, frame_allocator)
+    })
+    .expect("Mapping failed");
+
+    let ptr: *mut u64 = page.start_address().as_mut_ptr();
+    unsafe { ptr.write_volatile(1) };
+
+    memory::with_mapper(|mapper, _| unsafe {
+        memory::update_flags(page, PageTableFlags::PRESENT, mapper)
+    })
+    .expect("Updating flags failed");
+
+    // This faults, and the handler makes the page writable again and
+    // lets the write happen.
+    unsafe { ptr.write_volatile(42) };
+
+    assert_eq!(FAULTS.load(Ordering::SeqCst), 1);
+    assert_eq!(unsafe { ptr.read_volatile() }, 42);
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    blog_os::hlt_loop()
+}
+
+fn page() -> Page {
+    Page::containing_address(VirtAddr::new(PAGE_ADDR))
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let expected = PageFaultErrorCode::PROTECTION_VIOLATION
+        | PageFaultErrorCode::CAUSED_BY_WRITE;
+    let second_fault = FAULTS.fetch_add(1, Ordering::SeqCst) > 0;
+    if Cr2::read() != VirtAddr::new(PAGE_ADDR)
+        || !error_code.contains(expected)
+        || second_fault
+    {
+        serial_println!("[failed]\n");
+        serial_println!("Error: unexpected page fault {:?}", error_code);
+        exit_qemu(QemuExitCode::Failed);
+        blog_os::hlt_loop();
+    }
+
+    // The faulting write is retried when we return.
+    memory::with_mapper(|mapper, _| unsafe {
+        memory::update_flags(page(), WRITABLE, mapper)
+    })
+    .expect("Updating flags failed");
+}